@@ -0,0 +1,116 @@
+//! Renders a map file to a PNG without opening a window:
+//!
+//! ```text
+//! map_preview (<map> | --bsp <seed> | --cave <seed>) <output.png> [--assets <dir>]
+//!     [--tile-size <pixels>]
+//! ```
+//!
+//! `.tmx` and `.tmj` maps are read as Tiled maps, anything else as an ASCII map. `--bsp` and
+//! `--cave` draw a generated dungeon or cave instead.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use game::game::cave::CaveConfig;
+use game::game::dungeon::BspConfig;
+use game::game::tiled::TiledMap;
+use game::game::tiles::TileRegistry;
+use game::game::TileMap;
+use game::graphics::assets::{self, AssetsPath};
+use game::graphics::preview::MapPreview;
+
+const USAGE: &str = "usage: map_preview (<map> | --bsp <seed> | --cave <seed>) \
+    <output.png> [--assets <dir>] [--tile-size <pixels>]";
+
+enum Source {
+    File(PathBuf),
+    Bsp(u64),
+    Cave(u64),
+}
+
+struct Args {
+    source: Source,
+    output: PathBuf,
+    assets: Option<AssetsPath>,
+    tile_size: Option<u32>,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut paths = Vec::new();
+    let mut assets = None;
+    let mut tile_size = None;
+    let mut generated = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bsp" | "--cave" => {
+                let value = args.next().context(USAGE)?;
+                let seed = value.parse().with_context(|| format!("seed `{}`", value))?;
+                generated = Some(match arg.as_str() {
+                    "--bsp" => Source::Bsp(seed),
+                    _ => Source::Cave(seed),
+                });
+            }
+            "--assets" => assets = Some(args.next().context(USAGE)?.into()),
+            "--tile-size" => {
+                let size = args.next().context(USAGE)?;
+                tile_size = Some(
+                    size.parse()
+                        .with_context(|| format!("tile size `{}`", size))?,
+                );
+            }
+            flag if flag.starts_with("--") => bail!("unknown option `{}`\n{}", flag, USAGE),
+            path => paths.push(PathBuf::from(path)),
+        }
+    }
+
+    let (source, output) = match (generated, paths.as_slice()) {
+        (Some(source), [output]) => (source, output.clone()),
+        (None, [map, output]) => (Source::File(map.clone()), output.clone()),
+        _ => bail!(USAGE),
+    };
+    Ok(Args {
+        source,
+        output,
+        assets,
+        tile_size,
+    })
+}
+
+fn load_map(path: &Path, tiles: &TileRegistry) -> Result<TileMap> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("tmx" | "tmj") => Ok(TiledMap::load(path, tiles)?.map),
+        _ => TileMap::load_ascii(path, tiles),
+    }
+}
+
+fn make_map(source: &Source, tiles: &TileRegistry) -> Result<TileMap> {
+    match source {
+        Source::File(path) => load_map(path, tiles),
+        Source::Bsp(seed) => Ok(BspConfig::default().generate(*seed)?.map),
+        Source::Cave(seed) => CaveConfig::default().generate(*seed),
+    }
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = parse_args()?;
+    let assets_path = match args.assets {
+        Some(path) => path,
+        None => assets::make_assets_path()?,
+    };
+
+    let tiles = TileRegistry::load(&assets::tiles_path(&assets_path))?;
+    let map = make_map(&args.source, &tiles)?;
+    let preview = MapPreview::load(&assets_path, &tiles, args.tile_size)?;
+    preview.save(&map, &args.output)?;
+
+    let (width, height) = map.dimensions();
+    println!(
+        "Rendered {}x{} map to {}",
+        width,
+        height,
+        args.output.display()
+    );
+    Ok(())
+}
@@ -1,24 +1,77 @@
-use anyhow::{bail, Ok, Result};
-use winit::event::{Event, WindowEvent};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{bail, Context, Ok, Result};
+use serde::{Deserialize, Serialize};
+use winit::event::WindowEvent;
+use winit::event_loop::EventLoopProxy;
 
 use crate::graphics;
 
+use self::camera::CameraRig;
+use self::clock::Clock;
+use self::ecs::{
+    Commands, Entity, EventBus, Ref, SerializeRegistry, SnapshotRegistry, Transform, World,
+};
+use self::events::{EntityDied, LevelEntered, TileChanged, UserEvent};
 use self::input::Input;
-
-mod input;
-
-// Temp struct.
-pub struct ECS {
+use self::prefab::Prefabs;
+use self::rng::Rng;
+use self::schedule::{Schedule, ScheduleBuilder, Stage, SystemAccess, WorldState};
+use self::script::Scripts;
+use self::state::{AppState, StateMachine};
+use self::tiles::{TileId, TileRegistry};
+use self::timer::{DelayedActions, DelayedHandlers};
+use self::turns::{PathMap, Player, Turns};
+use self::tween::TweenHooks;
+
+pub mod ascii;
+pub mod camera;
+pub mod cave;
+pub mod chunks;
+pub mod clock;
+pub mod components;
+pub mod debug;
+pub mod dungeon;
+pub mod ecs;
+pub mod events;
+pub mod input;
+pub mod prefab;
+pub mod rng;
+pub mod save;
+pub mod schedule;
+pub mod script;
+pub mod snapshot;
+pub mod state;
+pub mod tiled;
+pub mod tiles;
+pub mod timer;
+pub mod turns;
+pub mod tween;
+
+/// Pairs the simulation with the renderer that draws it.
+pub struct Game {
     pub game_state: GameState,
     pub renderer: graphics::State,
+    schedule: Schedule<GameState>,
 }
 
-impl ECS {
-    pub fn new(game_state: GameState, renderer: graphics::State) -> Self {
-        Self {
+impl Game {
+    pub fn new(game_state: GameState, renderer: graphics::State) -> Result<Self> {
+        let schedule = build_schedule()?;
+        log::info!("System order:\n{}", schedule);
+
+        Ok(Self {
             game_state,
             renderer,
-        }
+            schedule,
+        })
+    }
+
+    /// Advances the simulation by one fixed step.
+    pub fn tick(&mut self) -> Result<()> {
+        self.schedule.run(&mut self.game_state)?;
+        self.game_state.finish_tick();
+        Ok(())
     }
 
     pub fn render(&mut self) -> Result<()> {
@@ -30,108 +83,552 @@ impl ECS {
     pub fn update_renderer(&mut self, renderer: graphics::State) {
         self.renderer = renderer
     }
+
+    /// See [`Schedule::set_deterministic`].
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.schedule.set_deterministic(deterministic);
+    }
+}
+
+/// Registers the systems that make up a tick. Gameplay systems go here.
+pub fn build_schedule() -> Result<Schedule<GameState>> {
+    let mut builder = ScheduleBuilder::new();
+
+    // Spawns and despawns queued by a stage are visible to every later stage.
+    builder.on_stage_end(|state: &mut GameState, _| {
+        state.apply_commands();
+        Ok(())
+    });
+    // Game time only passes while playing, menus and pause freeze it.
+    builder
+        .add_system(Stage::PreInput, "advance_clock", |state: &mut GameState| {
+            if let Some(clock) = state.world.resource_mut::<Clock>() {
+                clock.advance();
+            }
+            Ok(())
+        })
+        .run_if(state::in_state(AppState::Playing));
+    builder
+        .add_system(Stage::PreInput, "tick_timers", timer::tick_timers)
+        .after("advance_clock")
+        .run_if(state::in_state(AppState::Playing));
+    builder
+        .add_system(
+            Stage::PreInput,
+            "delayed_actions",
+            timer::run_delayed_actions,
+        )
+        .after("tick_timers")
+        .run_if(state::in_state(AppState::Playing));
+    builder.add_system(Stage::Input, "handle_input", |state: &mut GameState| {
+        state.update();
+        Ok(())
+    });
+    builder
+        .add_system(Stage::Input, "player_input", |state: &mut GameState| {
+            turns::read_player_input(state);
+            Ok(())
+        })
+        .run_if(state::in_state(AppState::Playing));
+    builder
+        .add_system(Stage::Simulate, "start_scripts", script::start_scripts)
+        .before("take_turns")
+        .run_if(state::in_state(AppState::Playing));
+    builder
+        .add_parallel_system(
+            Stage::Simulate,
+            "plan_paths",
+            SystemAccess::new()
+                .read::<TileMap>()
+                .read::<TileRegistry>()
+                .read::<Transform>()
+                .read::<Player>()
+                .write::<PathMap>(),
+            turns::plan_paths,
+        )
+        .before("take_turns")
+        .run_if(turns::action_submitted);
+    // The turn based world stands still until the player acted.
+    builder
+        .add_system(Stage::Simulate, "take_turns", turns::take_turns)
+        .run_if(turns::action_submitted);
+    builder
+        .add_system(Stage::Simulate, "update_tweens", tween::update_tweens)
+        .after("take_turns")
+        .run_if(state::in_state(AppState::Playing));
+    builder
+        .add_system(Stage::Simulate, "despawn_dead", components::despawn_dead)
+        .after("take_turns")
+        .run_if(state::in_state(AppState::Playing));
+    builder.add_system(
+        Stage::PostSimulate,
+        "apply_transitions",
+        |state: &mut GameState| {
+            state::apply_transitions(state);
+            Ok(())
+        },
+    );
+    // Pressed keys become Down after the first tick that saw them.
+    builder.add_system(
+        Stage::PostSimulate,
+        "advance_keys",
+        |state: &mut GameState| {
+            state.update_keys();
+            Ok(())
+        },
+    );
+
+    // Once the camera moved for this tick.
+    builder.add_system(Stage::RenderExtract, "stream_chunks", chunks::stream_chunks);
+    // After everything that moves entities, so the renderer sees this tick's positions.
+    builder.add_system(
+        Stage::RenderExtract,
+        "propagate_transforms",
+        |state: &mut GameState| {
+            ecs::propagate_transforms(&mut state.world);
+            Ok(())
+        },
+    );
+
+    builder.build()
+}
+
+impl WorldState for GameState {
+    fn world(&self) -> &World {
+        &self.world
+    }
 }
 
 pub struct GameState {
-    entities: Vec<Entity>,
-    map: TileMap,
+    world: World,
     input: Input,
-
-    // Worst state management ever, let me cook.
-    invert_triangle: bool,
-    render_quad: bool,
-    exit: bool,
+    events: EventBus,
+    commands: Commands,
+    delayed: DelayedHandlers,
+    tween_hooks: TweenHooks,
+    scripts: Scripts,
+    event_proxy: Option<EventLoopProxy<UserEvent>>,
+    states: StateMachine,
+    snapshots: SnapshotRegistry,
+    serializers: SerializeRegistry,
 }
 
 impl GameState {
     pub fn new() -> Result<Self> {
         let input = Input::new();
-        let map = TileMap::default();
+
+        let mut world = World::new();
+        world.insert_resource(TileMap::default());
+        world.insert_resource(TileRegistry::default());
+        world.insert_resource(Rng::default());
+        world.insert_resource(Clock::default());
+        world.insert_resource(Prefabs::new());
+        world.insert_resource(Turns::default());
+        world.insert_resource(PathMap::default());
+        world.insert_resource(CameraRig::default());
+        world.insert_resource(DelayedActions::default());
 
         Ok(Self {
             input,
-            entities: Vec::new(),
-            map,
-            exit: false,
-            invert_triangle: false,
-            render_quad: false,
+            world,
+            events: EventBus::new(),
+            commands: Commands::new(),
+            delayed: DelayedHandlers::default(),
+            tween_hooks: TweenHooks::default(),
+            scripts: Scripts::new(),
+            event_proxy: None,
+            states: StateMachine::new(AppState::MainMenu),
+            snapshots: snapshot::default_registry(),
+            serializers: save::default_registry(),
         })
     }
 
     pub fn update(&mut self) {
-        if self
-            .input
-            .is_logical_key_pressed(winit::keyboard::NamedKey::Escape)
-        {
-            self.exit = true;
-            return;
-        }
+        state::handle_input(self);
+        debug::handle_dump_key(self);
+    }
+
+    pub fn update_keys(&mut self) {
+        self.input.update_keys()
+    }
+
+    /// Closes the change detection window and advances the event queues after the schedule ran.
+    pub fn finish_tick(&mut self) {
+        self.send_deaths();
+        self.world.clear_trackers();
+        self.events.update();
+    }
+
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    pub fn world_mut(&mut self) -> &mut World {
+        &mut self.world
+    }
 
-        if self
-            .input
-            .is_physical_key_pressed(winit::keyboard::KeyCode::KeyI)
-        {
-            self.invert_triangle = !self.invert_triangle
+    pub fn commands(&mut self) -> &mut Commands {
+        &mut self.commands
+    }
+
+    /// Lets a system queue commands while it iterates the world.
+    pub fn world_and_commands(&mut self) -> (&World, &mut Commands) {
+        (&self.world, &mut self.commands)
+    }
+
+    /// Applies the queued commands. The schedule does this at the end of every stage.
+    pub fn apply_commands(&mut self) {
+        self.commands.apply(&mut self.world);
+        self.send_deaths();
+    }
+
+    /// Sends an [`EntityDied`] for every entity despawned since the last call.
+    fn send_deaths(&mut self) {
+        for entity in self.world.take_despawned() {
+            self.events.send(EntityDied { entity });
         }
+    }
+
+    /// Replaces the known prefabs with the ones in the assets directory.
+    pub fn load_prefabs(&mut self, assets_path: &graphics::assets::AssetsPath) -> Result<()> {
+        let prefabs = Prefabs::load_dir(&graphics::assets::prefabs_path(assets_path))?;
+        self.world.insert_resource(prefabs);
+        Ok(())
+    }
+
+    pub fn spawn_prefab(&mut self, name: &str) -> Result<Entity> {
+        let instance = self
+            .world
+            .resource::<Prefabs>()
+            .context("no prefabs loaded")?
+            .build(name)?;
+        instance.spawn(&mut self.world)
+    }
 
-        if self
-            .input
-            .is_physical_key_pressed(winit::keyboard::KeyCode::KeyQ)
-        {
-            self.render_quad = !self.render_quad
+    pub fn map(&self) -> Ref<'_, TileMap> {
+        self.world
+            .resource::<TileMap>()
+            .expect("map resource missing")
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    pub fn events_mut(&mut self) -> &mut EventBus {
+        &mut self.events
+    }
+
+    /// Changes a single tile and emits a [`TileChanged`] if its type actually changed.
+    pub fn set_tile(&mut self, x: usize, y: usize, ty: TileId) -> Result<()> {
+        let from = self
+            .world
+            .resource_mut::<TileMap>()
+            .context("no map loaded")?
+            .set(x, y, ty)?;
+        if from != ty {
+            self.events.send(TileChanged {
+                position: (x as u32, y as u32),
+                from,
+                to: ty,
+            });
         }
+
+        Ok(())
     }
 
-    pub fn update_keys(&mut self) {
-        self.input.update_keys()
+    /// Replaces the map and emits a [`LevelEntered`].
+    pub fn enter_level(&mut self, name: &str, map: TileMap) {
+        self.world.insert_resource(map);
+        self.events.send(LevelEntered {
+            name: name.to_string(),
+        });
     }
 
-    pub fn inverted(&self) -> bool {
-        self.invert_triangle
+    /// Proxy for background threads to send [`UserEvent`]s into the event loop. `None` until the
+    /// window loop hands one over.
+    pub fn event_proxy(&self) -> Option<EventLoopProxy<UserEvent>> {
+        self.event_proxy.clone()
     }
 
-    pub fn render_quad(&self) -> bool {
-        self.render_quad
+    pub fn set_event_proxy(&mut self, proxy: EventLoopProxy<UserEvent>) {
+        self.event_proxy = Some(proxy);
+    }
+
+    pub fn states(&self) -> &StateMachine {
+        &self.states
+    }
+
+    pub fn states_mut(&mut self) -> &mut StateMachine {
+        &mut self.states
+    }
+
+    pub fn app_state(&self) -> Option<AppState> {
+        self.states.current()
     }
 
     pub fn input(&mut self, event: &WindowEvent) {
         self.input.process_event(event);
     }
 
-    // NOTE: Right now no user events. When there are such, I can make `Event` generic on my user
-    // event.
-    pub fn handle(&self, _event: Event<()>) {}
+    /// Queues a user event on the bus, systems pick it up with an `EventReader<UserEvent>`.
+    pub fn handle(&mut self, event: UserEvent) {
+        self.events.send(event);
+    }
 
     pub fn exit(&self) -> bool {
-        self.exit
+        self.states.should_quit()
     }
 }
 
-type Entity = usize;
-
+/// Tiles in named layers, drawn bottom to top. Every map has a [`GROUND_LAYER`], the one
+/// `get` and `set` work on. Layers above it are empty until something is placed, e.g. rugs,
+/// blood splatters and furniture over the floor.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedTileMap")]
 pub struct TileMap {
-    tiles: Vec<TileType>,
+    layers: Vec<TileLayer>,
+    width: usize,
+    height: usize,
+}
+
+/// A [`TileMap`] as read from a file, before checking that its layers fit together.
+#[derive(Deserialize)]
+struct UncheckedTileMap {
+    layers: Vec<TileLayer>,
     width: usize,
     height: usize,
 }
 
+impl TryFrom<UncheckedTileMap> for TileMap {
+    type Error = anyhow::Error;
+
+    fn try_from(map: UncheckedTileMap) -> Result<Self> {
+        let Some(len) = map.width.checked_mul(map.height).filter(|len| *len > 0) else {
+            bail!("a {}x{} map is not possible", map.width, map.height)
+        };
+        if map.layers.first().map(|layer| layer.name.as_str()) != Some(GROUND_LAYER) {
+            bail!("the first layer has to be `{}`", GROUND_LAYER)
+        }
+        for (index, layer) in map.layers.iter().enumerate() {
+            if map.layers[..index].iter().any(|l| l.name == layer.name) {
+                bail!("layer `{}` exists twice", layer.name)
+            }
+            if layer.tiles.len() != len {
+                bail!(
+                    "layer `{}` has {} tiles instead of {}",
+                    layer.name,
+                    layer.tiles.len(),
+                    len
+                )
+            }
+        }
+
+        Ok(Self {
+            layers: map.layers,
+            width: map.width,
+            height: map.height,
+        })
+    }
+}
+
+pub const GROUND_LAYER: &str = "ground";
+
+/// Source of the versions of map layers and chunks. Global, so a version never stands for two
+/// different contents, not even across snapshots and loaded saves.
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+fn next_version() -> u64 {
+    NEXT_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TileLayer {
+    name: String,
+    /// Hidden layers are not drawn but still block movement.
+    pub visible: bool,
+    tiles: Vec<Option<TileId>>,
+    /// Handed out anew whenever a tile changes, so the renderer knows what to rebuild.
+    #[serde(skip, default = "next_version")]
+    version: u64,
+}
+
+impl PartialEq for TileLayer {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.visible == other.visible && self.tiles == other.tiles
+    }
+}
+
+impl Eq for TileLayer {}
+
+impl TileLayer {
+    fn new(name: &str, tiles: Vec<Option<TileId>>) -> Self {
+        Self {
+            name: name.to_string(),
+            visible: true,
+            tiles,
+            version: next_version(),
+        }
+    }
+
+    /// Fixed once the layer exists, the ground layer is found by its name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Changes whenever one of the layer's tiles does.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
 impl TileMap {
-    pub fn iter(&self) -> TileMapIter {
+    /// Iterates the ground layer.
+    pub fn iter(&self) -> TileMapIter<'_> {
+        self.iter_layer(0)
+    }
+
+    /// Iterates the tiles of the layer at `index`, skipping empty ones.
+    pub fn iter_layer(&self, index: usize) -> TileMapIter<'_> {
         TileMapIter {
             current_idx: 0,
-            tile_map: self,
+            tiles: self.layers.get(index).map_or(&[], |l| l.tiles.as_slice()),
+            width: self.width,
         }
     }
 
-    pub fn default() -> Self {
-        let width = 10;
-        let height = 10;
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
 
-        TileMap::new(width, height).unwrap()
+    /// Layers from bottom to top.
+    pub fn layers(&self) -> &[TileLayer] {
+        &self.layers
     }
 
-    pub fn dimensions(&self) -> (usize, usize) {
-        (self.width, self.height)
+    pub fn layer_index(&self, name: &str) -> Option<usize> {
+        self.layers.iter().position(|layer| layer.name == name)
+    }
+
+    pub fn layer_mut(&mut self, name: &str) -> Option<&mut TileLayer> {
+        self.layers.iter_mut().find(|layer| layer.name == name)
+    }
+
+    /// Adds an empty layer on top of the others.
+    pub fn add_layer(&mut self, name: &str) -> Result<()> {
+        if self.layer_index(name).is_some() {
+            bail!("layer `{}` already exists", name)
+        }
+
+        let tiles = vec![None; self.width * self.height];
+        self.layers.push(TileLayer::new(name, tiles));
+        Ok(())
+    }
+
+    pub fn remove_layer(&mut self, name: &str) -> Result<TileLayer> {
+        if name == GROUND_LAYER {
+            bail!("the ground layer can't be removed")
+        }
+        let Some(index) = self.layer_index(name) else {
+            bail!("no layer `{}`", name)
+        };
+        Ok(self.layers.remove(index))
+    }
+
+    /// Moves a layer to `index` in the draw order, 0 being the bottom. The ground always stays
+    /// at the bottom.
+    pub fn move_layer(&mut self, name: &str, index: usize) -> Result<()> {
+        let Some(from) = self.layer_index(name) else {
+            bail!("no layer `{}`", name)
+        };
+        if from == 0 || index == 0 {
+            bail!("the ground layer stays at the bottom")
+        }
+        if index >= self.layers.len() {
+            bail!("layer index {} is out of range", index)
+        }
+
+        let layer = self.layers.remove(from);
+        self.layers.insert(index, layer);
+        Ok(())
+    }
+
+    pub fn set_visible(&mut self, name: &str, visible: bool) -> Result<()> {
+        let layer = self
+            .layer_mut(name)
+            .with_context(|| format!("no layer `{}`", name))?;
+        layer.visible = visible;
+        Ok(())
+    }
+
+    /// The ground tile at `(x, y)`.
+    pub fn get(&self, x: usize, y: usize) -> Option<TileId> {
+        self.get_in(GROUND_LAYER, x, y)
+    }
+
+    /// Sets the ground tile at `(x, y)` and returns the previous one.
+    pub fn set(&mut self, x: usize, y: usize, ty: TileId) -> Result<TileId> {
+        let from = self.set_in(GROUND_LAYER, x, y, Some(ty))?;
+        Ok(from.unwrap_or_default())
+    }
+
+    /// The tile of layer `name` at `(x, y)`, `None` if there is none.
+    pub fn get_in(&self, name: &str, x: usize, y: usize) -> Option<TileId> {
+        let idx = self.index(x, y)?;
+        self.layers.get(self.layer_index(name)?)?.tiles[idx]
+    }
+
+    /// Places or clears a tile of layer `name` and returns the previous one.
+    pub fn set_in(
+        &mut self,
+        name: &str,
+        x: usize,
+        y: usize,
+        ty: Option<TileId>,
+    ) -> Result<Option<TileId>> {
+        let Some(idx) = self.index(x, y) else {
+            bail!("tile ({}, {}) is outside of the map", x, y)
+        };
+        let layer = self
+            .layer_mut(name)
+            .with_context(|| format!("no layer `{}`", name))?;
+        let from = std::mem::replace(&mut layer.tiles[idx], ty);
+        if from != ty {
+            layer.version = next_version();
+        }
+        Ok(from)
+    }
+
+    /// Every tile at `(x, y)` from the bottom up, hidden layers included.
+    pub fn stack(&self, x: usize, y: usize) -> impl Iterator<Item = TileId> + '_ {
+        let idx = self.index(x, y);
+        self.layers
+            .iter()
+            .filter_map(move |layer| layer.tiles[idx?])
+    }
+
+    fn index(&self, x: usize, y: usize) -> Option<usize> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+
+        Some(y * self.width + x)
+    }
+
+    /// A map with nothing but `tile` on the ground.
+    pub fn filled(width: usize, height: usize, tile: TileId) -> Result<Self> {
+        if width == 0 || height == 0 {
+            bail!("width and height must be larger than 0")
+        }
+
+        Ok(TileMap {
+            layers: vec![TileLayer::new(
+                GROUND_LAYER,
+                vec![Some(tile); width * height],
+            )],
+            width,
+            height,
+        })
     }
 
     pub fn new(width: usize, height: usize) -> Result<Self> {
@@ -143,50 +640,58 @@ impl TileMap {
         for y in 0..height {
             for x in 0..width {
                 if x == 0 || y == 0 || x == width - 1 || y == height - 1 {
-                    tiles.push(TileType::Wall);
+                    tiles.push(Some(TileId::WALL));
                 } else {
-                    tiles.push(TileType::Floor);
+                    tiles.push(Some(TileId::FLOOR));
                 }
             }
         }
 
         Ok(TileMap {
-            tiles,
+            layers: vec![TileLayer::new(GROUND_LAYER, tiles)],
             width,
             height,
         })
     }
 }
 
+impl Default for TileMap {
+    fn default() -> Self {
+        let width = 10;
+        let height = 10;
+
+        TileMap::new(width, height).unwrap()
+    }
+}
+
 pub struct TileMapIter<'a> {
     current_idx: usize,
-    tile_map: &'a TileMap,
+    tiles: &'a [Option<TileId>],
+    width: usize,
 }
 
 impl Iterator for TileMapIter<'_> {
     type Item = Tile;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let tile = self.tile_map.tiles.get(self.current_idx)?;
-        let x = self.current_idx % self.tile_map.width;
-        let y = self.current_idx / self.tile_map.height;
-        self.current_idx += 1;
-        Some(Self::Item {
-            position: (x as u32, y as u32),
-            ty: tile.clone(),
-        })
+        loop {
+            let tile = self.tiles.get(self.current_idx)?;
+            let x = self.current_idx % self.width;
+            let y = self.current_idx / self.width;
+            self.current_idx += 1;
+            if let Some(ty) = tile {
+                return Some(Self::Item {
+                    position: (x as u32, y as u32),
+                    ty: *ty,
+                });
+            }
+        }
     }
 }
 
 pub struct Tile {
     pub position: (u32, u32),
-    pub ty: TileType,
-}
-
-#[derive(Clone)]
-pub enum TileType {
-    Floor,
-    Wall,
+    pub ty: TileId,
 }
 
 #[cfg(test)]
@@ -197,6 +702,196 @@ mod tests {
     fn test_tile_map_iter() -> Result<()> {
         let positions: Vec<Tile> = TileMap::new(10, 10)?.iter().collect();
         assert_eq!(100, positions.len());
+
+        let map = TileMap::from_ascii(
+            "
+            ####
+            #..#
+            ## #
+            ",
+            &TileRegistry::default(),
+        )?;
+        let walls: Vec<(u32, u32)> = map
+            .iter()
+            .filter(|tile| tile.ty == TileId::WALL)
+            .map(|tile| tile.position)
+            .collect();
+        assert_eq!(11, map.iter().count());
+        assert_eq!(
+            vec![
+                (0, 0),
+                (1, 0),
+                (3, 0),
+                (0, 1),
+                (3, 1),
+                (0, 2),
+                (1, 2),
+                (2, 2),
+                (3, 2)
+            ],
+            walls
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tile_map_layers() -> Result<()> {
+        let mut map = TileMap::new(4, 4)?;
+        map.add_layer("decoration")?;
+        map.add_layer("overlay")?;
+        assert!(map.add_layer("overlay").is_err());
+        assert_eq!(0, map.iter_layer(1).count());
+
+        map.set_in("decoration", 1, 1, Some(TileId(2)))?;
+        map.set_in("overlay", 1, 1, Some(TileId(3)))?;
+        assert_eq!(Some(TileId::FLOOR), map.get(1, 1));
+        assert_eq!(
+            vec![TileId::FLOOR, TileId(2), TileId(3)],
+            map.stack(1, 1).collect::<Vec<_>>()
+        );
+
+        map.move_layer("overlay", 1)?;
+        assert_eq!(
+            vec![TileId::FLOOR, TileId(3), TileId(2)],
+            map.stack(1, 1).collect::<Vec<_>>()
+        );
+        assert!(map.move_layer(GROUND_LAYER, 2).is_err());
+        assert!(map.remove_layer(GROUND_LAYER).is_err());
+
+        assert_eq!(Some(TileId(2)), map.set_in("decoration", 1, 1, None)?);
+        assert_eq!(None, map.get_in("decoration", 1, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_versions_follow_changes() -> Result<()> {
+        let mut map = TileMap::new(3, 3)?;
+        map.add_layer("decoration")?;
+        let versions =
+            |map: &TileMap| -> Vec<u64> { map.layers().iter().map(TileLayer::version).collect() };
+        let before = versions(&map);
+
+        map.set(1, 1, TileId::FLOOR)?;
+        assert_eq!(before, versions(&map));
+        map.set_in("decoration", 1, 1, Some(TileId(2)))?;
+        let after = versions(&map);
+        assert_eq!(before[0], after[0]);
+        assert_ne!(before[1], after[1]);
+
+        // Equal content, but a loaded map gets versions of its own.
+        let loaded: TileMap = serde_json::from_value(serde_json::to_value(&map)?)?;
+        assert_eq!(map, loaded);
+        assert!(versions(&loaded).iter().all(|v| !after.contains(v)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_inconsistent_tile_maps_are_rejected() -> Result<()> {
+        let mut map = TileMap::new(3, 2)?;
+        map.add_layer("decoration")?;
+        let json = serde_json::to_value(&map)?;
+        assert_eq!(map, serde_json::from_value(json.clone())?);
+
+        let mut short = json.clone();
+        short["layers"][1]["tiles"].as_array_mut().unwrap().pop();
+        let mut wide = json.clone();
+        wide["width"] = 4.into();
+        let mut no_ground = json.clone();
+        no_ground["layers"][0]["name"] = "floor".into();
+        let mut swapped = json.clone();
+        swapped["layers"].as_array_mut().unwrap().swap(0, 1);
+        let mut twice = json.clone();
+        twice["layers"][1]["name"] = GROUND_LAYER.into();
+        let mut empty = json;
+        empty["width"] = 0.into();
+        empty["layers"] = serde_json::json!([{ "name": "ground", "visible": true, "tiles": [] }]);
+
+        for bad in [short, wide, no_ground, swapped, twice, empty] {
+            assert!(serde_json::from_value::<TileMap>(bad).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_tile_emits_event() -> Result<()> {
+        let mut state = GameState::new()?;
+        let mut reader = ecs::EventReader::<TileChanged>::new();
+
+        state.set_tile(2, 3, TileId::WALL)?;
+        state.set_tile(2, 3, TileId::WALL)?;
+        assert!(state.set_tile(10, 0, TileId::WALL).is_err());
+
+        let changes: Vec<&TileChanged> = state.events().read(&mut reader).collect();
+        assert_eq!(
+            vec![&TileChanged {
+                position: (2, 3),
+                from: TileId::FLOOR,
+                to: TileId::WALL
+            }],
+            changes
+        );
+        assert_eq!(Some(TileId::WALL), state.map().get(2, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_deaths_are_announced() -> Result<()> {
+        let mut state = GameState::new()?;
+        let mut reader = ecs::EventReader::<EntityDied>::new();
+        let alive = state.world_mut().spawn();
+        state
+            .world_mut()
+            .insert(alive, components::Health::new(3))?;
+        let slain = state.world_mut().spawn();
+        state
+            .world_mut()
+            .insert(slain, components::Health { current: 0, max: 3 })?;
+        let removed = state.world_mut().spawn();
+
+        components::despawn_dead(&mut state)?;
+        state.commands().despawn(removed);
+        state.apply_commands();
+
+        let died: Vec<Entity> = state.events().read(&mut reader).map(|e| e.entity).collect();
+        assert_eq!(vec![slain, removed], died);
+        assert!(state.world().is_alive(alive));
+        Ok(())
+    }
+
+    #[test]
+    fn test_commands_apply_at_stage_end() -> Result<()> {
+        struct Health(u32);
+        struct Loot;
+
+        let mut state = GameState::new()?;
+        let dead = state.world_mut().spawn();
+        state.world_mut().insert(dead, Health(0))?;
+
+        let mut builder = ScheduleBuilder::new();
+        builder.on_stage_end(|state: &mut GameState, _| {
+            state.apply_commands();
+            Ok(())
+        });
+        builder.add_system(Stage::Simulate, "combat", move |state: &mut GameState| {
+            let (world, commands) = state.world_and_commands();
+            for (entity, health) in &mut world.query::<(ecs::Entity, &Health)>()? {
+                if health.0 == 0 {
+                    commands.despawn(entity);
+                    commands.spawn().insert(Loot);
+                }
+            }
+            // Still there until the stage ends.
+            assert!(world.is_alive(dead));
+            Ok(())
+        });
+        builder.add_system(Stage::PostSimulate, "count", |state: &mut GameState| {
+            assert_eq!(1, state.world().len());
+            assert!(state.world().query::<&Loot>()?.iter().next().is_some());
+            Ok(())
+        });
+
+        builder.build()?.run(&mut state)?;
+        assert!(!state.world().is_alive(dead));
         Ok(())
     }
 }
@@ -0,0 +1,391 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use super::tiles::{TileId, TileRegistry};
+use super::{TileMap, GROUND_LAYER};
+
+/// Separates the legend from the rows.
+const HEADER_END: &str = "---";
+
+/// Header key for the map's dimensions, e.g. `size = 5x3`.
+const SIZE_KEY: &str = "size";
+
+/// Cells without a tile. Can't be remapped.
+const EMPTY: char = ' ';
+
+/// Picked in order for tiles that need a character when saving.
+const SPARE_CHARS: &str =
+    "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789~%&*+:;!?^@$";
+
+/// Which character stands for which tile, by tile name. `#` is a wall and `.` a floor unless
+/// the header says otherwise.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Legend(BTreeMap<char, String>);
+
+impl Default for Legend {
+    fn default() -> Self {
+        let mut legend = Self(BTreeMap::new());
+        legend.0.insert('#', "wall".to_string());
+        legend.0.insert('.', "floor".to_string());
+        legend
+    }
+}
+
+impl Legend {
+    pub fn insert(&mut self, c: char, tile: &str) -> Result<()> {
+        if c == EMPTY || c == '=' || c.is_control() {
+            bail!("`{}` can't stand for a tile", c.escape_default())
+        }
+        self.0.insert(c, tile.to_string());
+        Ok(())
+    }
+
+    pub fn get(&self, c: char) -> Option<&str> {
+        self.0.get(&c).map(String::as_str)
+    }
+
+    /// The default legend plus a `c = tile` line for each entry of `header`, and the size if
+    /// the header has a `size = WxH` line.
+    fn parse(header: &[&str]) -> Result<(Self, Option<(usize, usize)>)> {
+        let mut legend = Self::default();
+        let mut size = None;
+        for (number, line) in header.iter().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                bail!(
+                    "line {}: expected `<char> = <tile>`, found `{}`",
+                    number + 1,
+                    line
+                )
+            };
+            let (key, value) = (key.trim(), value.trim());
+            if key == SIZE_KEY {
+                size =
+                    Some(parse_size(value).with_context(|| {
+                        format!("line {}: invalid size `{}`", number + 1, value)
+                    })?);
+                continue;
+            }
+
+            let mut chars = key.chars();
+            let Some(c) = chars.next().filter(|_| chars.next().is_none()) else {
+                bail!(
+                    "line {}: expected `<char> = <tile>`, found `{}`",
+                    number + 1,
+                    line
+                )
+            };
+            legend
+                .insert(c, value)
+                .with_context(|| format!("line {}", number + 1))?;
+        }
+        Ok((legend, size))
+    }
+
+    fn char_for(&self, tile: &str) -> Option<char> {
+        self.0
+            .iter()
+            .find(|(_, name)| *name == tile)
+            .map(|(c, _)| *c)
+    }
+
+    /// Finds a free character for `tile`, preferring its initial.
+    fn assign(&mut self, tile: &str) -> Result<char> {
+        let initial = tile.chars().next().into_iter();
+        let c = initial
+            .clone()
+            .chain(initial.flat_map(char::to_uppercase))
+            .chain(SPARE_CHARS.chars())
+            .find(|c| !self.0.contains_key(c) && *c != EMPTY && *c != '=')
+            .context("ran out of characters for the legend")?;
+        self.insert(c, tile)?;
+        Ok(c)
+    }
+}
+
+/// Maps as text, for hand-made rooms and readable tests:
+///
+/// ```text
+/// ~ = water
+/// ---
+/// #####
+/// #.~.#
+/// #####
+/// ```
+///
+/// The header up to `---` is optional and adds to or overrides the [`Legend`]. Rows go from the
+/// top of the map down, spaces are cells without a tile.
+///
+/// A `size = WxH` line in the header fixes the dimensions and the rows are taken as they are.
+/// Without one, blank lines around the rows and indentation shared by all rows are ignored, so
+/// maps can be written inline in code.
+impl TileMap {
+    pub fn from_ascii(text: &str, tiles: &TileRegistry) -> Result<Self> {
+        let lines: Vec<&str> = text.lines().collect();
+        let ((legend, size), rows) = match lines.iter().position(|line| line.trim() == HEADER_END) {
+            Some(end) => (Legend::parse(&lines[..end])?, &lines[end + 1..]),
+            None => ((Legend::default(), None), &lines[..]),
+        };
+        let rows: Vec<&str> = match size {
+            Some(size) => sized_rows(rows, size)?,
+            None => dedent(rows),
+        };
+        let (width, height) = size.unwrap_or_else(|| {
+            let width = rows.iter().map(|row| row.chars().count()).max();
+            (width.unwrap_or(0), rows.len())
+        });
+        if width == 0 || height == 0 {
+            bail!("the map has no rows")
+        }
+
+        let mut map = TileMap::filled(width, height, TileId::FLOOR)?;
+        for (row, line) in rows.iter().enumerate() {
+            let y = height - 1 - row;
+            let mut chars = line.chars();
+            for x in 0..width {
+                let c = chars.next().unwrap_or(EMPTY);
+                let ty = match c {
+                    EMPTY => None,
+                    c => {
+                        let name = legend.get(c).with_context(|| {
+                            format!("row {}: `{}` is not in the legend", row + 1, c)
+                        })?;
+                        let id = tiles
+                            .id(name)
+                            .with_context(|| format!("`{}` is not in the tile registry", name))?;
+                        Some(id)
+                    }
+                };
+                map.set_in(GROUND_LAYER, x, y, ty)?;
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// Writes the map with its size and a header line for every tile that isn't a wall or
+    /// floor. Only works for maps with just the ground layer.
+    pub fn to_ascii(&self, tiles: &TileRegistry) -> Result<String> {
+        if self.layers.len() > 1 {
+            bail!(
+                "ASCII maps only have a ground layer, this one has {} layers",
+                self.layers.len()
+            )
+        }
+
+        let mut legend = Legend::default();
+        let mut used = BTreeSet::new();
+        let mut rows = Vec::with_capacity(self.height);
+        for y in (0..self.height).rev() {
+            let mut row = String::with_capacity(self.width);
+            for x in 0..self.width {
+                let c = match self.get_in(GROUND_LAYER, x, y) {
+                    None => EMPTY,
+                    Some(id) => {
+                        let name = &tiles
+                            .get(id)
+                            .with_context(|| format!("tile {} is not in the registry", id.0))?
+                            .name;
+                        match legend.char_for(name) {
+                            Some(c) => c,
+                            None => legend.assign(name)?,
+                        }
+                    }
+                };
+                used.insert(c);
+                row.push(c);
+            }
+            rows.push(row.trim_end().to_string());
+        }
+
+        let default = Legend::default();
+        let mut text = format!("{} = {}x{}\n", SIZE_KEY, self.width, self.height);
+        for c in used {
+            match legend.get(c) {
+                Some(name) if default.get(c) != Some(name) => {
+                    text.push_str(&format!("{} = {}\n", c, name));
+                }
+                _ => {}
+            }
+        }
+        text.push_str(HEADER_END);
+        text.push('\n');
+        for row in rows {
+            text.push_str(&row);
+            text.push('\n');
+        }
+        Ok(text)
+    }
+
+    pub fn load_ascii(path: &Path, tiles: &TileRegistry) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::from_ascii(&text, tiles).with_context(|| format!("loading {}", path.display()))
+    }
+
+    pub fn save_ascii(&self, path: &Path, tiles: &TileRegistry) -> Result<()> {
+        std::fs::write(path, self.to_ascii(tiles)?)
+            .with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+fn parse_size(value: &str) -> Result<(usize, usize)> {
+    let (width, height) = value
+        .split_once('x')
+        .context("expected `<width>x<height>`")?;
+    Ok((width.trim().parse()?, height.trim().parse()?))
+}
+
+/// Checks the rows against the size from the header. Lines below the map have to be blank.
+fn sized_rows<'a>(rows: &[&'a str], (width, height): (usize, usize)) -> Result<Vec<&'a str>> {
+    if rows.len() < height {
+        bail!("expected {} rows, found {}", height, rows.len())
+    }
+    if let Some(extra) = rows[height..].iter().position(|row| !row.trim().is_empty()) {
+        bail!(
+            "row {} is below the {} rows of the map",
+            height + extra + 1,
+            height
+        )
+    }
+    let rows = &rows[..height];
+    if let Some(row) = rows
+        .iter()
+        .position(|row| row.trim_end().chars().count() > width)
+    {
+        bail!("row {} is wider than {} tiles", row + 1, width)
+    }
+    Ok(rows.to_vec())
+}
+
+/// Drops blank lines around the rows and the indentation they share, counted in characters.
+fn dedent<'a>(rows: &[&'a str]) -> Vec<&'a str> {
+    let is_blank = |row: &&str| row.trim().is_empty();
+    let start = rows
+        .iter()
+        .position(|row| !is_blank(row))
+        .unwrap_or(rows.len());
+    let end = rows
+        .iter()
+        .rposition(|row| !is_blank(row))
+        .map_or(start, |i| i + 1);
+    let rows = &rows[start..end];
+    let indent = rows
+        .iter()
+        .filter(|row| !is_blank(row))
+        .map(|row| row.chars().take_while(|c| c.is_whitespace()).count())
+        .min()
+        .unwrap_or(0);
+
+    // Every row that isn't blank starts with at least `indent` whitespace characters.
+    rows.iter()
+        .map(|row| {
+            row.char_indices()
+                .nth(indent)
+                .map_or("", |(i, _)| &row[i..])
+        })
+        .map(str::trim_end)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> TileRegistry {
+        TileRegistry::from_ron(
+            r#"[
+                (name: "floor", sprite: "floor.png", walkable: true),
+                (name: "wall", sprite: "wall.png"),
+                (name: "water", sprite: "water.png"),
+                (name: "door", sprite: "door.png", walkable: true),
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_ascii_round_trip() -> Result<()> {
+        let tiles = registry();
+        let map = TileMap::from_ascii(
+            "
+            ~ = water
+            + = door
+            ---
+            #####
+            #.~.+
+            ## #
+            ",
+            &tiles,
+        )?;
+        assert_eq!((5, 3), map.dimensions());
+        assert_eq!(Some(TileId(2)), map.get(2, 1));
+        assert_eq!(Some(TileId(3)), map.get(4, 1));
+        assert_eq!(Some(TileId::WALL), map.get(0, 0));
+        assert_eq!(None, map.get(2, 0));
+        assert_eq!(None, map.get(4, 0));
+
+        let text = map.to_ascii(&tiles)?;
+        assert_eq!(
+            "size = 5x3\nd = door\nw = water\n---\n#####\n#.w.d\n## #\n",
+            text
+        );
+        assert_eq!(map, TileMap::from_ascii(&text, &tiles)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_edges_survive_a_round_trip() -> Result<()> {
+        let tiles = registry();
+        let mut map = TileMap::filled(4, 4, TileId::FLOOR)?;
+        for (x, y) in [(0, 0), (0, 1), (0, 2), (0, 3), (1, 3), (2, 3), (3, 3)] {
+            map.set_in(GROUND_LAYER, x, y, None)?;
+        }
+        map.set(3, 0, TileId(2))?;
+
+        let text = map.to_ascii(&tiles)?;
+        assert_eq!("size = 4x4\nw = water\n---\n\n ...\n ...\n ..w\n", text);
+        assert_eq!(map, TileMap::from_ascii(&text, &tiles)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_indentation_is_counted_in_characters() -> Result<()> {
+        let tiles = registry();
+        // A no-break space is two bytes, so a byte offset of one would split it.
+        let map = TileMap::from_ascii("\u{a0}#\n .", &tiles)?;
+        assert_eq!((1, 2), map.dimensions());
+        assert_eq!(Some(TileId::WALL), map.get(0, 1));
+        let map = TileMap::from_ascii("é = water\n---\n\t\té#\n\t\t .\n", &tiles)?;
+        assert_eq!((2, 2), map.dimensions());
+        assert_eq!(Some(TileId(2)), map.get(0, 1));
+        assert_eq!(None, map.get(0, 0));
+        assert_eq!(Some(TileId::FLOOR), map.get(1, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_ascii_maps_are_rejected() -> Result<()> {
+        let tiles = registry();
+        let error = |text| format!("{:#}", TileMap::from_ascii(text, &tiles).unwrap_err());
+
+        assert!(error("#?#").contains("`?` is not in the legend"));
+        assert!(error("L = lava\n---\n#L#").contains("`lava` is not in the tile registry"));
+        assert!(error("water\n---\n#").contains("expected `<char> = <tile>`"));
+        assert!(error("\n\n").contains("no rows"));
+        assert!(error("size = 3x1\n---\n####").contains("wider than 3 tiles"));
+        assert!(error("size = 3x2\n---\n###").contains("expected 2 rows, found 1"));
+        assert!(error("size = 3x1\n---\n###\n#").contains("row 2 is below"));
+        assert!(error("size = 3\n---\n###").contains("invalid size"));
+
+        let mut layered = TileMap::new(3, 3)?;
+        layered.add_layer("decoration")?;
+        assert!(layered.to_ascii(&tiles).is_err());
+        Ok(())
+    }
+}
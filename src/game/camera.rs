@@ -0,0 +1,26 @@
+use cgmath::{Vector2, Zero};
+use serde::{Deserialize, Serialize};
+
+use super::tween::Tweens;
+
+/// Where the renderer looks. The simulation owns it so camera moves can be tweened, saved and
+/// rolled back like everything else.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CameraRig {
+    /// Offset from the centre of the map, in tiles.
+    pub position: Vector2<f32>,
+    /// Larger values zoom in.
+    pub zoom: f32,
+    /// Camera moves, played like the [`Tweens`] of an entity.
+    pub tweens: Tweens,
+}
+
+impl Default for CameraRig {
+    fn default() -> Self {
+        Self {
+            position: Vector2::zero(),
+            zoom: 1.0,
+            tweens: Tweens::default(),
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::VecDeque;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::rng::Rng;
+use super::tiles::TileId;
+use super::TileMap;
+
+/// Caves by cellular automaton: the map starts as random noise and every iteration a tile
+/// becomes wall if enough of its eight neighbours are walls, which smooths the noise into
+/// caverns. Afterwards everything but the largest cavern is filled in, so the whole cave can be
+/// walked.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CaveConfig {
+    pub width: usize,
+    pub height: usize,
+    /// Chance of a tile starting out as wall, in percent.
+    pub fill_percent: u32,
+    pub iterations: u32,
+    /// A floor tile with at least this many wall neighbours becomes wall.
+    pub birth_limit: u8,
+    /// A wall tile with at least this many wall neighbours stays wall.
+    pub survival_limit: u8,
+}
+
+impl Default for CaveConfig {
+    fn default() -> Self {
+        Self {
+            width: 64,
+            height: 48,
+            fill_percent: 45,
+            iterations: 5,
+            birth_limit: 5,
+            survival_limit: 4,
+        }
+    }
+}
+
+impl CaveConfig {
+    /// Same seed, same cave. The border is always wall.
+    pub fn generate(&self, seed: u64) -> Result<TileMap> {
+        if self.width < 3 || self.height < 3 {
+            bail!(
+                "a {}x{} map has no room for a cave",
+                self.width,
+                self.height
+            )
+        }
+        if self.fill_percent > 100 {
+            bail!("fill percentage of {} is above 100", self.fill_percent)
+        }
+
+        let (width, height) = (self.width, self.height);
+        let border = |x: usize, y: usize| x == 0 || y == 0 || x == width - 1 || y == height - 1;
+        let mut rng = Rng::new(seed);
+        let mut walls: Vec<bool> = (0..width * height)
+            .map(|i| border(i % width, i / width) || rng.below(100) < self.fill_percent)
+            .collect();
+
+        for _ in 0..self.iterations {
+            walls = (0..width * height)
+                .map(|i| {
+                    let (x, y) = (i % width, i / width);
+                    if border(x, y) {
+                        return true;
+                    }
+                    let neighbours = wall_neighbours(&walls, width, x, y);
+                    if walls[i] {
+                        neighbours >= self.survival_limit
+                    } else {
+                        neighbours >= self.birth_limit
+                    }
+                })
+                .collect();
+        }
+
+        let mut map = TileMap::filled(width, height, TileId::WALL)?;
+        for (i, _) in walls.iter().enumerate().filter(|(_, wall)| !**wall) {
+            map.set(i % width, i / width, TileId::FLOOR)?;
+        }
+
+        let mut regions = floor_regions(&map);
+        let Some(largest) = (0..regions.len()).max_by_key(|i| regions[*i].len()) else {
+            bail!("seed {} left no floor, try a lower fill percentage", seed)
+        };
+        regions.swap_remove(largest);
+        for (x, y) in regions.into_iter().flatten() {
+            map.set(x, y, TileId::WALL)?;
+        }
+        Ok(map)
+    }
+}
+
+fn wall_neighbours(walls: &[bool], width: usize, x: usize, y: usize) -> u8 {
+    let mut count = 0;
+    for ny in y - 1..=y + 1 {
+        for nx in x - 1..=x + 1 {
+            if (nx, ny) != (x, y) && walls[ny * width + nx] {
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+/// Groups the floor tiles of the ground layer into areas connected horizontally or vertically.
+pub fn floor_regions(map: &TileMap) -> Vec<Vec<(usize, usize)>> {
+    let (width, height) = map.dimensions();
+    let mut seen = vec![false; width * height];
+    let mut regions = Vec::new();
+
+    for start in 0..width * height {
+        let (sx, sy) = (start % width, start / width);
+        if seen[start] || map.get(sx, sy) != Some(TileId::FLOOR) {
+            continue;
+        }
+
+        let mut region = Vec::new();
+        let mut queue = VecDeque::from([(sx, sy)]);
+        seen[start] = true;
+        while let Some((x, y)) = queue.pop_front() {
+            region.push((x, y));
+            let neighbours = [
+                (x.wrapping_sub(1), y),
+                (x + 1, y),
+                (x, y.wrapping_sub(1)),
+                (x, y + 1),
+            ];
+            for (nx, ny) in neighbours {
+                if map.get(nx, ny) == Some(TileId::FLOOR) && !seen[ny * width + nx] {
+                    seen[ny * width + nx] = true;
+                    queue.push_back((nx, ny));
+                }
+            }
+        }
+        regions.push(region);
+    }
+
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caves_are_one_connected_area() -> Result<()> {
+        let config = CaveConfig::default();
+        for seed in 0..10 {
+            let map = config.generate(seed)?;
+            let regions = floor_regions(&map);
+            assert_eq!(1, regions.len(), "seed {} has pockets", seed);
+            // Not just a few tiles left over.
+            assert!(regions[0].len() > config.width * config.height / 5);
+
+            let (width, height) = map.dimensions();
+            for x in 0..width {
+                assert_eq!(Some(TileId::WALL), map.get(x, 0));
+                assert_eq!(Some(TileId::WALL), map.get(x, height - 1));
+            }
+            for y in 0..height {
+                assert_eq!(Some(TileId::WALL), map.get(0, y));
+                assert_eq!(Some(TileId::WALL), map.get(width - 1, y));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_caves_are_deterministic() -> Result<()> {
+        let config = CaveConfig {
+            width: 30,
+            height: 20,
+            fill_percent: 40,
+            iterations: 3,
+            ..Default::default()
+        };
+        assert_eq!(config.generate(3)?, config.generate(3)?);
+        assert_ne!(config.generate(3)?, config.generate(4)?);
+
+        let solid = CaveConfig {
+            fill_percent: 100,
+            ..config.clone()
+        };
+        assert!(solid.generate(3).is_err());
+        let overfull = CaveConfig {
+            fill_percent: 101,
+            ..config
+        };
+        assert!(overfull.generate(3).is_err());
+        Ok(())
+    }
+}
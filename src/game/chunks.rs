@@ -0,0 +1,385 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::camera::CameraRig;
+use super::tiles::TileId;
+use super::{next_version, GameState, TileMap, GROUND_LAYER};
+
+/// Width and height of a chunk in tiles.
+pub const CHUNK_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ChunkCoord {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl ChunkCoord {
+    pub fn new(x: i32, y: i32) -> Self {
+        Self { x, y }
+    }
+
+    /// The chunk containing tile `(x, y)` and the position of the tile inside of it.
+    pub fn of_tile(x: i64, y: i64) -> (Self, (usize, usize)) {
+        let size = CHUNK_SIZE as i64;
+        let coord = Self::new(x.div_euclid(size) as i32, y.div_euclid(size) as i32);
+        let local = (x.rem_euclid(size) as usize, y.rem_euclid(size) as usize);
+        (coord, local)
+    }
+
+    /// Position of the chunk's bottom left tile.
+    pub fn origin(self) -> (i64, i64) {
+        let size = CHUNK_SIZE as i64;
+        (self.x as i64 * size, self.y as i64 * size)
+    }
+
+    /// Distance in chunks, diagonals counting as one.
+    fn distance(self, other: ChunkCoord) -> i32 {
+        (self.x - other.x).abs().max((self.y - other.y).abs())
+    }
+}
+
+/// Creates chunks the first time they are needed. Has to be deterministic, untouched chunks are
+/// thrown away when unloaded and generated again when the camera comes back.
+pub struct ChunkGenerator(Box<dyn Fn(ChunkCoord) -> Result<TileMap> + Send + Sync>);
+
+impl ChunkGenerator {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(ChunkCoord) -> Result<TileMap> + Send + Sync + 'static,
+    {
+        Self(Box::new(f))
+    }
+
+    /// Chunks that are all `tile`.
+    pub fn filled(tile: TileId) -> Self {
+        Self::new(move |_| TileMap::filled(CHUNK_SIZE, CHUNK_SIZE, tile))
+    }
+
+    pub fn generate(&self, coord: ChunkCoord) -> Result<TileMap> {
+        let chunk = (self.0)(coord).with_context(|| format!("generating chunk {:?}", coord))?;
+        if chunk.dimensions() != (CHUNK_SIZE, CHUNK_SIZE) {
+            bail!(
+                "chunk {:?} is {:?} tiles instead of {}x{}",
+                coord,
+                chunk.dimensions(),
+                CHUNK_SIZE,
+                CHUNK_SIZE
+            )
+        }
+        Ok(chunk)
+    }
+}
+
+/// A loaded chunk. Only its tiles are compared and saved, the version is handed out anew.
+#[derive(Clone, Debug)]
+struct LoadedChunk {
+    map: TileMap,
+    version: u64,
+}
+
+impl LoadedChunk {
+    fn new(map: TileMap) -> Self {
+        Self {
+            map,
+            version: next_version(),
+        }
+    }
+}
+
+impl PartialEq for LoadedChunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.map == other.map
+    }
+}
+
+impl Serialize for LoadedChunk {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.map.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LoadedChunk {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        TileMap::deserialize(deserializer).map(Self::new)
+    }
+}
+
+/// Tile storage for maps without bounds, e.g. the overworld. Only chunks around the camera are
+/// loaded, see [`stream_chunks`]. Every chunk is a [`TileMap`] of its own, layers included.
+///
+/// Tile positions are signed, `(0, 0)` is the bottom left tile of chunk `(0, 0)`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChunkMap {
+    #[serde(with = "chunk_list")]
+    loaded: HashMap<ChunkCoord, LoadedChunk>,
+    /// Unloaded chunks that were changed since they were generated.
+    #[serde(with = "chunk_list")]
+    stored: HashMap<ChunkCoord, TileMap>,
+    changed: HashSet<ChunkCoord>,
+    /// Chunks this close to the camera get loaded.
+    pub load_radius: i32,
+    /// Chunks further away than this get unloaded. Larger than `load_radius`, so walking back
+    /// and forth over a chunk border doesn't load and unload the same chunks every time.
+    pub unload_radius: i32,
+}
+
+impl Default for ChunkMap {
+    fn default() -> Self {
+        Self::new(2, 3)
+    }
+}
+
+impl ChunkMap {
+    pub fn new(load_radius: i32, unload_radius: i32) -> Self {
+        Self {
+            loaded: HashMap::new(),
+            stored: HashMap::new(),
+            changed: HashSet::new(),
+            load_radius,
+            unload_radius: unload_radius.max(load_radius),
+        }
+    }
+
+    pub fn is_loaded(&self, coord: ChunkCoord) -> bool {
+        self.loaded.contains_key(&coord)
+    }
+
+    pub fn chunk(&self, coord: ChunkCoord) -> Option<&TileMap> {
+        self.loaded.get(&coord).map(|chunk| &chunk.map)
+    }
+
+    /// A loaded chunk to change, e.g. to add a layer. It is kept when unloaded from then on.
+    pub fn chunk_mut(&mut self, coord: ChunkCoord) -> Option<&mut TileMap> {
+        let chunk = self.loaded.get_mut(&coord)?;
+        chunk.version = next_version();
+        self.changed.insert(coord);
+        Some(&mut chunk.map)
+    }
+
+    /// Changes whenever the chunk at `coord` is loaded or changed, for caching what is built
+    /// from its tiles. `None` if it isn't loaded.
+    pub fn version(&self, coord: ChunkCoord) -> Option<u64> {
+        self.loaded.get(&coord).map(|chunk| chunk.version)
+    }
+
+    /// Loaded chunks, ordered by coordinate.
+    pub fn chunks(&self) -> impl Iterator<Item = (ChunkCoord, &TileMap)> {
+        let mut chunks: Vec<_> = self
+            .loaded
+            .iter()
+            .map(|(coord, chunk)| (*coord, &chunk.map))
+            .collect();
+        chunks.sort_by_key(|(coord, _)| *coord);
+        chunks.into_iter()
+    }
+
+    /// The ground tile at `(x, y)`, `None` if its chunk isn't loaded.
+    pub fn get(&self, x: i64, y: i64) -> Option<TileId> {
+        self.get_in(GROUND_LAYER, x, y)
+    }
+
+    /// Sets the ground tile at `(x, y)` and returns the previous one. Only works on loaded
+    /// chunks.
+    pub fn set(&mut self, x: i64, y: i64, ty: TileId) -> Result<TileId> {
+        let from = self.set_in(GROUND_LAYER, x, y, Some(ty))?;
+        Ok(from.unwrap_or_default())
+    }
+
+    /// The tile of layer `name` at `(x, y)`, `None` if there is none or its chunk isn't loaded.
+    pub fn get_in(&self, name: &str, x: i64, y: i64) -> Option<TileId> {
+        let (coord, (lx, ly)) = ChunkCoord::of_tile(x, y);
+        self.chunk(coord)?.get_in(name, lx, ly)
+    }
+
+    /// Places or clears a tile of layer `name` and returns the previous one. Only works on
+    /// loaded chunks.
+    pub fn set_in(
+        &mut self,
+        name: &str,
+        x: i64,
+        y: i64,
+        ty: Option<TileId>,
+    ) -> Result<Option<TileId>> {
+        let (coord, (lx, ly)) = ChunkCoord::of_tile(x, y);
+        let Some(chunk) = self.chunk_mut(coord) else {
+            bail!("chunk {:?} of tile ({}, {}) is not loaded", coord, x, y)
+        };
+        chunk.set_in(name, lx, ly, ty)
+    }
+
+    /// Loads the chunk at `coord` if it isn't yet, from the stored chunks or the generator.
+    pub fn load(&mut self, coord: ChunkCoord, generator: &ChunkGenerator) -> Result<()> {
+        if self.loaded.contains_key(&coord) {
+            return Ok(());
+        }
+
+        let chunk = match self.stored.remove(&coord) {
+            Some(chunk) => chunk,
+            None => generator.generate(coord)?,
+        };
+        self.loaded.insert(coord, LoadedChunk::new(chunk));
+        Ok(())
+    }
+
+    /// Unloads the chunk at `coord`, keeping it around if it was changed.
+    pub fn unload(&mut self, coord: ChunkCoord) {
+        let Some(chunk) = self.loaded.remove(&coord) else {
+            return;
+        };
+        if self.changed.remove(&coord) {
+            self.stored.insert(coord, chunk.map);
+        }
+    }
+
+    /// Loads every chunk within `load_radius` of the one containing tile `(x, y)` and unloads
+    /// those beyond `unload_radius`.
+    pub fn stream_around(&mut self, x: i64, y: i64, generator: &ChunkGenerator) -> Result<()> {
+        let (center, _) = ChunkCoord::of_tile(x, y);
+        let far: Vec<ChunkCoord> = self
+            .loaded
+            .keys()
+            .filter(|coord| coord.distance(center) > self.unload_radius)
+            .copied()
+            .collect();
+        for coord in far {
+            self.unload(coord);
+        }
+
+        let r = self.load_radius;
+        for y in center.y - r..=center.y + r {
+            for x in center.x - r..=center.x + r {
+                self.load(ChunkCoord::new(x, y), generator)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// JSON only allows string keys, so chunks are written as a list of pairs.
+mod chunk_list {
+    use super::*;
+
+    pub fn serialize<S: Serializer, T: Serialize>(
+        chunks: &HashMap<ChunkCoord, T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut list: Vec<_> = chunks.iter().collect();
+        list.sort_by_key(|(coord, _)| **coord);
+        list.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: DeserializeOwned>(
+        deserializer: D,
+    ) -> Result<HashMap<ChunkCoord, T>, D::Error> {
+        let list = Vec::<(ChunkCoord, T)>::deserialize(deserializer)?;
+        Ok(list.into_iter().collect())
+    }
+}
+
+/// Keeps the chunks around the camera loaded. Does nothing unless there is both a [`ChunkMap`]
+/// and a [`ChunkGenerator`]. Chunks are drawn at their tile positions rather than around the
+/// centre of the map, so the camera position is a tile position too.
+pub fn stream_chunks(game: &mut GameState) -> Result<()> {
+    let world = &game.world;
+    let Some(generator) = world.resource::<ChunkGenerator>() else {
+        return Ok(());
+    };
+    let Some(mut chunks) = world.borrow_resource_mut::<ChunkMap>()? else {
+        return Ok(());
+    };
+
+    let position = world
+        .resource::<CameraRig>()
+        .map_or(cgmath::Vector2::new(0.0, 0.0), |rig| rig.position);
+    chunks.stream_around(
+        position.x.round() as i64,
+        position.y.round() as i64,
+        &generator,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tile_coordinates() {
+        assert_eq!((ChunkCoord::new(0, 0), (5, 31)), ChunkCoord::of_tile(5, 31));
+        assert_eq!(
+            (ChunkCoord::new(-1, 1), (31, 0)),
+            ChunkCoord::of_tile(-1, 32)
+        );
+        assert_eq!((-32, 32), ChunkCoord::new(-1, 1).origin());
+    }
+
+    #[test]
+    fn test_chunks_stream_around_a_position() -> Result<()> {
+        let generator = ChunkGenerator::filled(TileId::FLOOR);
+        let mut chunks = ChunkMap::new(1, 2);
+        chunks.stream_around(0, 0, &generator)?;
+        assert_eq!(9, chunks.chunks().count());
+        assert_eq!(Some(TileId::FLOOR), chunks.get(-32, 40));
+        assert_eq!(None, chunks.get(64, 0));
+
+        chunks.set(-1, -1, TileId::WALL)?;
+        // One chunk over, the old ones are still within the unload radius.
+        chunks.stream_around(32, 0, &generator)?;
+        assert_eq!(12, chunks.chunks().count());
+        assert!(chunks.is_loaded(ChunkCoord::new(-1, -1)));
+        // Far away, the changed chunk was stored instead of thrown away.
+        chunks.stream_around(128, 0, &generator)?;
+        assert!(!chunks.is_loaded(ChunkCoord::new(-1, -1)));
+        assert!(chunks.set(-1, -1, TileId::FLOOR).is_err());
+        chunks.stream_around(0, 0, &generator)?;
+        assert_eq!(Some(TileId::WALL), chunks.get(-1, -1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_changes_to_any_layer_are_kept() -> Result<()> {
+        let generator = ChunkGenerator::filled(TileId::FLOOR);
+        let mut chunks = ChunkMap::new(0, 0);
+        chunks.stream_around(0, 0, &generator)?;
+        let origin = ChunkCoord::new(0, 0);
+        let version = chunks.version(origin).unwrap();
+        assert_eq!(version, chunks.clone().version(origin).unwrap());
+
+        chunks.chunk_mut(origin).unwrap().add_layer("rugs")?;
+        assert_ne!(Some(version), chunks.version(origin));
+        let version = chunks.version(origin).unwrap();
+        chunks.set_in("rugs", 3, 4, Some(TileId(2)))?;
+        assert_ne!(Some(version), chunks.version(origin));
+
+        chunks.stream_around(100, 0, &generator)?;
+        assert_eq!(None, chunks.version(origin));
+        chunks.stream_around(0, 0, &generator)?;
+        assert_eq!(Some(TileId(2)), chunks.get_in("rugs", 3, 4));
+        assert_eq!(Some(TileId::FLOOR), chunks.get(3, 4));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_chunks_follows_the_camera() -> Result<()> {
+        let mut game = GameState::new()?;
+        game.world_mut()
+            .insert_resource(ChunkGenerator::filled(TileId::FLOOR));
+        game.world_mut().insert_resource(ChunkMap::new(0, 0));
+        game.world_mut()
+            .resource_mut::<CameraRig>()
+            .unwrap()
+            .position = cgmath::Vector2::new(100.0, -5.0);
+        stream_chunks(&mut game)?;
+
+        let chunks = game.world().resource::<ChunkMap>().unwrap();
+        let loaded: Vec<ChunkCoord> = chunks.chunks().map(|(coord, _)| coord).collect();
+        assert_eq!(vec![ChunkCoord::new(3, -1)], loaded);
+
+        let json = serde_json::to_string(&*chunks)?;
+        assert_eq!(*chunks, serde_json::from_str::<ChunkMap>(&json)?);
+        Ok(())
+    }
+}
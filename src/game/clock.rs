@@ -0,0 +1,17 @@
+use serde::{Deserialize, Serialize};
+
+/// Simulation time, counted in fixed ticks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Clock {
+    tick: u64,
+}
+
+impl Clock {
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn advance(&mut self) {
+        self.tick += 1;
+    }
+}
@@ -0,0 +1,51 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::ecs::Entity;
+use super::GameState;
+
+/// Display name of a monster, item or prop.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Name(pub String);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Health {
+    pub current: u32,
+    pub max: u32,
+}
+
+impl Health {
+    pub fn new(max: u32) -> Self {
+        Self { current: max, max }
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.current == 0
+    }
+}
+
+/// Despawns everything whose health ran out. The despawn sends the [`super::events::EntityDied`].
+pub fn despawn_dead(game: &mut GameState) -> Result<()> {
+    let (world, commands) = game.world_and_commands();
+    for (entity, health) in &mut world.query::<(Entity, &Health)>()? {
+        if health.is_dead() {
+            commands.despawn(entity);
+        }
+    }
+    Ok(())
+}
+
+/// Colour multiplied into an entity's sprite, white leaves it unchanged.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Tint(pub [f32; 4]);
+
+impl Tint {
+    pub const WHITE: Tint = Tint([1.0; 4]);
+}
+
+impl Default for Tint {
+    fn default() -> Self {
+        Self::WHITE
+    }
+}
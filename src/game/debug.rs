@@ -0,0 +1,164 @@
+use std::any::type_name;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use winit::keyboard::KeyCode;
+
+use super::ecs::{SerializedWorld, SkippedTypes};
+use super::state::StateSnapshot;
+use super::tiles::{TileDef, TileRegistry};
+use super::{Commands, GameState};
+
+/// Writes a world dump when pressed.
+pub const DUMP_KEY: KeyCode = KeyCode::F12;
+
+/// Where the hotkey puts dumps, relative to the working directory.
+pub const DUMP_DIR: &str = "dumps";
+
+/// Everything needed to reproduce a bug report: the map and the tiles it uses, all entities with
+/// their components, the resources and the state stack. Uses the save format for the world, so
+/// only registered components and resources are included. The rest is listed in `skipped`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct WorldDump {
+    /// Milliseconds since the unix epoch.
+    pub dumped_at: u128,
+    pub states: StateSnapshot,
+    pub tiles: Vec<TileDef>,
+    pub world: SerializedWorld,
+    pub skipped: SkippedTypes,
+}
+
+impl WorldDump {
+    pub fn new(game: &GameState) -> Result<Self> {
+        let (world, mut skipped) = game.serializers.serialize_lossy(&game.world)?;
+        // Written separately.
+        skipped
+            .resources
+            .retain(|name| name != type_name::<TileRegistry>());
+        Ok(Self {
+            dumped_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or_default(),
+            states: game.states.snapshot(),
+            tiles: game.tiles().defs().to_vec(),
+            world,
+            skipped,
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let json =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_str(&json).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// Writes the dump as pretty JSON into `dir`, named after the time it was taken.
+    pub fn write(&self, dir: &Path) -> Result<PathBuf> {
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        let path = dir.join(format!("dump-{}.json", self.dumped_at));
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("writing {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+/// Dumps the world to [`DUMP_DIR`] when [`DUMP_KEY`] is pressed. Failing to dump only gets
+/// reported, it's not worth stopping the game for.
+pub fn handle_dump_key(game: &GameState) {
+    if !game.input.is_physical_key_pressed(DUMP_KEY) {
+        return;
+    }
+
+    match WorldDump::new(game).and_then(|dump| dump.write(Path::new(DUMP_DIR))) {
+        Ok(path) => log::info!("Dumped world to {}", path.display()),
+        Err(err) => log::error!("Dumping the world failed: {:#}", err),
+    }
+}
+
+impl GameState {
+    /// Builds a fresh game from a dump written by the debug hotkey. Prefabs and scripts are
+    /// assets rather than world data, so load them separately if the bug needs them.
+    pub fn from_dump(path: &Path) -> Result<Self> {
+        let dump = WorldDump::read(path)?;
+        let mut game = GameState::new()?;
+        game.load_dump(dump)
+            .with_context(|| format!("loading {}", path.display()))?;
+        Ok(game)
+    }
+
+    /// Replaces the world, tiles and state stack with `dump`. No state hooks run.
+    pub fn load_dump(&mut self, dump: WorldDump) -> Result<()> {
+        let tiles = TileRegistry::new(dump.tiles).context("loading tiles")?;
+        self.serializers
+            .deserialize_into(&mut self.world, dump.world)?;
+        self.world.insert_resource(tiles);
+        self.states.restore(&dump.states);
+        self.commands = Commands::new();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::components::{Health, Name};
+    use crate::game::ecs::Transform;
+    use crate::game::state::{self, AppState, Transition};
+    use crate::game::tiles::TileId;
+
+    struct Secret;
+
+    #[test]
+    fn test_dump_round_trip() -> Result<()> {
+        let mut game = GameState::new()?;
+        state::apply_transitions(&mut game);
+        game.states_mut()
+            .request(Transition::Push(AppState::Playing));
+        state::apply_transitions(&mut game);
+        let goblin = game.world_mut().spawn();
+        game.world_mut().insert(goblin, Name("Goblin".into()))?;
+        game.world_mut().insert(goblin, Health::new(5))?;
+        game.world_mut()
+            .insert(goblin, Transform::from_xy(2.0, 3.0))?;
+        game.set_tile(4, 4, TileId::WALL)?;
+        let mut tiles = game.tiles().defs().to_vec();
+        tiles.push(TileDef {
+            name: "water".into(),
+            ..tiles[0].clone()
+        });
+        game.world_mut().insert_resource(TileRegistry::new(tiles)?);
+        // Neither is registered for saving.
+        game.world_mut().insert(goblin, Secret)?;
+        game.world_mut().insert_resource(Secret);
+
+        let dump = WorldDump::new(&game)?;
+        assert_eq!(vec![type_name::<Secret>()], dump.skipped.components);
+        assert!(dump
+            .skipped
+            .resources
+            .contains(&type_name::<Secret>().to_string()));
+        game.world_mut().remove::<Secret>(goblin);
+
+        let dir = std::env::temp_dir().join(format!("game-dump-{}", std::process::id()));
+        let path = WorldDump::new(&game)?.write(&dir)?;
+        let loaded = GameState::from_dump(&path);
+        let _ = std::fs::remove_dir_all(&dir);
+        let loaded = loaded?;
+
+        assert_eq!(
+            game.serializers.serialize(&game.world)?,
+            loaded.serializers.serialize(&loaded.world)?
+        );
+        assert_eq!(game.states.snapshot(), loaded.states.snapshot());
+        assert_eq!(
+            &[AppState::MainMenu, AppState::Playing],
+            loaded.states.stack()
+        );
+        assert_eq!(Some(TileId::WALL), loaded.map().get(4, 4));
+        assert_eq!(Some(TileId(2)), loaded.tiles().id("water"));
+        Ok(())
+    }
+}
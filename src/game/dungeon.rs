@@ -0,0 +1,407 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::rng::Rng;
+use super::tiles::TileId;
+use super::TileMap;
+
+/// How [`BspConfig::generate`] joins rooms.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CorridorStyle {
+    /// One horizontal and one vertical run.
+    #[default]
+    LShaped,
+    /// A single run where the rooms share rows or columns, otherwise two runs joined halfway
+    /// between the rooms.
+    Straight,
+    /// A random walk that drifts towards the other room.
+    Winding,
+}
+
+/// A rectangle of floor in a generated map.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Room {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Room {
+    pub fn center(&self) -> (usize, usize) {
+        (self.x + self.width / 2, self.y + self.height / 2)
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+
+    pub fn intersects(&self, other: &Room) -> bool {
+        self.x < other.x + other.width
+            && other.x < self.x + self.width
+            && self.y < other.y + other.height
+            && other.y < self.y + self.height
+    }
+}
+
+/// A generated map and the rooms carved into it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dungeon {
+    pub map: TileMap,
+    pub rooms: Vec<Room>,
+}
+
+/// Rooms and corridors by binary space partitioning: the map is split in two again and again,
+/// every part gets a room and the two halves of every split are joined by a corridor, so all
+/// rooms are reachable.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BspConfig {
+    pub width: usize,
+    pub height: usize,
+    /// Smallest width and height of a room, walls not included.
+    pub min_room_size: usize,
+    pub max_room_size: usize,
+    /// How many times the map gets split, up to `2^split_depth` rooms.
+    pub split_depth: u32,
+    pub corridor: CorridorStyle,
+}
+
+impl Default for BspConfig {
+    fn default() -> Self {
+        Self {
+            width: 64,
+            height: 48,
+            min_room_size: 4,
+            max_room_size: 12,
+            split_depth: 4,
+            corridor: CorridorStyle::LShaped,
+        }
+    }
+}
+
+/// A part of the map during partitioning. Rooms keep a column and row free on the right and
+/// top, so rooms of neighbouring leaves never touch.
+#[derive(Clone, Copy, Debug)]
+struct Leaf {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl BspConfig {
+    /// Same seed, same dungeon.
+    pub fn generate(&self, seed: u64) -> Result<Dungeon> {
+        if self.min_room_size == 0 || self.max_room_size < self.min_room_size {
+            bail!(
+                "room sizes must be between 1 and a maximum of at least the minimum, got {}..={}",
+                self.min_room_size,
+                self.max_room_size
+            )
+        }
+        // The outer wall plus the free row and column of the leaf.
+        if self.width < self.min_room_size + 2 || self.height < self.min_room_size + 2 {
+            bail!(
+                "a {}x{} map has no space for a room of at least {} tiles",
+                self.width,
+                self.height,
+                self.min_room_size
+            )
+        }
+
+        let mut generator = Generator {
+            config: self,
+            rng: Rng::new(seed),
+            map: TileMap::filled(self.width, self.height, TileId::WALL)?,
+            rooms: Vec::new(),
+        };
+        let root = Leaf {
+            x: 1,
+            y: 1,
+            width: self.width - 1,
+            height: self.height - 1,
+        };
+        generator.partition(root, self.split_depth)?;
+
+        Ok(Dungeon {
+            map: generator.map,
+            rooms: generator.rooms,
+        })
+    }
+}
+
+struct Generator<'a> {
+    config: &'a BspConfig,
+    rng: Rng,
+    map: TileMap,
+    rooms: Vec<Room>,
+}
+
+impl Generator<'_> {
+    /// Splits `leaf` up to `depth` times and returns the range of rooms placed inside of it.
+    fn partition(&mut self, leaf: Leaf, depth: u32) -> Result<std::ops::Range<usize>> {
+        let Some((a, b)) = self.split(leaf).filter(|_| depth > 0) else {
+            let room = self.place_room(leaf)?;
+            self.rooms.push(room);
+            return Ok(self.rooms.len() - 1..self.rooms.len());
+        };
+
+        let first = self.partition(a, depth - 1)?;
+        let second = self.partition(b, depth - 1)?;
+        let (from, to) = self.closest_rooms(first.clone(), second.clone());
+        self.connect(from, to)?;
+        Ok(first.start..second.end)
+    }
+
+    fn split(&mut self, leaf: Leaf) -> Option<(Leaf, Leaf)> {
+        let smallest = self.config.min_room_size + 1;
+        let can_split_x = leaf.width >= 2 * smallest;
+        let can_split_y = leaf.height >= 2 * smallest;
+        // Cut across the long side, so leaves don't end up as thin strips.
+        let split_x = match (can_split_x, can_split_y) {
+            (false, false) => return None,
+            (true, false) => true,
+            (false, true) => false,
+            _ if leaf.width * 4 > leaf.height * 5 => true,
+            _ if leaf.height * 4 > leaf.width * 5 => false,
+            _ => self.rng.chance(0.5),
+        };
+
+        if split_x {
+            let at = self.rng.range(smallest..leaf.width - smallest + 1);
+            let a = Leaf { width: at, ..leaf };
+            let b = Leaf {
+                x: leaf.x + at,
+                width: leaf.width - at,
+                ..leaf
+            };
+            Some((a, b))
+        } else {
+            let at = self.rng.range(smallest..leaf.height - smallest + 1);
+            let a = Leaf { height: at, ..leaf };
+            let b = Leaf {
+                y: leaf.y + at,
+                height: leaf.height - at,
+                ..leaf
+            };
+            Some((a, b))
+        }
+    }
+
+    fn place_room(&mut self, leaf: Leaf) -> Result<Room> {
+        let (min, max) = (self.config.min_room_size, self.config.max_room_size);
+        let width = self.rng.range(min..max.min(leaf.width - 1) + 1);
+        let height = self.rng.range(min..max.min(leaf.height - 1) + 1);
+        let room = Room {
+            x: leaf.x + self.rng.range(0..leaf.width - width),
+            y: leaf.y + self.rng.range(0..leaf.height - height),
+            width,
+            height,
+        };
+
+        for y in room.y..room.y + room.height {
+            for x in room.x..room.x + room.width {
+                self.map.set(x, y, TileId::FLOOR)?;
+            }
+        }
+        Ok(room)
+    }
+
+    /// The pair of rooms from both ranges whose centres are closest.
+    fn closest_rooms(
+        &self,
+        first: std::ops::Range<usize>,
+        second: std::ops::Range<usize>,
+    ) -> (Room, Room) {
+        let distance = |a: &Room, b: &Room| {
+            let ((ax, ay), (bx, by)) = (a.center(), b.center());
+            ax.abs_diff(bx) + ay.abs_diff(by)
+        };
+        let mut best = (self.rooms[first.start], self.rooms[second.start]);
+        for a in &self.rooms[first] {
+            for b in &self.rooms[second.clone()] {
+                if distance(a, b) < distance(&best.0, &best.1) {
+                    best = (*a, *b);
+                }
+            }
+        }
+        best
+    }
+
+    fn connect(&mut self, from: Room, to: Room) -> Result<()> {
+        let start = from.center();
+        let end = to.center();
+        match self.config.corridor {
+            CorridorStyle::LShaped => {
+                let corner = if self.rng.chance(0.5) {
+                    (end.0, start.1)
+                } else {
+                    (start.0, end.1)
+                };
+                self.carve_line(start, corner)?;
+                self.carve_line(corner, end)
+            }
+            CorridorStyle::Straight => {
+                let shared_x = overlap(from.x, from.width, to.x, to.width);
+                let shared_y = overlap(from.y, from.height, to.y, to.height);
+                match (shared_x, shared_y) {
+                    (Some(x), _) => self.carve_line((x, start.1), (x, end.1)),
+                    (_, Some(y)) => self.carve_line((start.0, y), (end.0, y)),
+                    _ => {
+                        let middle = (start.0 + end.0) / 2;
+                        self.carve_line(start, (middle, start.1))?;
+                        self.carve_line((middle, start.1), (middle, end.1))?;
+                        self.carve_line((middle, end.1), end)
+                    }
+                }
+            }
+            CorridorStyle::Winding => self.carve_winding(start, end),
+        }
+    }
+
+    /// Carves a horizontal or vertical line, both ends included.
+    fn carve_line(&mut self, from: (usize, usize), to: (usize, usize)) -> Result<()> {
+        for y in from.1.min(to.1)..=from.1.max(to.1) {
+            for x in from.0.min(to.0)..=from.0.max(to.0) {
+                self.map.set(x, y, TileId::FLOOR)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn carve_winding(&mut self, from: (usize, usize), to: (usize, usize)) -> Result<()> {
+        let (width, height) = self.map.dimensions();
+        let (mut x, mut y) = from;
+        // Mostly steps towards the target, so this gets there quickly. The cap is only there so
+        // an unlucky walk can't go on forever, a straight finish still connects the rooms.
+        let mut steps = 4 * (from.0.abs_diff(to.0) + from.1.abs_diff(to.1)) + 64;
+        self.map.set(x, y, TileId::FLOOR)?;
+        while (x, y) != to && steps > 0 {
+            steps -= 1;
+            let towards_x = x != to.0 && (y == to.1 || self.rng.chance(0.5));
+            if self.rng.chance(0.7) {
+                if towards_x {
+                    x = if to.0 > x { x + 1 } else { x - 1 };
+                } else {
+                    y = if to.1 > y { y + 1 } else { y - 1 };
+                }
+            } else {
+                // Sideways, staying off the outer wall.
+                let forward = self.rng.chance(0.5);
+                if towards_x {
+                    y = step_within(y, forward, height);
+                } else {
+                    x = step_within(x, forward, width);
+                }
+            }
+            self.map.set(x, y, TileId::FLOOR)?;
+        }
+
+        if (x, y) != to {
+            self.carve_line((x, y), (to.0, y))?;
+            self.carve_line((to.0, y), to)?;
+        }
+        Ok(())
+    }
+}
+
+/// The middle of the span two ranges share, if they do.
+fn overlap(a: usize, a_len: usize, b: usize, b_len: usize) -> Option<usize> {
+    let start = a.max(b);
+    let end = (a + a_len).min(b + b_len);
+    (start < end).then(|| (start + end - 1) / 2)
+}
+
+/// One step up or down, staying in `1..len - 1`.
+fn step_within(value: usize, forward: bool, len: usize) -> usize {
+    if forward && value + 2 < len {
+        value + 1
+    } else if !forward && value > 1 {
+        value - 1
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::cave::floor_regions;
+
+    #[test]
+    fn test_every_room_is_reachable() -> Result<()> {
+        for corridor in [
+            CorridorStyle::LShaped,
+            CorridorStyle::Straight,
+            CorridorStyle::Winding,
+        ] {
+            let config = BspConfig {
+                corridor,
+                ..Default::default()
+            };
+            for seed in 0..20 {
+                let Dungeon { map, rooms } = config.generate(seed)?;
+                assert!(rooms.len() > 1);
+                let regions = floor_regions(&map);
+                let region_of = |room: &Room| {
+                    let center = room.center();
+                    regions.iter().position(|region| region.contains(&center))
+                };
+                let first = region_of(&rooms[0]);
+                assert!(first.is_some());
+                for room in &rooms {
+                    assert_eq!(
+                        first,
+                        region_of(room),
+                        "{:?} seed {}: {:?} is cut off",
+                        corridor,
+                        seed,
+                        room
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_rooms_follow_the_config() -> Result<()> {
+        let config = BspConfig {
+            width: 40,
+            height: 30,
+            min_room_size: 3,
+            max_room_size: 6,
+            split_depth: 3,
+            corridor: CorridorStyle::Winding,
+        };
+        let dungeon = config.generate(7)?;
+        assert_eq!(dungeon, config.generate(7)?);
+        assert_ne!(dungeon, config.generate(8)?);
+        assert!(dungeon.rooms.len() <= 8);
+
+        for (i, room) in dungeon.rooms.iter().enumerate() {
+            assert!((3..=6).contains(&room.width) && (3..=6).contains(&room.height));
+            assert!(room.x >= 1 && room.x + room.width < config.width);
+            assert!(room.y >= 1 && room.y + room.height < config.height);
+            for other in &dungeon.rooms[i + 1..] {
+                assert!(!room.intersects(other));
+            }
+        }
+        // The outer wall stays closed.
+        for x in 0..config.width {
+            assert_eq!(Some(TileId::WALL), dungeon.map.get(x, 0));
+            assert_eq!(Some(TileId::WALL), dungeon.map.get(x, config.height - 1));
+        }
+        for y in 0..config.height {
+            assert_eq!(Some(TileId::WALL), dungeon.map.get(0, y));
+            assert_eq!(Some(TileId::WALL), dungeon.map.get(config.width - 1, y));
+        }
+
+        let too_small = BspConfig {
+            width: 4,
+            ..config.clone()
+        };
+        assert!(too_small.generate(0).is_err());
+        Ok(())
+    }
+}
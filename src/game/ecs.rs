@@ -0,0 +1,626 @@
+use std::any::{type_name, Any, TypeId};
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicIsize, Ordering};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+pub use self::commands::{CommandEntity, Commands, EntityCommands};
+pub use self::event::{EventBus, EventReader, Events};
+pub use self::hierarchy::{propagate_transforms, Children, GlobalTransform, Parent, Transform};
+pub use self::query::{Changed, Mut, Query, QueryData, QueryFilter, With, Without};
+pub use self::resource::{ResMut, Resource};
+pub use self::serialize::{SerializeRegistry, SerializedEntity, SerializedWorld, SkippedTypes};
+pub use self::snapshot::{EventsSnapshot, SnapshotRegistry, WorldSnapshot};
+
+pub mod commands;
+pub mod event;
+mod hierarchy;
+pub mod query;
+mod resource;
+mod serialize;
+mod snapshot;
+
+/// Handle to an entity living in a [`World`].
+///
+/// The generation is bumped every time a slot is freed, so a handle that outlived its entity is
+/// detected as stale instead of silently pointing at whatever got spawned into the same slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Entity {
+    index: u32,
+    generation: u32,
+}
+
+impl Entity {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+impl fmt::Display for Entity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+/// Allocator for entity ids. Freed indices are reused with a bumped generation.
+#[derive(Clone, Default)]
+struct Entities {
+    generations: Vec<u32>,
+    alive: Vec<bool>,
+    free: Vec<u32>,
+    len: usize,
+}
+
+impl Entities {
+    fn allocate(&mut self) -> Entity {
+        self.len += 1;
+
+        if let Some(index) = self.free.pop() {
+            self.alive[index as usize] = true;
+            return Entity {
+                index,
+                generation: self.generations[index as usize],
+            };
+        }
+
+        let index = self.generations.len() as u32;
+        self.generations.push(0);
+        self.alive.push(true);
+
+        Entity {
+            index,
+            generation: 0,
+        }
+    }
+
+    fn free(&mut self, entity: Entity) -> bool {
+        if !self.is_alive(entity) {
+            return false;
+        }
+
+        let idx = entity.index as usize;
+        self.alive[idx] = false;
+        self.generations[idx] = self.generations[idx].wrapping_add(1);
+        self.free.push(entity.index);
+        self.len -= 1;
+
+        true
+    }
+
+    fn is_alive(&self, entity: Entity) -> bool {
+        let idx = entity.index as usize;
+        self.alive.get(idx).copied().unwrap_or(false) && self.generations[idx] == entity.generation
+    }
+
+    fn iter(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.alive
+            .iter()
+            .enumerate()
+            .filter(|(_, alive)| **alive)
+            .map(|(idx, _)| Entity {
+                index: idx as u32,
+                generation: self.generations[idx],
+            })
+    }
+}
+
+/// Anything that can be attached to an entity.
+pub trait Component: Send + Sync + 'static {}
+
+impl<T: Send + Sync + 'static> Component for T {}
+
+/// Storage for a single component type, indexed by entity index. Next to every slot the tick of
+/// its last mutable access is kept for [`query::Changed`].
+pub struct Storage<T> {
+    slots: Vec<Option<T>>,
+    changed: Vec<u32>,
+}
+
+impl<T: Component> Storage<T> {
+    fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            changed: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, idx: usize, component: T, tick: u32) -> Option<T> {
+        if idx >= self.slots.len() {
+            self.slots.resize_with(idx + 1, || None);
+            self.changed.resize(idx + 1, 0);
+        }
+
+        self.changed[idx] = tick;
+        self.slots[idx].replace(component)
+    }
+
+    fn remove(&mut self, idx: usize) -> Option<T> {
+        self.slots.get_mut(idx).and_then(Option::take)
+    }
+
+    fn get(&self, idx: usize) -> Option<&T> {
+        self.slots.get(idx).and_then(Option::as_ref)
+    }
+
+    fn get_mut(&mut self, idx: usize, tick: u32) -> Option<&mut T> {
+        let component = self.slots.get_mut(idx).and_then(Option::as_mut)?;
+        self.changed[idx] = tick;
+        Some(component)
+    }
+}
+
+/// Type erased view on a [`Storage`], so the world can clean up after despawned entities without
+/// knowing every component type.
+trait AnyStorage: Send + Sync {
+    fn remove_entity(&mut self, idx: usize);
+    fn contains(&self, idx: usize) -> bool;
+    fn is_empty(&self) -> bool;
+    fn clear(&mut self);
+    fn type_name(&self) -> &'static str;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Component> AnyStorage for Storage<T> {
+    fn remove_entity(&mut self, idx: usize) {
+        self.remove(idx);
+    }
+
+    fn contains(&self, idx: usize) -> bool {
+        self.get(idx).is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.slots.iter().all(Option::is_none)
+    }
+
+    fn clear(&mut self) {
+        self.slots.clear();
+        self.changed.clear();
+    }
+
+    fn type_name(&self) -> &'static str {
+        type_name::<T>()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A storage or resource behind a runtime checked borrow flag, so queries can hand out mutable
+/// access to different types through a shared `&World`. Positive flag values count readers, -1
+/// marks a writer.
+struct BorrowCell<T: ?Sized> {
+    borrow: AtomicIsize,
+    /// Type name of the value, for telling types apart without downcasting.
+    type_name: &'static str,
+    value: UnsafeCell<Box<T>>,
+}
+
+// SAFETY: The value is only accessed through `&mut BorrowCell` or while holding a `BorrowGuard` of
+// the matching kind, and the value is `Send + Sync` itself.
+unsafe impl<T: ?Sized + Send + Sync> Sync for BorrowCell<T> {}
+
+impl<T: ?Sized> BorrowCell<T> {
+    fn new(type_name: &'static str, value: Box<T>) -> Self {
+        Self {
+            borrow: AtomicIsize::new(0),
+            type_name,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut().as_mut()
+    }
+
+    fn into_inner(self) -> Box<T> {
+        self.value.into_inner()
+    }
+
+    fn try_borrow(&self, write: bool) -> Option<BorrowGuard<'_>> {
+        if write {
+            self.borrow
+                .compare_exchange(0, -1, Ordering::Acquire, Ordering::Relaxed)
+                .ok()?;
+        } else {
+            let mut current = self.borrow.load(Ordering::Relaxed);
+            loop {
+                if current < 0 {
+                    return None;
+                }
+
+                match self.borrow.compare_exchange_weak(
+                    current,
+                    current + 1,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    std::result::Result::Ok(_) => break,
+                    Err(actual) => current = actual,
+                }
+            }
+        }
+
+        Some(BorrowGuard {
+            flag: &self.borrow,
+            write,
+        })
+    }
+
+    /// Like `try_borrow`, but with an error naming the conflicting type.
+    fn borrow(&self, name: &str, write: bool) -> Result<BorrowGuard<'_>> {
+        match self.try_borrow(write) {
+            Some(guard) => Ok(guard),
+            None if write => bail!("{} is already borrowed", name),
+            None => bail!("{} is already borrowed mutably", name),
+        }
+    }
+}
+
+/// Releases a storage borrow on drop.
+pub struct BorrowGuard<'w> {
+    flag: &'w AtomicIsize,
+    write: bool,
+}
+
+impl Drop for BorrowGuard<'_> {
+    fn drop(&mut self) {
+        if self.write {
+            self.flag.store(0, Ordering::Release);
+        } else {
+            self.flag.fetch_sub(1, Ordering::Release);
+        }
+    }
+}
+
+/// Shared reference to a component, keeping its storage borrowed while alive.
+pub struct Ref<'w, T> {
+    value: &'w T,
+    _guard: BorrowGuard<'w>,
+}
+
+impl<T> Deref for Ref<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+/// Raw view into a [`Storage`]. Only valid while the borrow it was created under is held.
+pub struct StorageView<T> {
+    slots: *mut Option<T>,
+    changed: *mut u32,
+    len: usize,
+    tick: u32,
+    last_tick: u32,
+}
+
+impl<T> StorageView<T> {
+    fn contains(&self, idx: usize) -> bool {
+        // SAFETY: In bounds, and the slot is not mutably referenced while queries check it.
+        idx < self.len && unsafe { (*self.slots.add(idx)).is_some() }
+    }
+
+    fn changed_since_last_tick(&self, idx: usize) -> bool {
+        // SAFETY: `contains` checks the bound first.
+        self.contains(idx) && unsafe { *self.changed.add(idx) } > self.last_tick
+    }
+
+    /// # Safety
+    /// The slot must be occupied and not mutably referenced for `'a`.
+    unsafe fn get<'a>(&self, idx: usize) -> &'a T {
+        (*self.slots.add(idx)).as_ref().unwrap()
+    }
+
+    /// # Safety
+    /// The view must come from a write borrow and the slot must be occupied and not otherwise
+    /// referenced for `'a`.
+    unsafe fn get_mut<'a>(&self, idx: usize) -> query::Mut<'a, T> {
+        query::Mut::new(
+            (*self.slots.add(idx)).as_mut().unwrap(),
+            &mut *self.changed.add(idx),
+            self.tick,
+        )
+    }
+}
+
+/// Owns all entities and their components.
+pub struct World {
+    entities: Entities,
+    storages: HashMap<TypeId, BorrowCell<dyn AnyStorage>>,
+    resources: HashMap<TypeId, BorrowCell<dyn Any + Send + Sync>>,
+    change_tick: u32,
+    last_change_tick: u32,
+    /// Entities despawned since the last [`World::take_despawned`] or [`World::clear_trackers`].
+    despawned: Vec<Entity>,
+}
+
+impl Default for World {
+    fn default() -> Self {
+        Self {
+            entities: Entities::default(),
+            storages: HashMap::new(),
+            resources: HashMap::new(),
+            change_tick: 1,
+            last_change_tick: 0,
+            despawned: Vec::new(),
+        }
+    }
+}
+
+impl World {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn spawn(&mut self) -> Entity {
+        self.entities.allocate()
+    }
+
+    /// Removes the entity and all of its components. Fails if the handle is stale. The entity is
+    /// detached from its parent and its children become roots, use
+    /// [`World::despawn_recursive`] to take them along.
+    pub fn despawn(&mut self, entity: Entity) -> Result<()> {
+        if !self.is_alive(entity) {
+            bail!("entity {} is not alive", entity)
+        }
+
+        self.remove_parent(entity);
+        for child in self.children(entity) {
+            self.remove::<Parent>(child);
+        }
+        self.entities.free(entity);
+
+        for storage in self.storages.values_mut() {
+            storage.get_mut().remove_entity(entity.index as usize);
+        }
+        self.despawned.push(entity);
+
+        Ok(())
+    }
+
+    /// Entities despawned since the last call, in the order they went.
+    pub fn take_despawned(&mut self) -> Vec<Entity> {
+        std::mem::take(&mut self.despawned)
+    }
+
+    pub fn is_alive(&self, entity: Entity) -> bool {
+        self.entities.is_alive(entity)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates all living entities in index order.
+    pub fn entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.entities.iter()
+    }
+
+    /// Attaches `component` to `entity`, returning the previous value of that type if any.
+    pub fn insert<T: Component>(&mut self, entity: Entity, component: T) -> Result<Option<T>> {
+        if !self.is_alive(entity) {
+            bail!("cannot insert component into dead entity {}", entity)
+        }
+
+        let tick = self.change_tick;
+        Ok(self
+            .storage_mut_or_default::<T>()
+            .insert(entity.index as usize, component, tick))
+    }
+
+    pub fn remove<T: Component>(&mut self, entity: Entity) -> Option<T> {
+        if !self.is_alive(entity) {
+            return None;
+        }
+
+        self.storage_mut::<T>()?.remove(entity.index as usize)
+    }
+
+    /// Borrows a component. Panics if its storage is currently borrowed mutably by a query.
+    pub fn get<T: Component>(&self, entity: Entity) -> Option<Ref<'_, T>> {
+        if !self.is_alive(entity) {
+            return None;
+        }
+
+        let guard = self
+            .borrow::<T>(false)
+            .unwrap_or_else(|err| panic!("{}", err))?;
+        // SAFETY: The read borrow is held by the returned `Ref`.
+        let storage = unsafe { &*self.storages[&TypeId::of::<T>()].value.get() };
+        let value = storage
+            .as_any()
+            .downcast_ref::<Storage<T>>()?
+            .get(entity.index as usize)?;
+
+        Some(Ref {
+            value,
+            _guard: guard,
+        })
+    }
+
+    /// Mutably borrows a component and marks it as changed.
+    pub fn get_mut<T: Component>(&mut self, entity: Entity) -> Option<&mut T> {
+        if !self.is_alive(entity) {
+            return None;
+        }
+
+        let tick = self.change_tick;
+        self.storage_mut::<T>()?
+            .get_mut(entity.index as usize, tick)
+    }
+
+    pub fn has<T: Component>(&self, entity: Entity) -> bool {
+        if !self.is_alive(entity) {
+            return false;
+        }
+
+        let Some(_guard) = self
+            .borrow::<T>(false)
+            .unwrap_or_else(|err| panic!("{}", err))
+        else {
+            return false;
+        };
+        // SAFETY: The read borrow is held by `_guard`.
+        let storage = unsafe { &*self.storages[&TypeId::of::<T>()].value.get() };
+        storage.contains(entity.index as usize)
+    }
+
+    /// Queries all entities that have every component in `Q`.
+    pub fn query<Q: QueryData>(&self) -> Result<Query<'_, Q>> {
+        Query::new(self)
+    }
+
+    /// Like [`World::query`], additionally restricted by the filter `F`.
+    pub fn query_filtered<Q: QueryData, F: QueryFilter>(&self) -> Result<Query<'_, Q, F>> {
+        Query::new(self)
+    }
+
+    /// Ends the current change detection window. Everything changed before this call no longer
+    /// matches [`query::Changed`].
+    pub fn clear_trackers(&mut self) {
+        self.last_change_tick = self.change_tick;
+        self.change_tick += 1;
+        self.despawned.clear();
+    }
+
+    fn borrow<T: Component>(&self, write: bool) -> Result<Option<BorrowGuard<'_>>> {
+        self.borrow_by_id(TypeId::of::<T>(), type_name::<T>(), write)
+    }
+
+    fn borrow_by_id(&self, id: TypeId, name: &str, write: bool) -> Result<Option<BorrowGuard<'_>>> {
+        match self.storages.get(&id) {
+            Some(cell) => cell.borrow(name, write).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// # Safety
+    /// A borrow of `T` must be held for as long as the view is used, and it must be a write
+    /// borrow if `write` is set.
+    unsafe fn view<T: Component>(&self, write: bool) -> Option<StorageView<T>> {
+        let cell = self.storages.get(&TypeId::of::<T>())?;
+        let (slots, changed, len) = if write {
+            let storage = (*cell.value.get())
+                .as_any_mut()
+                .downcast_mut::<Storage<T>>()?;
+            (
+                storage.slots.as_mut_ptr(),
+                storage.changed.as_mut_ptr(),
+                storage.slots.len(),
+            )
+        } else {
+            let storage = (*cell.value.get()).as_any().downcast_ref::<Storage<T>>()?;
+            (
+                storage.slots.as_ptr() as *mut _,
+                storage.changed.as_ptr() as *mut _,
+                storage.slots.len(),
+            )
+        };
+
+        Some(StorageView {
+            slots,
+            changed,
+            len,
+            tick: self.change_tick,
+            last_tick: self.last_change_tick,
+        })
+    }
+
+    fn storage_mut<T: Component>(&mut self) -> Option<&mut Storage<T>> {
+        self.storages
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|s| s.get_mut().as_any_mut().downcast_mut())
+    }
+
+    fn storage_mut_or_default<T: Component>(&mut self) -> &mut Storage<T> {
+        self.storages
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| BorrowCell::new(type_name::<T>(), Box::new(Storage::<T>::new())))
+            .get_mut()
+            .as_any_mut()
+            .downcast_mut()
+            .expect("storage registered under the wrong type id")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Position(i32, i32);
+
+    #[derive(Debug, PartialEq)]
+    struct Health(u32);
+
+    #[test]
+    fn test_spawn_and_despawn() -> Result<()> {
+        let mut world = World::new();
+        let a = world.spawn();
+        let b = world.spawn();
+        assert_eq!(2, world.len());
+
+        world.despawn(a)?;
+        assert!(!world.is_alive(a));
+        assert!(world.is_alive(b));
+        assert_eq!(vec![b], world.entities().collect::<Vec<_>>());
+        assert!(world.despawn(a).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stale_handle_is_detected_after_reuse() -> Result<()> {
+        let mut world = World::new();
+        let old = world.spawn();
+        world.insert(old, Health(3))?;
+        world.despawn(old)?;
+
+        let new = world.spawn();
+        assert_eq!(old.index(), new.index());
+        assert_ne!(old.generation(), new.generation());
+
+        assert!(world.get::<Health>(new).is_none());
+        assert!(world.get::<Health>(old).is_none());
+        assert!(world.insert(old, Health(1)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_get_remove() -> Result<()> {
+        let mut world = World::new();
+        let e = world.spawn();
+
+        assert_eq!(None, world.insert(e, Position(1, 2))?);
+        assert_eq!(Some(Position(1, 2)), world.insert(e, Position(3, 4))?);
+        world.insert(e, Health(10))?;
+
+        world.get_mut::<Health>(e).unwrap().0 -= 4;
+        assert_eq!(Some(&Health(6)), world.get::<Health>(e).as_deref());
+        assert!(world.has::<Position>(e));
+
+        assert_eq!(Some(Position(3, 4)), world.remove::<Position>(e));
+        assert!(!world.has::<Position>(e));
+        assert!(world.has::<Health>(e));
+        Ok(())
+    }
+}
@@ -0,0 +1,272 @@
+use super::{Component, Entity, World};
+
+/// Entity a command refers to: one that already exists or one spawned earlier by the same
+/// [`Commands`] buffer, which only gets its real id once the buffer is applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandEntity {
+    Existing(Entity),
+    Spawned(usize),
+}
+
+impl CommandEntity {
+    fn resolve(self, spawned: &[Entity]) -> Option<Entity> {
+        match self {
+            CommandEntity::Existing(entity) => Some(entity),
+            CommandEntity::Spawned(idx) => spawned.get(idx).copied(),
+        }
+    }
+}
+
+type CommandFn = Box<dyn FnOnce(&mut World, &mut Vec<Entity>) + Send>;
+
+/// Structural world changes recorded while systems iterate the world, applied later in one go.
+///
+/// Commands are applied in the order they were recorded. Commands targeting an entity that died
+/// before they got applied, e.g. because an earlier command despawned it, are skipped.
+#[derive(Default)]
+pub struct Commands {
+    queue: Vec<CommandFn>,
+    spawns: usize,
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records spawning a new entity. Components can be chained on the returned builder.
+    pub fn spawn(&mut self) -> EntityCommands<'_> {
+        let target = CommandEntity::Spawned(self.spawns);
+        self.spawns += 1;
+        self.push(|world, spawned| spawned.push(world.spawn()));
+
+        EntityCommands {
+            commands: self,
+            target,
+        }
+    }
+
+    pub fn entity(&mut self, entity: Entity) -> EntityCommands<'_> {
+        self.entity_for(CommandEntity::Existing(entity))
+    }
+
+    /// Records commands for `target`, e.g. an entity spawned earlier by this buffer.
+    pub fn entity_for(&mut self, target: CommandEntity) -> EntityCommands<'_> {
+        EntityCommands {
+            commands: self,
+            target,
+        }
+    }
+
+    pub fn despawn(&mut self, entity: Entity) {
+        self.entity(entity).despawn();
+    }
+
+    /// Records an arbitrary change to the world.
+    pub fn add<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut World) + Send + 'static,
+    {
+        self.push(move |world, _| f(world));
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Moves all commands of `other` to the end of this buffer, keeping their order.
+    pub fn append(&mut self, other: &mut Commands) {
+        let offset = self.spawns;
+        for command in other.queue.drain(..) {
+            self.queue.push(Box::new(move |world, spawned| {
+                // `other` numbered its spawns from zero, so give it a view starting at its first.
+                let mut own = spawned.split_off(offset.min(spawned.len()));
+                command(world, &mut own);
+                spawned.append(&mut own);
+            }));
+        }
+        self.spawns += std::mem::take(&mut other.spawns);
+    }
+
+    /// Applies and clears all recorded commands.
+    pub fn apply(&mut self, world: &mut World) {
+        let mut spawned = Vec::with_capacity(self.spawns);
+        for command in self.queue.drain(..) {
+            command(world, &mut spawned);
+        }
+        self.spawns = 0;
+    }
+
+    fn push<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut World, &mut Vec<Entity>) + Send + 'static,
+    {
+        self.queue.push(Box::new(f));
+    }
+}
+
+/// Records commands for a single entity.
+pub struct EntityCommands<'a> {
+    commands: &'a mut Commands,
+    target: CommandEntity,
+}
+
+impl EntityCommands<'_> {
+    /// The entity these commands target, usable in later commands of the same buffer.
+    pub fn id(&self) -> CommandEntity {
+        self.target
+    }
+
+    pub fn insert<T: Component>(self, component: T) -> Self {
+        let target = self.target;
+        self.commands.push(move |world, spawned| {
+            if let Some(entity) = target.resolve(spawned) {
+                // A dead entity means it was despawned earlier, which is not an error here.
+                let _ = world.insert(entity, component);
+            }
+        });
+        self
+    }
+
+    pub fn remove<T: Component>(self) -> Self {
+        let target = self.target;
+        self.commands.push(move |world, spawned| {
+            if let Some(entity) = target.resolve(spawned) {
+                world.remove::<T>(entity);
+            }
+        });
+        self
+    }
+
+    /// Attaches this entity to `parent`, see [`World::set_parent`]. Skipped if that fails.
+    pub fn set_parent(self, parent: CommandEntity) -> Self {
+        let target = self.target;
+        self.commands.push(move |world, spawned| {
+            if let (Some(child), Some(parent)) = (target.resolve(spawned), parent.resolve(spawned))
+            {
+                let _ = world.set_parent(child, parent);
+            }
+        });
+        self
+    }
+
+    pub fn despawn(self) {
+        let target = self.target;
+        self.commands.push(move |world, spawned| {
+            if let Some(entity) = target.resolve(spawned) {
+                let _ = world.despawn(entity);
+            }
+        });
+    }
+
+    /// Despawns this entity and everything attached to it.
+    pub fn despawn_recursive(self) {
+        let target = self.target;
+        self.commands.push(move |world, spawned| {
+            if let Some(entity) = target.resolve(spawned) {
+                let _ = world.despawn_recursive(entity);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Position(i32, i32);
+
+    struct Health(u32);
+
+    #[derive(Debug, PartialEq)]
+    struct Loot(&'static str);
+
+    #[test]
+    fn test_despawn_dead_and_drop_loot_in_one_tick() -> Result<()> {
+        let mut world = World::new();
+        let mut monsters = Vec::new();
+        for (x, hp) in [(0, 3), (1, 0), (2, 0)] {
+            let e = world.spawn();
+            world.insert(e, Position(x, 0))?;
+            world.insert(e, Health(hp))?;
+            monsters.push(e);
+        }
+
+        let mut commands = Commands::new();
+        let mut query = world.query::<(Entity, &Position, &Health)>()?;
+        for (entity, pos, health) in &mut query {
+            if health.0 == 0 {
+                commands.despawn(entity);
+                commands.spawn().insert(*pos).insert(Loot("gold"));
+            }
+        }
+        drop(query);
+
+        // Nothing changes until the buffer is applied.
+        assert_eq!(3, world.len());
+        commands.apply(&mut world);
+        assert!(commands.is_empty());
+
+        assert!(world.is_alive(monsters[0]));
+        assert!(!world.is_alive(monsters[1]));
+        assert!(!world.is_alive(monsters[2]));
+
+        let mut loot = world.query::<(&Position, &Loot)>()?;
+        let drops: Vec<Position> = loot.iter().map(|(pos, _)| *pos).collect();
+        assert_eq!(vec![Position(1, 0), Position(2, 0)], drops);
+        Ok(())
+    }
+
+    #[test]
+    fn test_commands_apply_in_recorded_order() -> Result<()> {
+        let mut world = World::new();
+        let e = world.spawn();
+
+        let mut commands = Commands::new();
+        commands.entity(e).insert(Loot("a")).remove::<Loot>();
+        let spawned = commands.spawn().insert(Loot("b")).id();
+        commands.add(|world| {
+            let names: Vec<&str> = world
+                .entities()
+                .filter_map(|e| world.get::<Loot>(e).map(|l| l.0))
+                .collect();
+            assert_eq!(vec!["b"], names);
+        });
+        commands.despawn(e);
+        commands.entity(e).insert(Loot("c"));
+        assert_eq!(CommandEntity::Spawned(0), spawned);
+        commands.apply(&mut world);
+
+        assert!(!world.is_alive(e));
+        assert_eq!(1, world.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_keeps_spawn_references() -> Result<()> {
+        let mut world = World::new();
+        let mut first = Commands::new();
+        first.spawn().insert(Loot("first"));
+
+        let mut second = Commands::new();
+        let id = second.spawn().id();
+        second.spawn();
+        second.add(|_| {});
+        second.entity_for(id).insert(Loot("second"));
+
+        first.append(&mut second);
+        first.apply(&mut world);
+
+        let mut loot = world.query::<(Entity, &Loot)>()?;
+        let found: Vec<(u32, &str)> = loot.iter().map(|(e, l)| (e.index(), l.0)).collect();
+        assert_eq!(vec![(0, "first"), (1, "second")], found);
+        Ok(())
+    }
+}
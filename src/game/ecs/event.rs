@@ -0,0 +1,313 @@
+use std::any::{Any, TypeId};
+use std::collections::{BTreeSet, HashMap};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Anything that can be sent through an [`EventBus`].
+pub trait Event: Send + Sync + 'static {}
+
+impl<T: Send + Sync + 'static> Event for T {}
+
+/// Double buffered queue of events of one type.
+///
+/// Events sent during a tick stay readable for that tick and the following one, then they are
+/// dropped by [`Events::update`]. That way a reader running before the sender in the schedule
+/// still sees every event exactly once.
+pub struct Events<E> {
+    previous: Vec<E>,
+    previous_start: usize,
+    current: Vec<E>,
+    current_start: usize,
+    /// How far each [`EventReader`] got. Kept with the events instead of in the readers, so
+    /// restoring a snapshot of the queue rolls its readers back as well.
+    cursors: Mutex<HashMap<u64, usize>>,
+}
+
+impl<E> Default for Events<E> {
+    fn default() -> Self {
+        Self {
+            previous: Vec::new(),
+            previous_start: 0,
+            current: Vec::new(),
+            current_start: 0,
+            cursors: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<E: Clone> Clone for Events<E> {
+    fn clone(&self) -> Self {
+        Self {
+            previous: self.previous.clone(),
+            previous_start: self.previous_start,
+            current: self.current.clone(),
+            current_start: self.current_start,
+            cursors: Mutex::new(self.cursors().clone()),
+        }
+    }
+}
+
+impl<E: PartialEq> PartialEq for Events<E> {
+    fn eq(&self, other: &Self) -> bool {
+        // Copied first, so comparing a queue with itself doesn't lock it twice.
+        let cursors = self.cursors().clone();
+        self.previous == other.previous
+            && self.previous_start == other.previous_start
+            && self.current == other.current
+            && self.current_start == other.current_start
+            && cursors == *other.cursors()
+    }
+}
+
+impl<E> Events<E> {
+    fn cursors(&self) -> MutexGuard<'_, HashMap<u64, usize>> {
+        // The map is only ever updated in one go, a panicking reader can't leave it half done.
+        self.cursors.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<E: Event> Events<E> {
+    pub fn send(&mut self, event: E) {
+        self.current.push(event);
+    }
+
+    /// Drops the events of the previous tick and starts a new buffer. Cursors of readers that
+    /// were dropped are forgotten.
+    pub fn update(&mut self) {
+        let live = live_readers();
+        self.cursors
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|id, _| live.contains(id));
+        drop(live);
+
+        self.previous = std::mem::take(&mut self.current);
+        self.previous_start = self.current_start;
+        self.current_start = self.previous_start + self.previous.len();
+    }
+
+    pub fn len(&self) -> usize {
+        self.previous.len() + self.current.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops all events, readers continue with the next one sent.
+    pub fn clear(&mut self) {
+        let total = self.total();
+        self.previous.clear();
+        self.current.clear();
+        self.previous_start = total;
+        self.current_start = total;
+    }
+
+    fn total(&self) -> usize {
+        self.current_start + self.current.len()
+    }
+}
+
+static NEXT_READER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Ids of the readers that still exist. A reader doesn't know every queue holding its cursor,
+/// snapshots copy them, so the queues check here instead.
+static LIVE_READERS: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+
+fn live_readers() -> MutexGuard<'static, BTreeSet<u64>> {
+    LIVE_READERS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Reads an [`Events`] queue. Every reader sees every event once, independent of other
+/// readers. Systems usually keep their reader in the closure.
+pub struct EventReader<E> {
+    id: u64,
+    _marker: PhantomData<fn() -> E>,
+}
+
+impl<E> Default for EventReader<E> {
+    fn default() -> Self {
+        let id = NEXT_READER_ID.fetch_add(1, Ordering::Relaxed);
+        live_readers().insert(id);
+        Self {
+            id,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<E> Drop for EventReader<E> {
+    fn drop(&mut self) {
+        live_readers().remove(&self.id);
+    }
+}
+
+impl<E: Event> EventReader<E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the events this reader has not seen yet. Events that were dropped before the
+    /// reader got to them are skipped.
+    pub fn read<'a>(&mut self, events: &'a Events<E>) -> impl Iterator<Item = &'a E> {
+        let total = events.total();
+        let next = events.cursors().insert(self.id, total).unwrap_or(0);
+        let start = next.max(events.previous_start).min(total);
+
+        let previous = events
+            .previous
+            .get(start - events.previous_start..)
+            .unwrap_or_default();
+        let current = &events.current[start.saturating_sub(events.current_start)..];
+
+        previous.iter().chain(current)
+    }
+}
+
+pub(super) trait AnyEvents: Send + Sync {
+    fn update(&mut self);
+    fn clear(&mut self);
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<E: Event> AnyEvents for Events<E> {
+    fn update(&mut self) {
+        Events::update(self)
+    }
+
+    fn clear(&mut self) {
+        Events::clear(self)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// One [`Events`] queue per event type.
+#[derive(Default)]
+pub struct EventBus {
+    pub(super) queues: HashMap<TypeId, Box<dyn AnyEvents>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn send<E: Event>(&mut self, event: E) {
+        self.queue_mut::<E>().send(event)
+    }
+
+    /// Reads the events of type `E` that `reader` has not seen yet.
+    pub fn read<'a, E: Event>(
+        &'a self,
+        reader: &mut EventReader<E>,
+    ) -> Box<dyn Iterator<Item = &'a E> + 'a> {
+        match self.queue::<E>() {
+            Some(events) => Box::new(reader.read(events)),
+            None => Box::new(std::iter::empty()),
+        }
+    }
+
+    pub fn queue<E: Event>(&self) -> Option<&Events<E>> {
+        self.queues
+            .get(&TypeId::of::<E>())
+            .and_then(|q| q.as_any().downcast_ref())
+    }
+
+    pub fn queue_mut<E: Event>(&mut self) -> &mut Events<E> {
+        self.queues
+            .entry(TypeId::of::<E>())
+            .or_insert_with(|| Box::new(Events::<E>::default()))
+            .as_any_mut()
+            .downcast_mut()
+            .expect("event queue registered under the wrong type id")
+    }
+
+    /// Advances every queue by one tick. Called once at the end of each tick.
+    pub fn update(&mut self) {
+        for queue in self.queues.values_mut() {
+            queue.update();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Hit(u32);
+
+    #[test]
+    fn test_events_live_for_two_ticks() {
+        let mut bus = EventBus::new();
+        let mut early = EventReader::<Hit>::new();
+        let mut late = EventReader::<Hit>::new();
+
+        // The early reader runs before the sender, the late one after it.
+        assert_eq!(0, bus.read(&mut early).count());
+        bus.send(Hit(1));
+        bus.send(Hit(2));
+        assert_eq!(
+            vec![&Hit(1), &Hit(2)],
+            bus.read(&mut late).collect::<Vec<_>>()
+        );
+        bus.update();
+
+        assert_eq!(
+            vec![&Hit(1), &Hit(2)],
+            bus.read(&mut early).collect::<Vec<_>>()
+        );
+        bus.send(Hit(3));
+        assert_eq!(vec![&Hit(3)], bus.read(&mut late).collect::<Vec<_>>());
+        bus.update();
+
+        assert_eq!(vec![&Hit(3)], bus.read(&mut early).collect::<Vec<_>>());
+        bus.update();
+        assert!(bus.queue::<Hit>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_lagging_reader_skips_dropped_events() {
+        let mut events = Events::default();
+        let mut reader = EventReader::new();
+
+        events.send(Hit(1));
+        events.update();
+        events.send(Hit(2));
+        events.update();
+        events.send(Hit(3));
+
+        assert_eq!(
+            vec![&Hit(2), &Hit(3)],
+            reader.read(&events).collect::<Vec<_>>()
+        );
+        assert_eq!(0, reader.read(&events).count());
+    }
+
+    #[test]
+    fn test_dropped_readers_are_forgotten() {
+        let mut events = Events::default();
+        let mut kept = EventReader::new();
+        let mut dropped = EventReader::new();
+        events.send(Hit(1));
+        assert_eq!(1, kept.read(&events).count());
+        assert_eq!(1, dropped.read(&events).count());
+        assert_eq!(2, events.cursors().len());
+
+        drop(dropped);
+        events.update();
+        assert_eq!(
+            vec![kept.id],
+            events.cursors().keys().copied().collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use anyhow::{bail, Result};
+use cgmath::{Vector2, Zero};
+use serde::{Deserialize, Serialize};
+
+use super::{Entity, World};
+
+/// Position relative to the parent, or to the world for entities without one.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Transform {
+    pub translation: Vector2<f32>,
+}
+
+impl Transform {
+    pub fn from_xy(x: f32, y: f32) -> Self {
+        Self {
+            translation: Vector2::new(x, y),
+        }
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: Vector2::zero(),
+        }
+    }
+}
+
+/// World position computed by [`propagate_transforms`]. Don't write it directly.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GlobalTransform(pub Transform);
+
+impl GlobalTransform {
+    pub fn translation(&self) -> Vector2<f32> {
+        self.0.translation
+    }
+}
+
+/// Entity this one is attached to. Kept in sync with [`Children`] by [`World::set_parent`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Parent(pub Entity);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Children(pub Vec<Entity>);
+
+impl World {
+    pub fn parent(&self, entity: Entity) -> Option<Entity> {
+        self.get::<Parent>(entity).map(|p| p.0)
+    }
+
+    pub fn children(&self, entity: Entity) -> Vec<Entity> {
+        self.get::<Children>(entity)
+            .map(|c| c.0.clone())
+            .unwrap_or_default()
+    }
+
+    /// Attaches `child` to `parent`, detaching it from its previous parent. Fails if either is
+    /// dead or if `parent` is `child` itself or one of its descendants.
+    pub fn set_parent(&mut self, child: Entity, parent: Entity) -> Result<()> {
+        if !self.is_alive(child) || !self.is_alive(parent) {
+            bail!("cannot attach {} to {}, both must be alive", child, parent)
+        }
+
+        let mut ancestor = Some(parent);
+        while let Some(current) = ancestor {
+            if current == child {
+                bail!("attaching {} to {} would create a cycle", child, parent)
+            }
+            ancestor = self.parent(current);
+        }
+
+        self.remove_parent(child);
+        self.insert(child, Parent(parent))?;
+        match self.get_mut::<Children>(parent) {
+            Some(children) => children.0.push(child),
+            None => {
+                self.insert(parent, Children(vec![child]))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Detaches `child` from its parent, making it a root. Returns the old parent.
+    pub fn remove_parent(&mut self, child: Entity) -> Option<Entity> {
+        let Parent(parent) = self.remove::<Parent>(child)?;
+        if let Some(children) = self.get_mut::<Children>(parent) {
+            children.0.retain(|c| *c != child);
+        }
+
+        Some(parent)
+    }
+
+    /// Despawns `entity` together with all of its descendants.
+    pub fn despawn_recursive(&mut self, entity: Entity) -> Result<()> {
+        if !self.is_alive(entity) {
+            bail!("entity {} is not alive", entity)
+        }
+
+        self.remove_parent(entity);
+        let mut stack = vec![entity];
+        while let Some(current) = stack.pop() {
+            stack.extend(self.children(current));
+            // Children may have been despawned on their own already.
+            if self.is_alive(current) {
+                self.despawn(current)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Recomputes the [`GlobalTransform`] of every entity with a [`Transform`]. Children without a
+/// transform of their own pass their parent's position on to their children.
+pub fn propagate_transforms(world: &mut World) {
+    let roots: Vec<Entity> = world
+        .entities()
+        .filter(|e| world.parent(*e).is_none_or(|p| !world.is_alive(p)))
+        .collect();
+
+    let mut globals = Vec::new();
+    let mut stack: Vec<(Entity, Vector2<f32>)> =
+        roots.into_iter().map(|e| (e, Vector2::zero())).collect();
+    while let Some((entity, parent_translation)) = stack.pop() {
+        let translation = match world.get::<Transform>(entity) {
+            Some(local) => {
+                let translation = parent_translation + local.translation;
+                globals.push((entity, translation));
+                translation
+            }
+            None => parent_translation,
+        };
+
+        for child in world.children(entity) {
+            if world.is_alive(child) {
+                stack.push((child, translation));
+            }
+        }
+    }
+
+    for (entity, translation) in globals {
+        let global = GlobalTransform(Transform { translation });
+        // Only touch changed positions so `Changed<GlobalTransform>` stays meaningful.
+        if world.get::<GlobalTransform>(entity).as_deref() != Some(&global) {
+            world
+                .insert(entity, global)
+                .expect("entity died while propagating");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn_at(world: &mut World, x: f32, y: f32) -> Result<Entity> {
+        let e = world.spawn();
+        world.insert(e, Transform::from_xy(x, y))?;
+        Ok(e)
+    }
+
+    fn global(world: &World, e: Entity) -> (f32, f32) {
+        let t = world.get::<GlobalTransform>(e).unwrap().translation();
+        (t.x, t.y)
+    }
+
+    #[test]
+    fn test_propagation_and_reparenting() -> Result<()> {
+        let mut world = World::new();
+        let monster = spawn_at(&mut world, 3.0, 4.0)?;
+        let health_bar = spawn_at(&mut world, 0.0, 1.0)?;
+        let sword = spawn_at(&mut world, 0.5, 0.0)?;
+        let chest = spawn_at(&mut world, 10.0, 10.0)?;
+        world.set_parent(health_bar, monster)?;
+        world.set_parent(sword, monster)?;
+
+        propagate_transforms(&mut world);
+        assert_eq!((3.0, 5.0), global(&world, health_bar));
+        assert_eq!((3.5, 4.0), global(&world, sword));
+
+        world.get_mut::<Transform>(monster).unwrap().translation.x = 5.0;
+        world.set_parent(sword, chest)?;
+        propagate_transforms(&mut world);
+        assert_eq!((5.0, 5.0), global(&world, health_bar));
+        assert_eq!((10.5, 10.0), global(&world, sword));
+        assert_eq!(vec![health_bar], world.children(monster));
+        assert_eq!(Some(chest), world.parent(sword));
+
+        assert!(world.set_parent(chest, sword).is_err());
+        assert!(world.set_parent(chest, chest).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_despawn_recursive() -> Result<()> {
+        let mut world = World::new();
+        let wall = spawn_at(&mut world, 0.0, 0.0)?;
+        let torch = spawn_at(&mut world, 0.0, 0.5)?;
+        let flame = spawn_at(&mut world, 0.0, 0.2)?;
+        let other = spawn_at(&mut world, 1.0, 0.0)?;
+        world.set_parent(torch, wall)?;
+        world.set_parent(flame, torch)?;
+
+        world.despawn_recursive(torch)?;
+        assert!(!world.is_alive(torch));
+        assert!(!world.is_alive(flame));
+        assert!(world.is_alive(wall));
+        assert!(world.is_alive(other));
+        assert!(world.children(wall).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_despawn_detaches_from_hierarchy() -> Result<()> {
+        let mut world = World::new();
+        let monster = spawn_at(&mut world, 3.0, 4.0)?;
+        let health_bar = spawn_at(&mut world, 0.0, 1.0)?;
+        let sword = spawn_at(&mut world, 0.5, 0.0)?;
+        let gem = spawn_at(&mut world, 0.0, 0.1)?;
+        world.set_parent(health_bar, monster)?;
+        world.set_parent(sword, monster)?;
+        world.set_parent(gem, sword)?;
+
+        world.despawn(health_bar)?;
+        assert_eq!(vec![sword], world.children(monster));
+
+        world.despawn(monster)?;
+        assert_eq!(None, world.parent(sword));
+        assert_eq!(Some(sword), world.parent(gem));
+        propagate_transforms(&mut world);
+        assert_eq!((0.5, 0.0), global(&world, sword));
+        assert_eq!((0.5, 0.1), global(&world, gem));
+
+        let mut commands = crate::game::ecs::Commands::new();
+        commands.despawn(sword);
+        commands.apply(&mut world);
+        assert_eq!(None, world.parent(gem));
+        Ok(())
+    }
+}
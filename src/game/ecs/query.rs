@@ -0,0 +1,575 @@
+use std::any::{type_name, TypeId};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+use anyhow::{bail, Result};
+
+use super::{BorrowGuard, Component, Entity, StorageView, World};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ComponentId {
+    id: TypeId,
+    name: &'static str,
+}
+
+impl ComponentId {
+    fn of<T: Component>() -> Self {
+        Self {
+            id: TypeId::of::<T>(),
+            name: type_name::<T>(),
+        }
+    }
+}
+
+/// Component types a query reads, writes or only filters on.
+#[derive(Clone, Debug, Default)]
+pub struct Access {
+    reads: Vec<ComponentId>,
+    writes: Vec<ComponentId>,
+    filters: Vec<ComponentId>,
+}
+
+impl Access {
+    pub fn add_read<T: Component>(&mut self) -> Result<()> {
+        let component = ComponentId::of::<T>();
+        if self.writes.contains(&component) {
+            bail!("{} is both read and written", component.name)
+        }
+
+        if !self.reads.contains(&component) {
+            self.reads.push(component);
+        }
+
+        Ok(())
+    }
+
+    pub fn add_write<T: Component>(&mut self) -> Result<()> {
+        let component = ComponentId::of::<T>();
+        if self.writes.contains(&component) {
+            bail!("{} is written more than once", component.name)
+        }
+        if self.reads.contains(&component) {
+            bail!("{} is both read and written", component.name)
+        }
+
+        self.writes.push(component);
+        Ok(())
+    }
+
+    /// Filters only look at presence and change ticks, so they never alias with data access.
+    pub fn add_filter<T: Component>(&mut self) {
+        let component = ComponentId::of::<T>();
+        if !self.filters.contains(&component) {
+            self.filters.push(component);
+        }
+    }
+
+    fn is_written(&self, id: TypeId) -> bool {
+        self.writes.iter().any(|c| c.id == id)
+    }
+
+    /// Acquires the storage borrows needed for this access, failing if any of them is already
+    /// taken in a conflicting way.
+    fn borrow<'w>(&self, world: &'w World) -> Result<Vec<BorrowGuard<'w>>> {
+        let mut guards = Vec::new();
+
+        for component in &self.writes {
+            guards.extend(world.borrow_by_id(component.id, component.name, true)?);
+        }
+
+        let shared = self.reads.iter().chain(&self.filters);
+        let mut seen = Vec::new();
+        for component in shared {
+            if self.is_written(component.id) || seen.contains(&component.id) {
+                continue;
+            }
+            seen.push(component.id);
+            guards.extend(world.borrow_by_id(component.id, component.name, false)?);
+        }
+
+        Ok(guards)
+    }
+}
+
+/// What a query fetches per entity, e.g. `&Position`, `&mut Health` or a tuple of those.
+pub trait QueryData {
+    type Fetch;
+    type Item<'q>;
+
+    fn access(access: &mut Access) -> Result<()>;
+
+    /// # Safety
+    /// The borrows described by `access` must be held for as long as the fetch is used.
+    unsafe fn fetch(world: &World, access: &Access) -> Self::Fetch;
+
+    fn contains(fetch: &Self::Fetch, idx: usize) -> bool;
+
+    /// # Safety
+    /// `contains` must hold for the entity and no other item for it may be alive.
+    unsafe fn item<'q>(fetch: &Self::Fetch, entity: Entity) -> Self::Item<'q>;
+}
+
+/// Restricts which entities a query visits without fetching anything.
+pub trait QueryFilter {
+    type Fetch;
+
+    fn access(access: &mut Access);
+
+    /// # Safety
+    /// The borrows described by `access` must be held for as long as the fetch is used.
+    unsafe fn fetch(world: &World, access: &Access) -> Self::Fetch;
+
+    fn matches(fetch: &Self::Fetch, idx: usize) -> bool;
+}
+
+impl QueryData for Entity {
+    type Fetch = ();
+    type Item<'q> = Entity;
+
+    fn access(_access: &mut Access) -> Result<()> {
+        Ok(())
+    }
+
+    unsafe fn fetch(_world: &World, _access: &Access) -> Self::Fetch {}
+
+    fn contains(_fetch: &Self::Fetch, _idx: usize) -> bool {
+        true
+    }
+
+    unsafe fn item<'q>(_fetch: &Self::Fetch, entity: Entity) -> Self::Item<'q> {
+        entity
+    }
+}
+
+impl<T: Component> QueryData for &T {
+    type Fetch = Option<StorageView<T>>;
+    type Item<'q> = &'q T;
+
+    fn access(access: &mut Access) -> Result<()> {
+        access.add_read::<T>()
+    }
+
+    unsafe fn fetch(world: &World, _access: &Access) -> Self::Fetch {
+        world.view(false)
+    }
+
+    fn contains(fetch: &Self::Fetch, idx: usize) -> bool {
+        fetch.as_ref().is_some_and(|view| view.contains(idx))
+    }
+
+    unsafe fn item<'q>(fetch: &Self::Fetch, entity: Entity) -> Self::Item<'q> {
+        fetch.as_ref().unwrap().get(entity.index as usize)
+    }
+}
+
+impl<T: Component> QueryData for &mut T {
+    type Fetch = Option<StorageView<T>>;
+    type Item<'q> = Mut<'q, T>;
+
+    fn access(access: &mut Access) -> Result<()> {
+        access.add_write::<T>()
+    }
+
+    unsafe fn fetch(world: &World, _access: &Access) -> Self::Fetch {
+        world.view(true)
+    }
+
+    fn contains(fetch: &Self::Fetch, idx: usize) -> bool {
+        fetch.as_ref().is_some_and(|view| view.contains(idx))
+    }
+
+    unsafe fn item<'q>(fetch: &Self::Fetch, entity: Entity) -> Self::Item<'q> {
+        fetch.as_ref().unwrap().get_mut(entity.index as usize)
+    }
+}
+
+impl<T: Component> QueryData for Option<&T> {
+    type Fetch = Option<StorageView<T>>;
+    type Item<'q> = Option<&'q T>;
+
+    fn access(access: &mut Access) -> Result<()> {
+        access.add_read::<T>()
+    }
+
+    unsafe fn fetch(world: &World, _access: &Access) -> Self::Fetch {
+        world.view(false)
+    }
+
+    fn contains(_fetch: &Self::Fetch, _idx: usize) -> bool {
+        true
+    }
+
+    unsafe fn item<'q>(fetch: &Self::Fetch, entity: Entity) -> Self::Item<'q> {
+        let idx = entity.index as usize;
+        fetch
+            .as_ref()
+            .filter(|view| view.contains(idx))
+            .map(|view| view.get(idx))
+    }
+}
+
+/// Only entities that have a `T`.
+pub struct With<T>(PhantomData<T>);
+
+/// Only entities without a `T`.
+pub struct Without<T>(PhantomData<T>);
+
+/// Only entities whose `T` was inserted or mutably accessed since the last
+/// [`World::clear_trackers`].
+pub struct Changed<T>(PhantomData<T>);
+
+impl<T: Component> QueryFilter for With<T> {
+    type Fetch = Option<StorageView<T>>;
+
+    fn access(access: &mut Access) {
+        access.add_filter::<T>()
+    }
+
+    unsafe fn fetch(world: &World, access: &Access) -> Self::Fetch {
+        world.view(access.is_written(TypeId::of::<T>()))
+    }
+
+    fn matches(fetch: &Self::Fetch, idx: usize) -> bool {
+        fetch.as_ref().is_some_and(|view| view.contains(idx))
+    }
+}
+
+impl<T: Component> QueryFilter for Without<T> {
+    type Fetch = Option<StorageView<T>>;
+
+    fn access(access: &mut Access) {
+        access.add_filter::<T>()
+    }
+
+    unsafe fn fetch(world: &World, access: &Access) -> Self::Fetch {
+        world.view(access.is_written(TypeId::of::<T>()))
+    }
+
+    fn matches(fetch: &Self::Fetch, idx: usize) -> bool {
+        !fetch.as_ref().is_some_and(|view| view.contains(idx))
+    }
+}
+
+impl<T: Component> QueryFilter for Changed<T> {
+    type Fetch = Option<StorageView<T>>;
+
+    fn access(access: &mut Access) {
+        access.add_filter::<T>()
+    }
+
+    unsafe fn fetch(world: &World, access: &Access) -> Self::Fetch {
+        world.view(access.is_written(TypeId::of::<T>()))
+    }
+
+    fn matches(fetch: &Self::Fetch, idx: usize) -> bool {
+        fetch
+            .as_ref()
+            .is_some_and(|view| view.changed_since_last_tick(idx))
+    }
+}
+
+impl QueryFilter for () {
+    type Fetch = ();
+
+    fn access(_access: &mut Access) {}
+
+    unsafe fn fetch(_world: &World, _access: &Access) -> Self::Fetch {}
+
+    fn matches(_fetch: &Self::Fetch, _idx: usize) -> bool {
+        true
+    }
+}
+
+macro_rules! impl_query_tuple {
+    ($($name:ident),+) => {
+        #[allow(non_snake_case)]
+        impl<$($name: QueryData),+> QueryData for ($($name,)+) {
+            type Fetch = ($($name::Fetch,)+);
+            type Item<'q> = ($($name::Item<'q>,)+);
+
+            fn access(access: &mut Access) -> Result<()> {
+                $($name::access(access)?;)+
+                Ok(())
+            }
+
+            unsafe fn fetch(world: &World, access: &Access) -> Self::Fetch {
+                ($($name::fetch(world, access),)+)
+            }
+
+            fn contains(fetch: &Self::Fetch, idx: usize) -> bool {
+                let ($($name,)+) = fetch;
+                true $(&& $name::contains($name, idx))+
+            }
+
+            unsafe fn item<'q>(fetch: &Self::Fetch, entity: Entity) -> Self::Item<'q> {
+                let ($($name,)+) = fetch;
+                ($($name::item($name, entity),)+)
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<$($name: QueryFilter),+> QueryFilter for ($($name,)+) {
+            type Fetch = ($($name::Fetch,)+);
+
+            fn access(access: &mut Access) {
+                $($name::access(access);)+
+            }
+
+            unsafe fn fetch(world: &World, access: &Access) -> Self::Fetch {
+                ($($name::fetch(world, access),)+)
+            }
+
+            fn matches(fetch: &Self::Fetch, idx: usize) -> bool {
+                let ($($name,)+) = fetch;
+                true $(&& $name::matches($name, idx))+
+            }
+        }
+    };
+}
+
+impl_query_tuple!(A);
+impl_query_tuple!(A, B);
+impl_query_tuple!(A, B, C);
+impl_query_tuple!(A, B, C, D);
+impl_query_tuple!(A, B, C, D, E);
+impl_query_tuple!(A, B, C, D, E, F);
+
+/// Mutable access to a component that marks it as changed once it is written through.
+pub struct Mut<'q, T> {
+    value: &'q mut T,
+    changed: &'q mut u32,
+    tick: u32,
+}
+
+impl<'q, T> Mut<'q, T> {
+    pub(super) fn new(value: &'q mut T, changed: &'q mut u32, tick: u32) -> Self {
+        Self {
+            value,
+            changed,
+            tick,
+        }
+    }
+}
+
+impl<T> Deref for Mut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> DerefMut for Mut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        *self.changed = self.tick;
+        self.value
+    }
+}
+
+/// Borrows the storages of `Q` and `F` for as long as it lives. Created by [`World::query`] and
+/// [`World::query_filtered`].
+pub struct Query<'w, Q: QueryData, F: QueryFilter = ()> {
+    world: &'w World,
+    data: Q::Fetch,
+    filter: F::Fetch,
+    _guards: Vec<BorrowGuard<'w>>,
+}
+
+impl<'w, Q: QueryData, F: QueryFilter> Query<'w, Q, F> {
+    pub(super) fn new(world: &'w World) -> Result<Self> {
+        let mut access = Access::default();
+        Q::access(&mut access)?;
+        F::access(&mut access);
+
+        let guards = access.borrow(world)?;
+        // SAFETY: `guards` holds every borrow in `access` and lives as long as the fetches.
+        let (data, filter) = unsafe { (Q::fetch(world, &access), F::fetch(world, &access)) };
+
+        Ok(Self {
+            world,
+            data,
+            filter,
+            _guards: guards,
+        })
+    }
+
+    fn matches(&self, idx: usize) -> bool {
+        Q::contains(&self.data, idx) && F::matches(&self.filter, idx)
+    }
+
+    pub fn iter(&mut self) -> QueryIter<'_, 'w, Q, F> {
+        QueryIter {
+            query: self,
+            idx: 0,
+        }
+    }
+
+    /// Fetches a single entity, `None` if it is dead or does not match.
+    pub fn get(&mut self, entity: Entity) -> Option<Q::Item<'_>> {
+        if !self.world.is_alive(entity) || !self.matches(entity.index as usize) {
+            return None;
+        }
+
+        // SAFETY: The entity matches and `&mut self` prevents other items from being alive.
+        Some(unsafe { Q::item(&self.data, entity) })
+    }
+}
+
+pub struct QueryIter<'q, 'w, Q: QueryData, F: QueryFilter> {
+    query: &'q Query<'w, Q, F>,
+    idx: usize,
+}
+
+impl<'q, Q: QueryData, F: QueryFilter> Iterator for QueryIter<'q, '_, Q, F> {
+    type Item = Q::Item<'q>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entities = &self.query.world.entities;
+
+        while self.idx < entities.alive.len() {
+            let idx = self.idx;
+            self.idx += 1;
+
+            if !entities.alive[idx] || !self.query.matches(idx) {
+                continue;
+            }
+
+            let entity = Entity {
+                index: idx as u32,
+                generation: entities.generations[idx],
+            };
+            // SAFETY: Every entity is visited once and the query is borrowed mutably for 'q.
+            return Some(unsafe { Q::item(&self.query.data, entity) });
+        }
+
+        None
+    }
+}
+
+impl<'q, 'w, Q: QueryData, F: QueryFilter> IntoIterator for &'q mut Query<'w, Q, F> {
+    type Item = Q::Item<'q>;
+    type IntoIter = QueryIter<'q, 'w, Q, F>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Position(i32, i32);
+
+    #[derive(Debug, PartialEq)]
+    struct Health(u32);
+
+    struct Player;
+
+    struct Dead;
+
+    fn spawn(world: &mut World, pos: Position, health: u32) -> Result<Entity> {
+        let e = world.spawn();
+        world.insert(e, pos)?;
+        world.insert(e, Health(health))?;
+        Ok(e)
+    }
+
+    #[test]
+    fn test_query_with_filters() -> Result<()> {
+        let mut world = World::new();
+        let player = spawn(&mut world, Position(0, 0), 10)?;
+        world.insert(player, Player)?;
+        let monster = spawn(&mut world, Position(1, 1), 5)?;
+        let corpse = spawn(&mut world, Position(2, 2), 0)?;
+        world.insert(corpse, Dead)?;
+        let rock = world.spawn();
+        world.insert(rock, Position(3, 3))?;
+
+        let mut query =
+            world.query_filtered::<(Entity, &Position, &mut Health), Without<Dead>>()?;
+        for (_, _, mut health) in &mut query {
+            health.0 += 1;
+        }
+        let alive: Vec<Entity> = query.iter().map(|(e, _, _)| e).collect();
+        assert_eq!(vec![player, monster], alive);
+        drop(query);
+
+        let mut players = world.query_filtered::<&Health, With<Player>>()?;
+        assert_eq!(vec![&Health(11)], players.iter().collect::<Vec<_>>());
+        assert_eq!(Some(&Health(0)), world.get::<Health>(corpse).as_deref());
+        Ok(())
+    }
+
+    #[test]
+    fn test_changed_filter() -> Result<()> {
+        let mut world = World::new();
+        let a = spawn(&mut world, Position(0, 0), 1)?;
+        let b = spawn(&mut world, Position(1, 1), 1)?;
+
+        let mut changed = world.query_filtered::<Entity, Changed<Position>>()?;
+        assert_eq!(2, changed.iter().count());
+        drop(changed);
+
+        world.clear_trackers();
+        let mut positions = world.query::<(Entity, &mut Position)>()?;
+        for (e, mut pos) in &mut positions {
+            // Only writing through `Mut` counts as a change.
+            if e == b {
+                pos.0 += 1;
+            }
+        }
+        drop(positions);
+
+        let mut changed = world.query_filtered::<Entity, Changed<Position>>()?;
+        assert_eq!(vec![b], changed.iter().collect::<Vec<_>>());
+        drop(changed);
+
+        world.clear_trackers();
+        world.get_mut::<Position>(a).unwrap().1 = 5;
+        let mut changed = world.query_filtered::<&mut Position, Changed<Position>>()?;
+        assert_eq!(
+            vec![Position(0, 5)],
+            changed
+                .iter()
+                .map(|p| Position(p.0, p.1))
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_aliasing_is_rejected() -> Result<()> {
+        let mut world = World::new();
+        spawn(&mut world, Position(0, 0), 1)?;
+
+        assert!(world.query::<(&Health, &mut Health)>().is_err());
+        assert!(world.query::<(&mut Health, &mut Health)>().is_err());
+
+        let writer = world.query::<&mut Health>()?;
+        assert!(world.query::<&Health>().is_err());
+        assert!(world.query_filtered::<&Position, With<Health>>().is_err());
+        let mut reader = world.query::<&Position>()?;
+        let mut second_reader = world.query::<(Entity, &Position)>()?;
+        assert_eq!(1, reader.iter().count());
+        assert_eq!(1, second_reader.iter().count());
+        drop(writer);
+
+        assert!(world.query::<&mut Health>().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_optional_component() -> Result<()> {
+        let mut world = World::new();
+        let a = spawn(&mut world, Position(0, 0), 1)?;
+        let b = world.spawn();
+        world.insert(b, Position(1, 1))?;
+
+        let mut query = world.query::<(&Position, Option<&Health>)>()?;
+        assert_eq!(Some(&Health(1)), query.get(a).unwrap().1);
+        assert_eq!(None, query.get(b).unwrap().1);
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use std::any::{type_name, TypeId};
+use std::ops::{Deref, DerefMut};
+
+use anyhow::Result;
+
+use super::{BorrowCell, BorrowGuard, Ref, World};
+
+/// A singleton stored on the [`World`] instead of on an entity, e.g. the map, the RNG or the
+/// clock.
+pub trait Resource: Send + Sync + 'static {}
+
+impl<T: Send + Sync + 'static> Resource for T {}
+
+/// Mutable reference to a resource borrowed through a shared [`World`].
+pub struct ResMut<'w, R> {
+    value: &'w mut R,
+    _guard: BorrowGuard<'w>,
+}
+
+impl<R> Deref for ResMut<'_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        self.value
+    }
+}
+
+impl<R> DerefMut for ResMut<'_, R> {
+    fn deref_mut(&mut self) -> &mut R {
+        self.value
+    }
+}
+
+impl World {
+    /// Inserts a resource, returning the previous one of the same type.
+    pub fn insert_resource<R: Resource>(&mut self, resource: R) -> Option<R> {
+        let old = self.resources.insert(
+            TypeId::of::<R>(),
+            BorrowCell::new(type_name::<R>(), Box::new(resource)),
+        )?;
+
+        old.into_inner().downcast().ok().map(|r| *r)
+    }
+
+    pub fn remove_resource<R: Resource>(&mut self) -> Option<R> {
+        let old = self.resources.remove(&TypeId::of::<R>())?;
+        old.into_inner().downcast().ok().map(|r| *r)
+    }
+
+    pub fn contains_resource<R: Resource>(&self) -> bool {
+        self.resources.contains_key(&TypeId::of::<R>())
+    }
+
+    /// Borrows a resource. Panics if it is currently borrowed mutably.
+    pub fn resource<R: Resource>(&self) -> Option<Ref<'_, R>> {
+        let cell = self.resources.get(&TypeId::of::<R>())?;
+        let guard = cell
+            .borrow(type_name::<R>(), false)
+            .unwrap_or_else(|err| panic!("{}", err));
+        // SAFETY: The read borrow is held by the returned `Ref`.
+        let value = unsafe { &*cell.value.get() }.downcast_ref::<R>()?;
+
+        Some(Ref {
+            value,
+            _guard: guard,
+        })
+    }
+
+    /// Borrows a resource mutably through a shared world, for systems running in parallel.
+    /// Fails if it is borrowed already.
+    pub fn borrow_resource_mut<R: Resource>(&self) -> Result<Option<ResMut<'_, R>>> {
+        let Some(cell) = self.resources.get(&TypeId::of::<R>()) else {
+            return Ok(None);
+        };
+        let guard = cell.borrow(type_name::<R>(), true)?;
+        // SAFETY: The write borrow is held by the returned `ResMut`.
+        let value = unsafe { &mut *cell.value.get() }.downcast_mut::<R>();
+
+        Ok(value.map(|value| ResMut {
+            value,
+            _guard: guard,
+        }))
+    }
+
+    pub fn resource_mut<R: Resource>(&mut self) -> Option<&mut R> {
+        self.resources
+            .get_mut(&TypeId::of::<R>())?
+            .get_mut()
+            .downcast_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Score(u32);
+
+    #[test]
+    fn test_resources() -> Result<()> {
+        let mut world = World::new();
+        assert!(world.resource::<Score>().is_none());
+
+        assert_eq!(None, world.insert_resource(Score(1)));
+        world.resource_mut::<Score>().unwrap().0 += 1;
+        assert_eq!(Score(2), *world.resource::<Score>().unwrap());
+
+        {
+            // Any number of shared borrows may coexist.
+            let a = world.resource::<Score>().unwrap();
+            let b = world.resource::<Score>().unwrap();
+            assert_eq!(a.0, b.0);
+        }
+
+        {
+            let mut score = world.borrow_resource_mut::<Score>()?.unwrap();
+            score.0 += 1;
+            assert!(world.borrow_resource_mut::<Score>().is_err());
+        }
+        assert_eq!(Score(3), *world.resource::<Score>().unwrap());
+
+        assert_eq!(Some(Score(3)), world.insert_resource(Score(5)));
+        assert_eq!(Some(Score(5)), world.remove_resource::<Score>());
+        assert!(!world.contains_resource::<Score>());
+        Ok(())
+    }
+}
@@ -0,0 +1,371 @@
+use std::any::{Any, TypeId};
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{AnyStorage, Component, Entities, Entity, Resource, Storage, World};
+
+type AnyResource = dyn Any + Send + Sync;
+
+struct ComponentSerde {
+    name: String,
+    serialize: fn(&dyn AnyStorage, usize) -> Option<serde_json::Result<Value>>,
+    insert: fn(&mut World, Entity, Value) -> Result<()>,
+}
+
+struct ResourceSerde {
+    name: String,
+    serialize: fn(&AnyResource) -> serde_json::Result<Value>,
+    insert: fn(&mut World, Value) -> Result<()>,
+}
+
+fn serialize_component<T: Component + Serialize>(
+    storage: &dyn AnyStorage,
+    idx: usize,
+) -> Option<serde_json::Result<Value>> {
+    let storage = storage.as_any().downcast_ref::<Storage<T>>()?;
+    storage.get(idx).map(serde_json::to_value)
+}
+
+fn insert_component<T: Component + DeserializeOwned>(
+    world: &mut World,
+    entity: Entity,
+    value: Value,
+) -> Result<()> {
+    world.insert(entity, serde_json::from_value::<T>(value)?)?;
+    Ok(())
+}
+
+fn serialize_resource<R: Resource + Serialize>(
+    resource: &AnyResource,
+) -> serde_json::Result<Value> {
+    serde_json::to_value(resource.downcast_ref::<R>().unwrap())
+}
+
+fn insert_resource<R: Resource + DeserializeOwned>(world: &mut World, value: Value) -> Result<()> {
+    world.insert_resource(serde_json::from_value::<R>(value)?);
+    Ok(())
+}
+
+/// An entity and its components, keyed by their registered names.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SerializedEntity {
+    pub index: u32,
+    pub generation: u32,
+    pub components: BTreeMap<String, Value>,
+}
+
+/// A world as plain data. Entity ids are kept as they are, so components referring to other
+/// entities stay valid after loading.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SerializedWorld {
+    /// Generation of every slot of the entity allocator, including free ones.
+    pub generations: Vec<u32>,
+    pub free: Vec<u32>,
+    pub entities: Vec<SerializedEntity>,
+    pub resources: BTreeMap<String, Value>,
+}
+
+/// Rust type names of the components and resources a lossy serialization left out.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkippedTypes {
+    pub components: Vec<String>,
+    pub resources: Vec<String>,
+}
+
+/// Names under which component and resource types are written, decoupled from the Rust type
+/// names so types can be renamed without breaking existing files.
+///
+/// Like snapshots, serializing a world with unregistered components fails and unregistered
+/// resources are skipped.
+#[derive(Default)]
+pub struct SerializeRegistry {
+    components: HashMap<TypeId, ComponentSerde>,
+    resources: HashMap<TypeId, ResourceSerde>,
+}
+
+impl SerializeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register_component<T>(&mut self, name: &str) -> &mut Self
+    where
+        T: Component + Serialize + DeserializeOwned,
+    {
+        self.components.insert(
+            TypeId::of::<T>(),
+            ComponentSerde {
+                name: name.to_string(),
+                serialize: serialize_component::<T>,
+                insert: insert_component::<T>,
+            },
+        );
+        self
+    }
+
+    pub fn register_resource<R>(&mut self, name: &str) -> &mut Self
+    where
+        R: Resource + Serialize + DeserializeOwned,
+    {
+        self.resources.insert(
+            TypeId::of::<R>(),
+            ResourceSerde {
+                name: name.to_string(),
+                serialize: serialize_resource::<R>,
+                insert: insert_resource::<R>,
+            },
+        );
+        self
+    }
+
+    pub fn serialize(&self, world: &World) -> Result<SerializedWorld> {
+        let (data, skipped) = self.serialize_lossy(world)?;
+        if let Some(name) = skipped.components.first() {
+            bail!("component {} is not registered for serialization", name)
+        }
+        Ok(data)
+    }
+
+    /// Like [`serialize`](Self::serialize), but leaves out unregistered components instead of
+    /// failing. Also returns the type names of everything that was left out.
+    pub fn serialize_lossy(&self, world: &World) -> Result<(SerializedWorld, SkippedTypes)> {
+        let mut skipped = SkippedTypes::default();
+        let mut storages = Vec::new();
+        let mut guards = Vec::new();
+        for (id, cell) in &world.storages {
+            let guard = cell.borrow(cell.type_name, false)?;
+            // SAFETY: The read borrow is kept in `guards` until serializing is done.
+            let storage = unsafe { &**cell.value.get() };
+            guards.push(guard);
+            match self.components.get(id) {
+                _ if storage.is_empty() => {}
+                Some(serde) => storages.push((serde, storage)),
+                None => skipped.components.push(storage.type_name().to_string()),
+            }
+        }
+
+        let mut entities = Vec::new();
+        for entity in world.entities() {
+            let mut components = BTreeMap::new();
+            for (serde, storage) in &storages {
+                if let Some(value) = (serde.serialize)(*storage, entity.index as usize) {
+                    let value = value.with_context(|| format!("serializing {}", serde.name))?;
+                    components.insert(serde.name.clone(), value);
+                }
+            }
+            entities.push(SerializedEntity {
+                index: entity.index,
+                generation: entity.generation,
+                components,
+            });
+        }
+
+        let mut resources = BTreeMap::new();
+        for (id, serde) in &self.resources {
+            if let Some(cell) = world.resources.get(id) {
+                let _guard = cell.borrow(&serde.name, false)?;
+                // SAFETY: The read borrow is held by `_guard`.
+                let resource = unsafe { &**cell.value.get() };
+                let value = (serde.serialize)(resource)
+                    .with_context(|| format!("serializing {}", serde.name))?;
+                resources.insert(serde.name.clone(), value);
+            }
+        }
+        for (id, cell) in &world.resources {
+            if !self.resources.contains_key(id) {
+                skipped.resources.push(cell.type_name.to_string());
+            }
+        }
+        skipped.components.sort();
+        skipped.resources.sort();
+
+        let data = SerializedWorld {
+            generations: world.entities.generations.clone(),
+            free: world.entities.free.clone(),
+            entities,
+            resources,
+        };
+        Ok((data, skipped))
+    }
+
+    /// Replaces all entities of `world` and its registered resources with `data`. Unregistered
+    /// resources are kept. `data` is loaded into a fresh world first, so on error `world` is left
+    /// untouched rather than half loaded.
+    pub fn deserialize_into(&self, world: &mut World, data: SerializedWorld) -> Result<()> {
+        let components: HashMap<&str, &ComponentSerde> = self
+            .components
+            .values()
+            .map(|serde| (serde.name.as_str(), serde))
+            .collect();
+        let resources: HashMap<&str, &ResourceSerde> = self
+            .resources
+            .values()
+            .map(|serde| (serde.name.as_str(), serde))
+            .collect();
+
+        let mut loaded = World {
+            change_tick: world.change_tick,
+            last_change_tick: world.last_change_tick,
+            ..World::default()
+        };
+        self.load(&mut loaded, &components, &resources, data)?;
+
+        for (id, mut cell) in world.storages.drain() {
+            cell.get_mut().clear();
+            loaded.storages.entry(id).or_insert(cell);
+        }
+        for (id, cell) in world.resources.drain() {
+            if !self.resources.contains_key(&id) {
+                loaded.resources.insert(id, cell);
+            }
+        }
+        *world = loaded;
+        Ok(())
+    }
+
+    fn load(
+        &self,
+        world: &mut World,
+        components: &HashMap<&str, &ComponentSerde>,
+        resources: &HashMap<&str, &ResourceSerde>,
+        data: SerializedWorld,
+    ) -> Result<()> {
+        let slots = data.generations.len();
+        let mut alive = vec![false; slots];
+        for entity in &data.entities {
+            let Some(slot) = alive.get_mut(entity.index as usize) else {
+                bail!("entity {} is out of range", entity.index)
+            };
+            if *slot || data.generations[entity.index as usize] != entity.generation {
+                bail!(
+                    "entity {}v{} is inconsistent",
+                    entity.index,
+                    entity.generation
+                )
+            }
+            *slot = true;
+        }
+        if data
+            .free
+            .iter()
+            .any(|idx| alive.get(*idx as usize) != Some(&false))
+        {
+            bail!("free list contains a living or unknown entity")
+        }
+
+        world.entities = Entities {
+            generations: data.generations,
+            len: data.entities.len(),
+            alive,
+            free: data.free,
+        };
+
+        for saved in data.entities {
+            let entity = Entity {
+                index: saved.index,
+                generation: saved.generation,
+            };
+            for (name, value) in saved.components {
+                let Some(serde) = components.get(name.as_str()) else {
+                    bail!("unknown component `{}`", name)
+                };
+                (serde.insert)(world, entity, value)
+                    .with_context(|| format!("loading component `{}` of {}", name, entity))?;
+            }
+        }
+
+        for (name, value) in data.resources {
+            let Some(serde) = resources.get(name.as_str()) else {
+                bail!("unknown resource `{}`", name)
+            };
+            (serde.insert)(world, value).with_context(|| format!("loading resource `{}`", name))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Position(i32, i32);
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Owner(Entity);
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Score(u32);
+
+    struct Settings;
+
+    fn registry() -> SerializeRegistry {
+        let mut registry = SerializeRegistry::new();
+        registry
+            .register_component::<Position>("position")
+            .register_component::<Owner>("owner")
+            .register_resource::<Score>("score");
+        registry
+    }
+
+    #[test]
+    fn test_round_trip_keeps_entity_ids() -> Result<()> {
+        let registry = registry();
+        let mut world = World::new();
+        let dead = world.spawn();
+        let a = world.spawn();
+        let b = world.spawn();
+        world.despawn(dead)?;
+        world.insert(a, Position(1, 2))?;
+        world.insert(b, Owner(a))?;
+        world.insert_resource(Score(9));
+        world.insert_resource(Settings);
+
+        let data = registry.serialize(&world)?;
+        let json = serde_json::to_string(&data)?;
+
+        let mut loaded = World::new();
+        loaded.insert_resource(Settings);
+        registry.deserialize_into(&mut loaded, serde_json::from_str(&json)?)?;
+        assert_eq!(data, registry.serialize(&loaded)?);
+        assert_eq!(Position(1, 2), *loaded.get::<Position>(a).unwrap());
+        assert_eq!(Owner(a), *loaded.get::<Owner>(b).unwrap());
+        assert!(loaded.contains_resource::<Settings>());
+        assert_eq!(world.spawn(), loaded.spawn());
+        Ok(())
+    }
+
+    #[test]
+    fn test_inconsistent_data_is_rejected() -> Result<()> {
+        let registry = registry();
+        let mut world = World::new();
+        let a = world.spawn();
+        world.insert(a, Position(1, 2))?;
+        let data = registry.serialize(&world)?;
+
+        let mut unknown = data.clone();
+        unknown.entities[0]
+            .components
+            .insert("mana".into(), Value::from(3));
+        let mut wrong_type = data.clone();
+        wrong_type.entities[0]
+            .components
+            .insert("position".into(), Value::from("north"));
+        let mut bad_id = data;
+        bad_id.entities[0].generation = 4;
+
+        for bad in [unknown, wrong_type, bad_id] {
+            let mut loaded = World::new();
+            let b = loaded.spawn();
+            loaded.insert(b, Position(3, 4))?;
+            assert!(registry.deserialize_into(&mut loaded, bad).is_err());
+            assert_eq!(Position(3, 4), *loaded.get::<Position>(b).unwrap());
+        }
+        Ok(())
+    }
+}
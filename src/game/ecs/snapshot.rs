@@ -0,0 +1,340 @@
+use std::any::{type_name, Any, TypeId};
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+
+use super::event::{AnyEvents, Event};
+use super::{
+    AnyStorage, BorrowCell, Component, Entities, EventBus, Events, Resource, Storage, World,
+};
+
+type AnyResource = dyn Any + Send + Sync;
+
+struct ComponentFns {
+    clone: fn(&dyn AnyStorage) -> Box<dyn AnyStorage>,
+    eq: fn(&dyn AnyStorage, &dyn AnyStorage) -> bool,
+}
+
+struct ResourceFns {
+    name: &'static str,
+    clone: fn(&AnyResource) -> Box<AnyResource>,
+    eq: fn(&AnyResource, &AnyResource) -> bool,
+}
+
+fn clone_storage<T: Component + Clone>(storage: &dyn AnyStorage) -> Box<dyn AnyStorage> {
+    let storage = storage.as_any().downcast_ref::<Storage<T>>().unwrap();
+    Box::new(Storage {
+        slots: storage.slots.clone(),
+        changed: storage.changed.clone(),
+    })
+}
+
+fn eq_storage<T: Component + PartialEq>(a: &dyn AnyStorage, b: &dyn AnyStorage) -> bool {
+    let a = a.as_any().downcast_ref::<Storage<T>>().unwrap();
+    let b = b.as_any().downcast_ref::<Storage<T>>().unwrap();
+    a.slots == b.slots && a.changed == b.changed
+}
+
+struct EventFns {
+    clone: fn(&dyn AnyEvents) -> Box<dyn AnyEvents>,
+    eq: fn(&dyn AnyEvents, &dyn AnyEvents) -> bool,
+}
+
+fn clone_events<E: Event + Clone>(events: &dyn AnyEvents) -> Box<dyn AnyEvents> {
+    Box::new(events.as_any().downcast_ref::<Events<E>>().unwrap().clone())
+}
+
+fn eq_events<E: Event + PartialEq>(a: &dyn AnyEvents, b: &dyn AnyEvents) -> bool {
+    a.as_any().downcast_ref::<Events<E>>() == b.as_any().downcast_ref::<Events<E>>()
+}
+
+fn clone_resource<R: Resource + Clone>(resource: &AnyResource) -> Box<AnyResource> {
+    Box::new(resource.downcast_ref::<R>().unwrap().clone())
+}
+
+fn eq_resource<R: Resource + PartialEq>(a: &AnyResource, b: &AnyResource) -> bool {
+    a.downcast_ref::<R>() == b.downcast_ref::<R>()
+}
+
+/// Copy of everything a [`SnapshotRegistry`] knows about in a world.
+pub struct WorldSnapshot {
+    entities: Entities,
+    storages: HashMap<TypeId, Box<dyn AnyStorage>>,
+    resources: HashMap<TypeId, Box<AnyResource>>,
+    change_tick: u32,
+    last_change_tick: u32,
+}
+
+/// Copy of the registered event queues of an [`EventBus`], including where every reader is.
+pub struct EventsSnapshot {
+    queues: HashMap<TypeId, Box<dyn AnyEvents>>,
+}
+
+impl WorldSnapshot {
+    pub fn len(&self) -> usize {
+        self.entities.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Types that are part of the simulation state and how to copy them.
+///
+/// Every component type in use has to be registered, taking a snapshot of a world with unknown
+/// components fails. Unregistered resources are treated as configuration and left alone, both
+/// when taking and when restoring snapshots. Events of unregistered types are dropped when
+/// restoring.
+#[derive(Default)]
+pub struct SnapshotRegistry {
+    components: HashMap<TypeId, ComponentFns>,
+    resources: HashMap<TypeId, ResourceFns>,
+    events: HashMap<TypeId, EventFns>,
+}
+
+impl SnapshotRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register_component<T: Component + Clone + PartialEq>(&mut self) -> &mut Self {
+        self.components.insert(
+            TypeId::of::<T>(),
+            ComponentFns {
+                clone: clone_storage::<T>,
+                eq: eq_storage::<T>,
+            },
+        );
+        self
+    }
+
+    pub fn register_resource<R: Resource + Clone + PartialEq>(&mut self) -> &mut Self {
+        self.resources.insert(
+            TypeId::of::<R>(),
+            ResourceFns {
+                name: type_name::<R>(),
+                clone: clone_resource::<R>,
+                eq: eq_resource::<R>,
+            },
+        );
+        self
+    }
+
+    pub fn register_event<E: Event + Clone + PartialEq>(&mut self) -> &mut Self {
+        self.events.insert(
+            TypeId::of::<E>(),
+            EventFns {
+                clone: clone_events::<E>,
+                eq: eq_events::<E>,
+            },
+        );
+        self
+    }
+
+    pub fn snapshot(&self, world: &World) -> Result<WorldSnapshot> {
+        let mut storages = HashMap::new();
+        for (id, cell) in &world.storages {
+            let guard = cell.borrow("component storage", false)?;
+            // SAFETY: The read borrow is held by `guard`.
+            let storage = unsafe { &**cell.value.get() };
+            // Empty storages are skipped, a world that never had a component matches one that
+            // lost all of them.
+            match self.components.get(id) {
+                _ if storage.is_empty() => {}
+                Some(fns) => {
+                    storages.insert(*id, (fns.clone)(storage));
+                }
+                None => bail!(
+                    "component {} is not registered for snapshots",
+                    storage.type_name()
+                ),
+            }
+            drop(guard);
+        }
+
+        let mut resources = HashMap::new();
+        for (id, fns) in &self.resources {
+            let Some(cell) = world.resources.get(id) else {
+                continue;
+            };
+            let _guard = cell.borrow(fns.name, false)?;
+            // SAFETY: The read borrow is held by `_guard`.
+            let resource = unsafe { &**cell.value.get() };
+            resources.insert(*id, (fns.clone)(resource));
+        }
+
+        Ok(WorldSnapshot {
+            entities: world.entities.clone(),
+            storages,
+            resources,
+            change_tick: world.change_tick,
+            last_change_tick: world.last_change_tick,
+        })
+    }
+
+    /// Puts `world` back into the state captured by `snapshot`. Components added since are
+    /// dropped, registered resources missing from the snapshot are removed.
+    pub fn restore(&self, world: &mut World, snapshot: &WorldSnapshot) -> Result<()> {
+        for id in snapshot.storages.keys() {
+            if !self.components.contains_key(id) {
+                bail!("snapshot contains a component that is not registered")
+            }
+        }
+
+        world.entities = snapshot.entities.clone();
+        world.change_tick = snapshot.change_tick;
+        world.last_change_tick = snapshot.last_change_tick;
+
+        for cell in world.storages.values_mut() {
+            cell.get_mut().clear();
+        }
+        for (id, storage) in &snapshot.storages {
+            let copy = (self.components[id].clone)(storage.as_ref());
+            let type_name = copy.type_name();
+            world.storages.insert(*id, BorrowCell::new(type_name, copy));
+        }
+
+        for (id, fns) in &self.resources {
+            match snapshot.resources.get(id) {
+                Some(resource) => {
+                    let copy = (fns.clone)(resource.as_ref());
+                    world.resources.insert(*id, BorrowCell::new(fns.name, copy));
+                }
+                None => {
+                    world.resources.remove(id);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether two snapshots hold exactly the same state.
+    pub fn matches(&self, a: &WorldSnapshot, b: &WorldSnapshot) -> bool {
+        let entities_match = a.entities.generations == b.entities.generations
+            && a.entities.alive == b.entities.alive
+            && a.entities.free == b.entities.free;
+        if !entities_match
+            || a.change_tick != b.change_tick
+            || a.last_change_tick != b.last_change_tick
+        {
+            return false;
+        }
+
+        let storages_match = a.storages.len() == b.storages.len()
+            && a.storages.iter().all(|(id, storage)| {
+                let eq = self.components[id].eq;
+                b.storages
+                    .get(id)
+                    .is_some_and(|other| eq(storage.as_ref(), other.as_ref()))
+            });
+        let resources_match = a.resources.len() == b.resources.len()
+            && a.resources.iter().all(|(id, resource)| {
+                let eq = self.resources[id].eq;
+                b.resources
+                    .get(id)
+                    .is_some_and(|other| eq(resource.as_ref(), other.as_ref()))
+            });
+
+        storages_match && resources_match
+    }
+
+    pub fn snapshot_events(&self, bus: &EventBus) -> EventsSnapshot {
+        let queues = bus
+            .queues
+            .iter()
+            .filter_map(|(id, queue)| {
+                let fns = self.events.get(id)?;
+                Some((*id, (fns.clone)(queue.as_ref())))
+            })
+            .collect();
+        EventsSnapshot { queues }
+    }
+
+    /// Puts the registered queues of `bus` back into the state captured by `snapshot` and
+    /// clears all others.
+    pub fn restore_events(&self, bus: &mut EventBus, snapshot: &EventsSnapshot) {
+        for (id, queue) in bus.queues.iter_mut() {
+            if !snapshot.queues.contains_key(id) {
+                queue.clear();
+            }
+        }
+        for (id, queue) in &snapshot.queues {
+            bus.queues
+                .insert(*id, (self.events[id].clone)(queue.as_ref()));
+        }
+    }
+
+    pub fn events_match(&self, a: &EventsSnapshot, b: &EventsSnapshot) -> bool {
+        a.queues.len() == b.queues.len()
+            && a.queues.iter().all(|(id, queue)| {
+                let eq = self.events[id].eq;
+                b.queues
+                    .get(id)
+                    .is_some_and(|other| eq(queue.as_ref(), other.as_ref()))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Position(i32, i32);
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Score(u32);
+
+    struct Unregistered;
+
+    fn registry() -> SnapshotRegistry {
+        let mut registry = SnapshotRegistry::new();
+        registry
+            .register_component::<Position>()
+            .register_resource::<Score>();
+        registry
+    }
+
+    #[test]
+    fn test_restore_undoes_changes() -> Result<()> {
+        let registry = registry();
+        let mut world = World::new();
+        let a = world.spawn();
+        world.insert(a, Position(1, 2))?;
+        world.insert_resource(Score(3));
+        let snapshot = registry.snapshot(&world)?;
+
+        world.get_mut::<Position>(a).unwrap().0 = 10;
+        world.despawn(a)?;
+        let b = world.spawn();
+        world.insert(b, Position(5, 5))?;
+        world.remove_resource::<Score>();
+        assert!(!registry.matches(&snapshot, &registry.snapshot(&world)?));
+
+        registry.restore(&mut world, &snapshot)?;
+        assert!(registry.matches(&snapshot, &registry.snapshot(&world)?));
+        assert!(world.is_alive(a));
+        assert!(!world.is_alive(b));
+        assert_eq!(Position(1, 2), *world.get::<Position>(a).unwrap());
+        assert_eq!(Score(3), *world.resource::<Score>().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unregistered_components_are_rejected() -> Result<()> {
+        let registry = registry();
+        let mut world = World::new();
+        let a = world.spawn();
+        world.insert(a, Unregistered)?;
+
+        let err = registry.snapshot(&world).err().unwrap().to_string();
+        assert!(err.contains("Unregistered"), "{}", err);
+
+        // An emptied storage is fine again.
+        world.remove::<Unregistered>(a);
+        assert_eq!(1, registry.snapshot(&world)?.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+use std::path::PathBuf;
+
+use super::ecs::Entity;
+use super::tiles::TileId;
+use super::turns::Action;
+
+/// Events injected from outside the simulation. Background threads (asset loaders, network) send
+/// them through the event loop proxy, see [`super::GameState::event_proxy`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum UserEvent {
+    AssetLoaded(PathBuf),
+    AssetFailed { path: PathBuf, error: String },
+    Message(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntityDied {
+    pub entity: Entity,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TileChanged {
+    pub position: (u32, u32),
+    pub from: TileId,
+    pub to: TileId,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LevelEntered {
+    pub name: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ActorActed {
+    pub entity: Entity,
+    pub action: Action,
+}
+
+/// A labelled [`super::tween::Tween`] completed. `entity` is `None` for camera tweens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TweenFinished {
+    pub entity: Option<Entity>,
+    pub label: String,
+}
+
+/// A script hook failed, e.g. by raising an error or running too long. The entity went on as if
+/// the hook did nothing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScriptError {
+    pub script: String,
+    pub hook: &'static str,
+    pub message: String,
+}
@@ -50,6 +50,12 @@ impl From<ElementState> for InputState {
     }
 }
 
+impl Default for Input {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Input {
     pub fn new() -> Self {
         Self {
@@ -62,6 +68,12 @@ impl Input {
 
     pub fn process_event(&mut self, event: &WindowEvent) {
         match event {
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor_pos = *position;
+            }
+            WindowEvent::MouseInput { state, button, .. } => {
+                self.mouse_button.insert(*button, (*state).into());
+            }
             WindowEvent::KeyboardInput { event, .. } if !event.repeat => {
                 if let PhysicalKey::Code(key_code) = event.physical_key {
                     self.physical_keys.insert(key_code, event.state.into());
@@ -78,13 +90,23 @@ impl Input {
     pub fn is_physical_key_pressed(&self, k: KeyCode) -> bool {
         self.physical_keys
             .get(&k)
-            .map_or(false, InputState::is_pressed)
+            .is_some_and(InputState::is_pressed)
     }
 
     pub fn is_logical_key_pressed(&self, k: NamedKey) -> bool {
         self.logical_keys
             .get(&k)
-            .map_or(false, InputState::is_pressed)
+            .is_some_and(InputState::is_pressed)
+    }
+
+    pub fn is_mouse_button_pressed(&self, b: MouseButton) -> bool {
+        self.mouse_button
+            .get(&b)
+            .is_some_and(InputState::is_pressed)
+    }
+
+    pub fn cursor_position(&self) -> PhysicalPosition<f64> {
+        self.cursor_pos
     }
 
     pub(crate) fn update_keys(&mut self) {
@@ -105,5 +127,14 @@ impl Input {
             InputState::Down => true,
             InputState::Released => false,
         });
+
+        self.mouse_button.retain(|_, state| match state {
+            InputState::Pressed => {
+                *state = InputState::Down;
+                true
+            }
+            InputState::Down => true,
+            InputState::Released => false,
+        });
     }
 }
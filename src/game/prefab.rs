@@ -0,0 +1,332 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use ron::value::RawValue;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use super::components::{Health, Name};
+use super::ecs::{Component, Entity, Transform, World};
+use super::script::Script;
+use super::tiles::TileRegistry;
+use super::turns::Actor;
+
+const PREFAB_EXTENSION: &str = "ron";
+
+/// Contents of one prefab file, e.g. `prefabs/goblin.ron`:
+///
+/// ```ron
+/// (
+///     base: Some("monster"),
+///     components: {
+///         "Name": "Goblin",
+///         "Health": (current: 5, max: 5),
+///     },
+/// )
+/// ```
+///
+/// Components of the base prefab are inherited, components listed here replace them.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PrefabDef {
+    #[serde(default)]
+    pub base: Option<String>,
+    #[serde(default)]
+    pub components: BTreeMap<String, Box<RawValue>>,
+}
+
+type Insert = Box<dyn FnOnce(&mut World, Entity) -> Result<()>>;
+
+type ComponentLoader = Box<dyn Fn(&RawValue) -> Result<Insert> + Send + Sync>;
+
+/// Every known prefab plus the components that can appear in them. Kept as a world resource.
+pub struct Prefabs {
+    defs: HashMap<String, PrefabDef>,
+    loaders: HashMap<String, ComponentLoader>,
+}
+
+impl Default for Prefabs {
+    fn default() -> Self {
+        let mut prefabs = Self {
+            defs: HashMap::new(),
+            loaders: HashMap::new(),
+        };
+        prefabs.register::<Name>("Name");
+        prefabs.register::<Health>("Health");
+        prefabs.register::<Transform>("Transform");
+        prefabs.register_with("Tile", |world, name: String| {
+            let tiles = world
+                .resource::<TileRegistry>()
+                .context("tile registry missing")?;
+            tiles
+                .id(&name)
+                .with_context(|| format!("unknown tile `{}`", name))
+        });
+        prefabs.register::<Actor>("Actor");
+        prefabs.register::<Script>("Script");
+        prefabs
+    }
+}
+
+impl Prefabs {
+    /// Knows the built-in components but no prefabs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads every `.ron` file in `dir`, named after the file stem. A missing directory just
+    /// means there are no prefabs.
+    pub fn load_dir(dir: &Path) -> Result<Self> {
+        let mut prefabs = Self::new();
+        if !dir.exists() {
+            log::info!("No prefabs at {}", dir.display());
+            return Ok(prefabs);
+        }
+
+        let entries =
+            std::fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != PREFAB_EXTENSION) {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                bail!("prefab file name {} is not valid utf-8", path.display())
+            };
+            let source = std::fs::read_to_string(&path)
+                .with_context(|| format!("reading {}", path.display()))?;
+            prefabs
+                .add_source(name, &source)
+                .with_context(|| format!("loading {}", path.display()))?;
+        }
+
+        prefabs.validate()?;
+        log::info!(
+            "Loaded {} prefabs from {}",
+            prefabs.defs.len(),
+            dir.display()
+        );
+        Ok(prefabs)
+    }
+
+    /// Makes `T` usable in prefab files under `name`.
+    pub fn register<T: Component + DeserializeOwned>(&mut self, name: &str) {
+        let component_name = name.to_string();
+        self.loaders.insert(
+            name.to_string(),
+            Box::new(move |value| {
+                let component: T = value
+                    .into_rust()
+                    .with_context(|| format!("invalid value for component `{}`", component_name))?;
+                Ok(Box::new(move |world: &mut World, entity| {
+                    world.insert(entity, component)?;
+                    Ok(())
+                }))
+            }),
+        );
+    }
+
+    /// Like [`Prefabs::register`], but the file holds a `V` that `convert` turns into the
+    /// component at spawn time, when the world is at hand. Tiles use this to be named rather
+    /// than numbered.
+    pub fn register_with<T, V, F>(&mut self, name: &str, convert: F)
+    where
+        T: Component,
+        V: DeserializeOwned + 'static,
+        F: Fn(&World, V) -> Result<T> + Clone + Send + Sync + 'static,
+    {
+        let component_name = name.to_string();
+        self.loaders.insert(
+            name.to_string(),
+            Box::new(move |value| {
+                let value: V = value
+                    .into_rust()
+                    .with_context(|| format!("invalid value for component `{}`", component_name))?;
+                let convert = convert.clone();
+                Ok(Box::new(move |world: &mut World, entity| {
+                    let component = convert(world, value)?;
+                    world.insert(entity, component)?;
+                    Ok(())
+                }))
+            }),
+        );
+    }
+
+    pub fn add(&mut self, name: &str, def: PrefabDef) {
+        self.defs.insert(name.to_string(), def);
+    }
+
+    /// Parses a prefab from RON source.
+    pub fn add_source(&mut self, name: &str, source: &str) -> Result<()> {
+        let def: PrefabDef = ron::from_str(source).context("parsing prefab")?;
+        self.add(name, def);
+        Ok(())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.defs.contains_key(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.defs.keys().map(String::as_str)
+    }
+
+    /// Checks that every prefab can be resolved, so broken content shows up at load time
+    /// instead of the first time something spawns.
+    pub fn validate(&self) -> Result<()> {
+        for name in self.defs.keys() {
+            self.build(name)?;
+        }
+        Ok(())
+    }
+
+    /// Spawns the prefab called `name`. Nothing is spawned if any of its components is invalid.
+    pub fn spawn(&self, world: &mut World, name: &str) -> Result<Entity> {
+        self.build(name)?.spawn(world)
+    }
+
+    /// Deserializes the components of `name` without touching a world.
+    pub fn build(&self, name: &str) -> Result<PrefabInstance> {
+        let mut inserts = Vec::new();
+        for (component, value) in self.resolve(name)? {
+            let Some(loader) = self.loaders.get(&component) else {
+                bail!("prefab `{}` uses unknown component `{}`", name, component)
+            };
+            inserts.push(loader(&value).with_context(|| format!("in prefab `{}`", name))?);
+        }
+
+        Ok(PrefabInstance { inserts })
+    }
+
+    /// Merges the components of `name` with those of its bases, the most derived one winning.
+    fn resolve(&self, name: &str) -> Result<BTreeMap<String, Box<RawValue>>> {
+        let mut chain = Vec::new();
+        let mut next = Some(name);
+        while let Some(current) = next {
+            if chain.contains(&current) {
+                chain.push(current);
+                bail!("prefab inheritance cycle: {}", chain.join(" -> "))
+            }
+            let Some(def) = self.defs.get(current) else {
+                match chain.last() {
+                    Some(child) => bail!("prefab `{}` has unknown base `{}`", child, current),
+                    None => bail!("unknown prefab `{}`", current),
+                }
+            };
+            chain.push(current);
+            next = def.base.as_deref();
+        }
+
+        let mut components = BTreeMap::new();
+        for current in chain.iter().rev() {
+            for (component, value) in &self.defs[*current].components {
+                components.insert(component.clone(), value.clone());
+            }
+        }
+
+        Ok(components)
+    }
+}
+
+/// Components of a prefab, ready to be inserted.
+pub struct PrefabInstance {
+    inserts: Vec<Insert>,
+}
+
+impl PrefabInstance {
+    /// Components registered with [`Prefabs::register_with`] can still fail here, in which case
+    /// the entity is despawned again.
+    pub fn spawn(self, world: &mut World) -> Result<Entity> {
+        let entity = world.spawn();
+        for insert in self.inserts {
+            if let Err(err) = insert(world, entity) {
+                world.despawn(entity)?;
+                return Err(err);
+            }
+        }
+
+        Ok(entity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::tiles::TileId;
+
+    fn test_prefabs() -> Result<Prefabs> {
+        let mut prefabs = Prefabs::new();
+        prefabs.add_source(
+            "monster",
+            r#"(components: {
+                "Name": "Monster",
+                "Health": (current: 10, max: 10),
+                "Tile": "wall",
+            })"#,
+        )?;
+        prefabs.add_source(
+            "goblin",
+            r#"(base: Some("monster"), components: {
+                "Name": "Goblin",
+                "Transform": (translation: (x: 1.0, y: 2.0)),
+            })"#,
+        )?;
+        Ok(prefabs)
+    }
+
+    #[test]
+    fn test_spawn_with_inheritance() -> Result<()> {
+        let prefabs = test_prefabs()?;
+        prefabs.validate()?;
+
+        let mut world = World::new();
+        world.insert_resource(TileRegistry::default());
+        let goblin = prefabs.spawn(&mut world, "goblin")?;
+        assert_eq!(Name("Goblin".into()), *world.get::<Name>(goblin).unwrap());
+        assert_eq!(Health::new(10), *world.get::<Health>(goblin).unwrap());
+        assert_eq!(TileId::WALL, *world.get::<TileId>(goblin).unwrap());
+        assert_eq!(
+            Transform::from_xy(1.0, 2.0),
+            *world.get::<Transform>(goblin).unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_prefabs_are_rejected() -> Result<()> {
+        let mut prefabs = test_prefabs()?;
+        let mut world = World::new();
+        world.insert_resource(TileRegistry::default());
+        assert!(prefabs.spawn(&mut world, "dragon").is_err());
+
+        prefabs.add_source("bad", r#"(components: { "Health": "lots" })"#)?;
+        prefabs.add_source("odd", r#"(components: { "Mana": 3 })"#)?;
+        prefabs.add_source("a", r#"(base: Some("b"))"#)?;
+        prefabs.add_source("b", r#"(base: Some("a"))"#)?;
+        prefabs.add_source(
+            "lava",
+            r#"(components: { "Name": "Lava", "Tile": "lava" })"#,
+        )?;
+        for name in ["bad", "odd", "a", "lava"] {
+            assert!(prefabs.spawn(&mut world, name).is_err());
+        }
+        assert!(world.is_empty());
+
+        let err = prefabs.build("a").err().unwrap().to_string();
+        assert_eq!("prefab inheritance cycle: a -> b -> a", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shipped_prefabs_load() -> Result<()> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/prefabs");
+        let prefabs = Prefabs::load_dir(&dir)?;
+        assert!(prefabs.contains("goblin"));
+
+        let mut world = World::new();
+        let goblin = prefabs.spawn(&mut world, "goblin")?;
+        assert_eq!(Health::new(5), *world.get::<Health>(goblin).unwrap());
+        assert!(world.has::<Transform>(goblin));
+        Ok(())
+    }
+}
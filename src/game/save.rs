@@ -0,0 +1,524 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::camera::CameraRig;
+use super::chunks::ChunkMap;
+use super::clock::Clock;
+use super::components::{Health, Name, Tint};
+use super::ecs::{Children, GlobalTransform, Parent, SerializeRegistry, Transform};
+use super::rng::Rng;
+use super::script::Script;
+use super::state::{AppState, Transition};
+use super::tiles::TileId;
+use super::timer::{Cooldown, DelayedActions, Timer};
+use super::turns::{Actor, Player, Turns};
+use super::tween::Tweens;
+use super::{Commands, GameState, TileMap, GROUND_LAYER};
+
+/// Version written into new saves. Bump it whenever the saved data changes shape and register a
+/// migration from the previous version.
+pub const SAVE_VERSION: u32 = 3;
+
+const SLOT_EXTENSION: &str = "json";
+
+/// Rewrites the world data of a save from one version to the next.
+pub type Migration = Box<dyn Fn(&mut Value) -> Result<()>>;
+
+/// Errors about the save file itself, as opposed to I/O errors. Returned inside
+/// [`anyhow::Error`], so use `downcast_ref` to tell them apart.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SaveError {
+    EmptySlot(u32),
+    Corrupted { slot: u32, reason: String },
+    TooNew { version: u32, supported: u32 },
+    NoMigration { from: u32 },
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::EmptySlot(slot) => write!(f, "save slot {} is empty", slot),
+            SaveError::Corrupted { slot, reason } => {
+                write!(f, "save slot {} is corrupted: {}", slot, reason)
+            }
+            SaveError::TooNew { version, supported } => write!(
+                f,
+                "save version {} is newer than the supported version {}",
+                version, supported
+            ),
+            SaveError::NoMigration { from } => {
+                write!(f, "no migration from save version {}", from)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+#[derive(Serialize, Deserialize)]
+struct SaveFile {
+    version: u32,
+    /// Seconds since the unix epoch.
+    saved_at: u64,
+    /// FNV-1a hash of `world` in its compact form, to catch files edited or cut off on disk.
+    checksum: String,
+    world: Value,
+}
+
+/// What the save menu shows for a slot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlotInfo {
+    pub slot: u32,
+    pub version: u32,
+    pub saved_at: u64,
+}
+
+/// The components and resources written to saves. Names are part of the file format, so don't
+/// rename them without adding a migration.
+pub fn default_registry() -> SerializeRegistry {
+    let mut registry = SerializeRegistry::new();
+    registry
+        .register_component::<Transform>("transform")
+        .register_component::<GlobalTransform>("global_transform")
+        .register_component::<Parent>("parent")
+        .register_component::<Children>("children")
+        .register_component::<Name>("name")
+        .register_component::<Health>("health")
+        .register_component::<TileId>("tile_type")
+        .register_component::<Actor>("actor")
+        .register_component::<Player>("player")
+        .register_component::<Timer>("timer")
+        .register_component::<Cooldown>("cooldown")
+        .register_component::<Tint>("tint")
+        .register_component::<Tweens>("tweens")
+        .register_component::<Script>("script")
+        .register_resource::<TileMap>("map")
+        .register_resource::<ChunkMap>("chunks")
+        .register_resource::<Rng>("rng")
+        .register_resource::<Clock>("clock")
+        .register_resource::<Turns>("turns")
+        .register_resource::<CameraRig>("camera")
+        .register_resource::<DelayedActions>("delayed_actions");
+    registry
+}
+
+/// Numbered save files in one directory.
+pub struct SaveSlots {
+    dir: PathBuf,
+    migrations: BTreeMap<u32, Migration>,
+}
+
+impl SaveSlots {
+    pub fn new(dir: &Path) -> Self {
+        let mut slots = Self {
+            dir: dir.to_path_buf(),
+            migrations: BTreeMap::new(),
+        };
+        slots.add_migration(1, tile_names_to_ids);
+        slots.add_migration(2, single_layer_to_layers);
+        slots
+    }
+
+    /// Registers the migration from version `from` to `from + 1`.
+    pub fn add_migration<F>(&mut self, from: u32, migration: F)
+    where
+        F: Fn(&mut Value) -> Result<()> + 'static,
+    {
+        self.migrations.insert(from, Box::new(migration));
+    }
+
+    pub fn path(&self, slot: u32) -> PathBuf {
+        self.dir.join(format!("slot-{}.{}", slot, SLOT_EXTENSION))
+    }
+
+    pub fn save(&self, slot: u32, game: &GameState) -> Result<()> {
+        let world = serde_json::to_value(game.serializers.serialize(&game.world)?)?;
+        let file = SaveFile {
+            version: SAVE_VERSION,
+            saved_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            checksum: checksum(&world)?,
+            world,
+        };
+
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("creating {}", self.dir.display()))?;
+        // Write next to the slot first, so a crash while saving can't destroy the old save.
+        let path = self.path(slot);
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&file)?)
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, &path).with_context(|| format!("writing {}", path.display()))?;
+
+        log::info!("Saved slot {} to {}", slot, path.display());
+        Ok(())
+    }
+
+    /// Replaces the simulation state of `game` with the save in `slot`, migrating it if it is
+    /// older, and switches to [`AppState::Playing`]. `game` is only touched once the file has
+    /// been read and verified. If its contents still turn out to be inconsistent, `game` is left
+    /// as it was.
+    pub fn load(&self, slot: u32, game: &mut GameState) -> Result<()> {
+        let file = self.read(slot)?;
+        let mut world = file.world;
+        for version in file.version..SAVE_VERSION {
+            let Some(migration) = self.migrations.get(&version) else {
+                return Err(SaveError::NoMigration { from: version }.into());
+            };
+            migration(&mut world)
+                .with_context(|| format!("migrating slot {} from version {}", slot, version))?;
+        }
+
+        let data = serde_json::from_value(world).map_err(|err| corrupted(slot, err))?;
+        game.serializers
+            .deserialize_into(&mut game.world, data)
+            .map_err(|err| corrupted(slot, format!("{:#}", err)))?;
+        game.commands = Commands::new();
+        game.states.request(Transition::Reset(AppState::Playing));
+
+        log::info!("Loaded slot {} (version {})", slot, file.version);
+        Ok(())
+    }
+
+    /// Every slot with a save in it, corrupted ones included.
+    pub fn slots(&self) -> Result<Vec<SlotInfo>> {
+        let mut slots = Vec::new();
+        if !self.dir.exists() {
+            return Ok(slots);
+        }
+
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != SLOT_EXTENSION) {
+                continue;
+            }
+            let slot = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.strip_prefix("slot-"))
+                .and_then(|s| s.parse().ok());
+            let Some(slot) = slot else {
+                continue;
+            };
+            let (version, saved_at) = match self.read(slot) {
+                Ok(file) => (file.version, file.saved_at),
+                Err(_) => (0, 0),
+            };
+            slots.push(SlotInfo {
+                slot,
+                version,
+                saved_at,
+            });
+        }
+
+        slots.sort_by_key(|info| info.slot);
+        Ok(slots)
+    }
+
+    pub fn delete(&self, slot: u32) -> Result<()> {
+        let path = self.path(slot);
+        if !path.exists() {
+            return Err(SaveError::EmptySlot(slot).into());
+        }
+        std::fs::remove_file(&path).with_context(|| format!("deleting {}", path.display()))
+    }
+
+    fn read(&self, slot: u32) -> Result<SaveFile> {
+        let path = self.path(slot);
+        if !path.exists() {
+            return Err(SaveError::EmptySlot(slot).into());
+        }
+
+        let bytes = std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+        let file: SaveFile = serde_json::from_slice(&bytes).map_err(|err| corrupted(slot, err))?;
+        if file.version > SAVE_VERSION {
+            return Err(SaveError::TooNew {
+                version: file.version,
+                supported: SAVE_VERSION,
+            }
+            .into());
+        }
+        if checksum(&file.world)? != file.checksum {
+            return Err(corrupted(slot, "checksum mismatch"));
+        }
+
+        Ok(file)
+    }
+}
+
+fn corrupted(slot: u32, reason: impl fmt::Display) -> anyhow::Error {
+    SaveError::Corrupted {
+        slot,
+        reason: reason.to_string(),
+    }
+    .into()
+}
+
+/// Version 1 stored tiles by their enum name, version 2 by [`TileId`].
+fn tile_names_to_ids(world: &mut Value) -> Result<()> {
+    fn convert(tile: &mut Value) -> Result<()> {
+        let id = match tile.as_str() {
+            Some("Floor") => TileId::FLOOR,
+            Some("Wall") => TileId::WALL,
+            Some(other) => bail!("unknown tile type `{}`", other),
+            None => return Ok(()),
+        };
+        *tile = serde_json::to_value(id)?;
+        Ok(())
+    }
+
+    if let Some(tiles) = world
+        .pointer_mut("/resources/map/tiles")
+        .and_then(Value::as_array_mut)
+    {
+        for tile in tiles {
+            convert(tile)?;
+        }
+    }
+    if let Some(entities) = world.pointer_mut("/entities").and_then(Value::as_array_mut) {
+        for entity in entities {
+            if let Some(tile) = entity.pointer_mut("/components/tile_type") {
+                convert(tile)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Version 2 maps had a single list of tiles, version 3 maps have layers.
+fn single_layer_to_layers(world: &mut Value) -> Result<()> {
+    let Some(map) = world
+        .pointer_mut("/resources/map")
+        .and_then(Value::as_object_mut)
+    else {
+        return Ok(());
+    };
+    let Some(tiles) = map.remove("tiles") else {
+        return Ok(());
+    };
+
+    let ground = serde_json::json!({ "name": GROUND_LAYER, "visible": true, "tiles": tiles });
+    map.insert("layers".into(), Value::Array(vec![ground]));
+    Ok(())
+}
+
+fn checksum(world: &Value) -> Result<String> {
+    let bytes = serde_json::to_vec(world)?;
+    let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    Ok(format!("{:016x}", hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::timer::DelayedAction;
+
+    /// Empty directory under the system temp dir, removed again on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("game-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn save_error(result: Result<()>) -> SaveError {
+        let err = result.expect_err("expected an error");
+        match err.downcast::<SaveError>() {
+            Ok(err) => err,
+            Err(err) => panic!("not a save error: {:#}", err),
+        }
+    }
+
+    fn played_game() -> Result<GameState> {
+        let mut game = GameState::new()?;
+        game.world_mut().insert_resource(Rng::new(3));
+        let goblin = game.world_mut().spawn();
+        game.world_mut().insert(goblin, Name("Goblin".into()))?;
+        game.world_mut().insert(goblin, Health::new(5))?;
+        let torch = game.world_mut().spawn();
+        game.world_mut()
+            .insert(torch, Transform::from_xy(0.0, 0.5))?;
+        game.world_mut().set_parent(torch, goblin)?;
+        game.world_mut().resource_mut::<Clock>().unwrap().advance();
+        game.world_mut().resource_mut::<Rng>().unwrap().next_u32();
+        game.set_tile(3, 3, TileId::WALL)?;
+        game.delayed_handlers_mut()
+            .register("burn_out", |game, torch| {
+                game.world_mut().despawn(torch.unwrap())
+            });
+        game.after(5, DelayedAction::on("burn_out", torch))?;
+        Ok(game)
+    }
+
+    #[test]
+    fn test_save_and_load_round_trip() -> Result<()> {
+        let dir = TempDir::new("round-trip");
+        let slots = SaveSlots::new(&dir.0);
+        let game = played_game()?;
+        slots.save(1, &game)?;
+        slots.save(3, &game)?;
+
+        let mut loaded = GameState::new()?;
+        slots.load(1, &mut loaded)?;
+        assert_eq!(
+            game.serializers.serialize(&game.world)?,
+            loaded.serializers.serialize(&loaded.world)?
+        );
+        assert_eq!(1, loaded.world().resource::<Clock>().unwrap().tick());
+        assert_eq!(Some(TileId::WALL), loaded.map().get(3, 3));
+        assert_eq!(1, loaded.pending_actions());
+
+        let numbers: Vec<u32> = slots.slots()?.iter().map(|info| info.slot).collect();
+        assert_eq!(vec![1, 3], numbers);
+        slots.delete(3)?;
+        assert_eq!(
+            SaveError::EmptySlot(3),
+            save_error(slots.load(3, &mut loaded))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupted_saves_are_rejected() -> Result<()> {
+        let dir = TempDir::new("corrupted");
+        let slots = SaveSlots::new(&dir.0);
+        slots.save(1, &played_game()?)?;
+        let original = std::fs::read_to_string(slots.path(1))?;
+        let mut game = GameState::new()?;
+
+        std::fs::write(slots.path(1), &original[..original.len() / 2])?;
+        assert!(matches!(
+            save_error(slots.load(1, &mut game)),
+            SaveError::Corrupted { slot: 1, .. }
+        ));
+
+        std::fs::write(slots.path(1), original.replace("Goblin", "Dragon"))?;
+        assert_eq!(
+            SaveError::Corrupted {
+                slot: 1,
+                reason: "checksum mismatch".into()
+            },
+            save_error(slots.load(1, &mut game))
+        );
+
+        let newer = original.replace(
+            &format!("\"version\": {}", SAVE_VERSION),
+            &format!("\"version\": {}", SAVE_VERSION + 1),
+        );
+        std::fs::write(slots.path(1), newer)?;
+        assert!(matches!(
+            save_error(slots.load(1, &mut game)),
+            SaveError::TooNew { .. }
+        ));
+
+        // Valid JSON with the right checksum, but the map is missing its tiles.
+        let mut file: SaveFile = serde_json::from_str(&original)?;
+        file.world["resources"]["map"]["layers"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("tiles");
+        file.checksum = checksum(&file.world)?;
+        std::fs::write(slots.path(1), serde_json::to_vec(&file)?)?;
+        let mut game = played_game()?;
+        assert!(matches!(
+            save_error(slots.load(1, &mut game)),
+            SaveError::Corrupted { slot: 1, .. }
+        ));
+
+        // Nothing was loaded along the way and the previous game is intact.
+        assert_eq!(Some(TileId::WALL), game.map().get(3, 3));
+        let mut query = game.world().query::<&Name>()?;
+        assert_eq!("Goblin", query.iter().next().unwrap().0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_old_versions_are_migrated() -> Result<()> {
+        let dir = TempDir::new("migrate");
+        let mut slots = SaveSlots::new(&dir.0);
+        slots.save(1, &played_game()?)?;
+
+        // Pretend version 0 called health `hp` and stored only the current value.
+        let mut file: SaveFile = serde_json::from_str(&std::fs::read_to_string(slots.path(1))?)?;
+        for entity in file.world["entities"].as_array_mut().unwrap() {
+            let components = entity["components"].as_object_mut().unwrap();
+            if let Some(health) = components.remove("health") {
+                components.insert("hp".into(), health["current"].clone());
+            }
+        }
+        file.version = 0;
+        file.checksum = checksum(&file.world)?;
+        std::fs::write(slots.path(1), serde_json::to_vec(&file)?)?;
+
+        let mut game = GameState::new()?;
+        assert_eq!(
+            SaveError::NoMigration { from: 0 },
+            save_error(slots.load(1, &mut game))
+        );
+
+        slots.add_migration(0, |world| {
+            for entity in world["entities"].as_array_mut().context("no entities")? {
+                let components = entity["components"].as_object_mut().context("no map")?;
+                if let Some(hp) = components.remove("hp") {
+                    let health = serde_json::json!({ "current": hp, "max": hp });
+                    components.insert("health".into(), health);
+                }
+            }
+            Ok(())
+        });
+        slots.load(1, &mut game)?;
+
+        let mut query = game.world().query::<(&Name, &Health)>()?;
+        let (name, health) = query.iter().next().unwrap();
+        assert_eq!("Goblin", name.0);
+        assert_eq!(Health::new(5), *health);
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_1_maps_are_migrated() -> Result<()> {
+        let dir = TempDir::new("tile-ids");
+        let slots = SaveSlots::new(&dir.0);
+        slots.save(1, &played_game()?)?;
+
+        let mut file: SaveFile = serde_json::from_str(&std::fs::read_to_string(slots.path(1))?)?;
+        // Version 1 had a single layer of named tiles.
+        let map = file.world["resources"]["map"].as_object_mut().unwrap();
+        let mut tiles = map.remove("layers").unwrap()[0]["tiles"].take();
+        for tile in tiles.as_array_mut().unwrap() {
+            *tile = match tile.as_u64() {
+                Some(0) => "Floor".into(),
+                _ => "Wall".into(),
+            };
+        }
+        map.insert("tiles".into(), tiles);
+        file.version = 1;
+        file.checksum = checksum(&file.world)?;
+        std::fs::write(slots.path(1), serde_json::to_vec(&file)?)?;
+
+        let mut game = GameState::new()?;
+        slots.load(1, &mut game)?;
+        assert_eq!(Some(TileId::WALL), game.map().get(3, 3));
+        assert_eq!(Some(TileId::FLOOR), game.map().get(2, 3));
+        Ok(())
+    }
+}
@@ -0,0 +1,736 @@
+use std::any::TypeId;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
+use rayon::prelude::*;
+
+use super::ecs::World;
+
+/// Fixed stages of a tick, run in declaration order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Stage {
+    PreInput,
+    Input,
+    Simulate,
+    PostSimulate,
+    RenderExtract,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [
+        Stage::PreInput,
+        Stage::Input,
+        Stage::Simulate,
+        Stage::PostSimulate,
+        Stage::RenderExtract,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::PreInput => "pre-input",
+            Stage::Input => "input",
+            Stage::Simulate => "simulate",
+            Stage::PostSimulate => "post-simulate",
+            Stage::RenderExtract => "render-extract",
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+pub type System<T> = Box<dyn FnMut(&mut T) -> Result<()>>;
+
+pub type Condition<T> = Box<dyn Fn(&T) -> bool>;
+
+pub type StageHook<T> = Box<dyn FnMut(&mut T, Stage) -> Result<()>>;
+
+/// A system that only needs a shared [`World`], so it can run on the thread pool next to others.
+pub type ParallelSystem = Box<dyn Fn(&World) -> Result<()> + Send + Sync>;
+
+/// State that parallel systems get their [`World`] from.
+pub trait WorldState {
+    fn world(&self) -> &World;
+}
+
+impl WorldState for World {
+    fn world(&self) -> &World {
+        self
+    }
+}
+
+/// Component and resource types a parallel system reads and writes. Systems in the same stage
+/// whose accesses don't conflict may run at the same time.
+///
+/// The declaration is only used for scheduling, the world still checks every borrow. A system
+/// touching something it didn't declare fails with a borrow error instead of racing.
+#[derive(Clone, Debug, Default)]
+pub struct SystemAccess {
+    reads: Vec<TypeId>,
+    writes: Vec<TypeId>,
+}
+
+impl SystemAccess {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read<T: 'static>(mut self) -> Self {
+        self.reads.push(TypeId::of::<T>());
+        self
+    }
+
+    pub fn write<T: 'static>(mut self) -> Self {
+        self.writes.push(TypeId::of::<T>());
+        self
+    }
+
+    /// Whether one of the two writes something the other one touches.
+    pub fn conflicts_with(&self, other: &SystemAccess) -> bool {
+        let touches =
+            |access: &SystemAccess, id| access.reads.contains(id) || access.writes.contains(id);
+        self.writes.iter().any(|id| touches(other, id))
+            || other.writes.iter().any(|id| touches(self, id))
+    }
+}
+
+enum Run<T> {
+    /// Gets the whole state and runs alone.
+    Exclusive(System<T>),
+    Parallel {
+        system: ParallelSystem,
+        access: SystemAccess,
+        world: fn(&T) -> &World,
+    },
+}
+
+struct SystemEntry<T> {
+    name: String,
+    stage: Stage,
+    before: Vec<String>,
+    after: Vec<String>,
+    condition: Option<Condition<T>>,
+    run: Run<T>,
+}
+
+impl<T> SystemEntry<T> {
+    fn access(&self) -> Option<&SystemAccess> {
+        match &self.run {
+            Run::Exclusive(_) => None,
+            Run::Parallel { access, .. } => Some(access),
+        }
+    }
+}
+
+/// Returned by [`ScheduleBuilder::add_system`] to attach ordering constraints.
+pub struct SystemHandle<'a, T> {
+    entry: &'a mut SystemEntry<T>,
+}
+
+impl<T> SystemHandle<'_, T> {
+    /// Run this system before the system called `other`.
+    pub fn before(self, other: &str) -> Self {
+        self.entry.before.push(other.to_string());
+        self
+    }
+
+    /// Run this system after the system called `other`.
+    pub fn after(self, other: &str) -> Self {
+        self.entry.after.push(other.to_string());
+        self
+    }
+
+    /// Skip this system on ticks where `condition` does not hold.
+    pub fn run_if<C>(self, condition: C) -> Self
+    where
+        C: Fn(&T) -> bool + 'static,
+    {
+        self.entry.condition = Some(Box::new(condition));
+        self
+    }
+}
+
+/// Collects systems and their constraints. Ordering is only resolved in [`ScheduleBuilder::build`],
+/// so systems can reference each other regardless of registration order.
+pub struct ScheduleBuilder<T> {
+    systems: Vec<SystemEntry<T>>,
+    stage_end: Vec<StageHook<T>>,
+}
+
+impl<T> Default for ScheduleBuilder<T> {
+    fn default() -> Self {
+        Self {
+            systems: Vec::new(),
+            stage_end: Vec::new(),
+        }
+    }
+}
+
+impl<T> ScheduleBuilder<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_system<F>(&mut self, stage: Stage, name: &str, system: F) -> SystemHandle<'_, T>
+    where
+        F: FnMut(&mut T) -> Result<()> + 'static,
+    {
+        self.push(stage, name, Run::Exclusive(Box::new(system)))
+    }
+
+    /// Adds a system that may run in parallel with the other parallel systems of its stage,
+    /// as long as their `access` doesn't conflict and no ordering constraint is in the way.
+    pub fn add_parallel_system<F>(
+        &mut self,
+        stage: Stage,
+        name: &str,
+        access: SystemAccess,
+        system: F,
+    ) -> SystemHandle<'_, T>
+    where
+        T: WorldState,
+        F: Fn(&World) -> Result<()> + Send + Sync + 'static,
+    {
+        let run = Run::Parallel {
+            system: Box::new(system),
+            access,
+            world: T::world,
+        };
+        self.push(stage, name, run)
+    }
+
+    fn push(&mut self, stage: Stage, name: &str, run: Run<T>) -> SystemHandle<'_, T> {
+        self.systems.push(SystemEntry {
+            name: name.to_string(),
+            stage,
+            before: Vec::new(),
+            after: Vec::new(),
+            condition: None,
+            run,
+        });
+
+        SystemHandle {
+            entry: self.systems.last_mut().unwrap(),
+        }
+    }
+
+    /// Runs `hook` after every stage, even when all its systems were skipped. This is where
+    /// deferred work like queued commands gets applied.
+    pub fn on_stage_end<F>(&mut self, hook: F)
+    where
+        F: FnMut(&mut T, Stage) -> Result<()> + 'static,
+    {
+        self.stage_end.push(Box::new(hook));
+    }
+
+    /// Resolves the order of every stage. Fails on duplicate or unknown system names, on
+    /// constraints that contradict the stage order and on cycles.
+    pub fn build(self) -> Result<Schedule<T>> {
+        let mut by_name = HashMap::new();
+        for (idx, system) in self.systems.iter().enumerate() {
+            if by_name.insert(system.name.as_str(), idx).is_some() {
+                bail!("system `{}` registered twice", system.name)
+            }
+        }
+
+        // Edge `a -> b` means a runs before b.
+        let mut edges: Vec<Vec<usize>> = vec![Vec::new(); self.systems.len()];
+        for (idx, system) in self.systems.iter().enumerate() {
+            let constraints = system
+                .before
+                .iter()
+                .map(|other| (other, true))
+                .chain(system.after.iter().map(|other| (other, false)));
+
+            for (other, is_before) in constraints {
+                let Some(&other_idx) = by_name.get(other.as_str()) else {
+                    bail!(
+                        "system `{}` is ordered against unknown system `{}`",
+                        system.name,
+                        other
+                    )
+                };
+                let (first, second) = if is_before {
+                    (idx, other_idx)
+                } else {
+                    (other_idx, idx)
+                };
+
+                let (first_stage, second_stage) =
+                    (self.systems[first].stage, self.systems[second].stage);
+                if first_stage == second_stage {
+                    edges[first].push(second);
+                } else if first_stage > second_stage {
+                    bail!(
+                        "`{}` must run before `{}` but stage {} runs after stage {}",
+                        self.systems[first].name,
+                        self.systems[second].name,
+                        first_stage,
+                        second_stage
+                    )
+                }
+            }
+        }
+
+        let mut order = Vec::with_capacity(Stage::ALL.len());
+        for stage in Stage::ALL {
+            let members: Vec<usize> = (0..self.systems.len())
+                .filter(|idx| self.systems[*idx].stage == stage)
+                .collect();
+            let sorted = sort_stage(&members, &edges).map_err(|cycle| {
+                let names: Vec<&str> = cycle
+                    .iter()
+                    .map(|idx| self.systems[*idx].name.as_str())
+                    .collect();
+                anyhow::anyhow!("cycle in stage {}: {}", stage, names.join(" -> "))
+            })?;
+            let batches = batch_stage(&sorted, &self.systems, &edges);
+            order.push((stage, sorted, batches));
+        }
+
+        Ok(Schedule {
+            systems: self.systems,
+            order,
+            stage_end: self.stage_end,
+            deterministic: false,
+        })
+    }
+}
+
+/// Splits the sorted systems of a stage into batches that may run at the same time. Batches are
+/// contiguous runs of parallel systems that neither conflict nor are ordered against each other,
+/// exclusive systems always get a batch of their own. Running the batches in order therefore
+/// respects every constraint.
+fn batch_stage<T>(
+    sorted: &[usize],
+    systems: &[SystemEntry<T>],
+    edges: &[Vec<usize>],
+) -> Vec<Vec<usize>> {
+    let mut batches: Vec<Vec<usize>> = Vec::new();
+    for &idx in sorted {
+        let fits = |batch: &Vec<usize>| {
+            let Some(access) = systems[idx].access() else {
+                return false;
+            };
+            batch.iter().all(|&other| {
+                systems[other]
+                    .access()
+                    .is_some_and(|other| !other.conflicts_with(access))
+                    && !edges[other].contains(&idx)
+            })
+        };
+        match batches.last_mut() {
+            Some(batch) if fits(batch) => batch.push(idx),
+            _ => batches.push(vec![idx]),
+        }
+    }
+    batches
+}
+
+/// Kahn's algorithm, breaking ties by registration order so the result is stable. On failure the
+/// systems forming a cycle are returned, with the first one repeated at the end.
+fn sort_stage(members: &[usize], edges: &[Vec<usize>]) -> Result<Vec<usize>, Vec<usize>> {
+    let mut in_degree: HashMap<usize, usize> = members.iter().map(|idx| (*idx, 0)).collect();
+    for idx in members {
+        for next in &edges[*idx] {
+            *in_degree.get_mut(next).unwrap() += 1;
+        }
+    }
+
+    let mut ready: BinaryHeap<Reverse<usize>> = in_degree
+        .iter()
+        .filter(|(_, degree)| **degree == 0)
+        .map(|(idx, _)| Reverse(*idx))
+        .collect();
+    let mut sorted = Vec::with_capacity(members.len());
+
+    while let Some(Reverse(idx)) = ready.pop() {
+        sorted.push(idx);
+        for next in &edges[idx] {
+            let degree = in_degree.get_mut(next).unwrap();
+            *degree -= 1;
+            if *degree == 0 {
+                ready.push(Reverse(*next));
+            }
+        }
+    }
+
+    if sorted.len() == members.len() {
+        return Ok(sorted);
+    }
+
+    // Every unsorted system has an unsorted predecessor, so walking backwards from any of them
+    // must run into a cycle.
+    let remaining: Vec<usize> = members
+        .iter()
+        .copied()
+        .filter(|idx| in_degree[idx] > 0)
+        .collect();
+    let mut path = vec![remaining[0]];
+    loop {
+        let current = *path.last().unwrap();
+        let prev = remaining
+            .iter()
+            .copied()
+            .find(|idx| edges[*idx].contains(&current))
+            .unwrap();
+        if let Some(pos) = path.iter().position(|idx| *idx == prev) {
+            let mut cycle = vec![prev];
+            cycle.extend(path[pos + 1..].iter().rev());
+            cycle.push(prev);
+            return Err(cycle);
+        }
+        path.push(prev);
+    }
+}
+
+/// Systems in their resolved order. Built with [`ScheduleBuilder`].
+pub struct Schedule<T> {
+    systems: Vec<SystemEntry<T>>,
+    /// Per stage the systems in resolved order and the same systems grouped into batches.
+    order: Vec<(Stage, Vec<usize>, Vec<Vec<usize>>)>,
+    stage_end: Vec<StageHook<T>>,
+    deterministic: bool,
+}
+
+impl<T> Schedule<T> {
+    /// Runs every stage in order.
+    pub fn run(&mut self, state: &mut T) -> Result<()> {
+        for stage in Stage::ALL {
+            self.run_stage(stage, state)?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs parallel systems one after another in their resolved order instead of on the thread
+    /// pool, e.g. while recording or playing back a replay.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    pub fn run_stage(&mut self, stage: Stage, state: &mut T) -> Result<()> {
+        let (_, _, batches) = self.order.iter().find(|(s, ..)| *s == stage).unwrap();
+
+        for batch in batches {
+            if let [idx] = batch[..] {
+                let system = &mut self.systems[idx];
+                if let Run::Exclusive(run) = &mut system.run {
+                    if system.condition.as_ref().is_some_and(|c| !c(state)) {
+                        continue;
+                    }
+                    run(state).with_context(|| {
+                        format!("system `{}` failed in stage {}", system.name, stage)
+                    })?;
+                    continue;
+                }
+            }
+
+            // Conditions of a batch are checked up front, its systems don't see each other's
+            // effects anyway.
+            let mut parallel = Vec::with_capacity(batch.len());
+            let mut world_of = None;
+            for system in batch.iter().map(|idx| &self.systems[*idx]) {
+                if system.condition.as_ref().is_some_and(|c| !c(state)) {
+                    continue;
+                }
+                if let Run::Parallel {
+                    system: run, world, ..
+                } = &system.run
+                {
+                    parallel.push((system.name.as_str(), run));
+                    world_of = Some(*world);
+                }
+            }
+            let Some(world_of) = world_of else {
+                continue;
+            };
+
+            let world = world_of(state);
+            let run = |(name, system): &(&str, &ParallelSystem)| {
+                system(world)
+                    .with_context(|| format!("system `{}` failed in stage {}", name, stage))
+            };
+            if self.deterministic || parallel.len() == 1 {
+                parallel.iter().try_for_each(run)?;
+            } else {
+                let results: Vec<Result<()>> = parallel.par_iter().map(run).collect();
+                // Report the first failure in resolved order, not whichever finished first.
+                results.into_iter().collect::<Result<()>>()?;
+            }
+        }
+
+        for hook in &mut self.stage_end {
+            hook(state, stage).with_context(|| format!("end of stage {} failed", stage))?;
+        }
+
+        Ok(())
+    }
+
+    /// Names of the systems of `stage` in the order they run.
+    pub fn order(&self, stage: Stage) -> Vec<&str> {
+        self.order
+            .iter()
+            .find(|(s, ..)| *s == stage)
+            .map(|(_, order, _)| {
+                order
+                    .iter()
+                    .map(|idx| self.systems[*idx].name.as_str())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Names of the systems of `stage` grouped by the batches that may run at the same time.
+    pub fn batches(&self, stage: Stage) -> Vec<Vec<&str>> {
+        self.order
+            .iter()
+            .find(|(s, ..)| *s == stage)
+            .map(|(_, _, batches)| {
+                batches
+                    .iter()
+                    .map(|batch| {
+                        batch
+                            .iter()
+                            .map(|idx| self.systems[*idx].name.as_str())
+                            .collect()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl<T> fmt::Display for Schedule<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for stage in Stage::ALL {
+            writeln!(f, "{}:", stage)?;
+            for (pos, name) in self.order(stage).iter().enumerate() {
+                writeln!(f, "  {}. {}", pos + 1, name)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(name: &'static str) -> impl FnMut(&mut Vec<&'static str>) -> Result<()> {
+        move |log| {
+            log.push(name);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_constraints_and_stage_order() -> Result<()> {
+        let mut builder = ScheduleBuilder::new();
+        builder.add_system(Stage::RenderExtract, "extract", log("extract"));
+        builder
+            .add_system(Stage::Simulate, "collide", log("collide"))
+            .after("move");
+        builder.add_system(Stage::Simulate, "move", log("move"));
+        builder
+            .add_system(Stage::Simulate, "ai", log("ai"))
+            .before("move")
+            .after("read_keys");
+        builder.add_system(Stage::Input, "read_keys", log("read_keys"));
+
+        let mut schedule = builder.build()?;
+        let mut ran = Vec::new();
+        schedule.run(&mut ran)?;
+
+        assert_eq!(vec!["read_keys", "ai", "move", "collide", "extract"], ran);
+        assert_eq!(
+            vec!["ai", "move", "collide"],
+            schedule.order(Stage::Simulate)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unconstrained_systems_keep_registration_order() -> Result<()> {
+        let mut builder = ScheduleBuilder::new();
+        builder.add_system(Stage::Simulate, "c", log("c"));
+        builder.add_system(Stage::Simulate, "a", log("a"));
+        builder.add_system(Stage::Simulate, "b", log("b"));
+
+        let schedule = builder.build()?;
+        assert_eq!(vec!["c", "a", "b"], schedule.order(Stage::Simulate));
+        assert_eq!(
+            "pre-input:\ninput:\nsimulate:\n  1. c\n  2. a\n  3. b\npost-simulate:\nrender-extract:\n",
+            schedule.to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_if_skips_system() -> Result<()> {
+        let mut builder = ScheduleBuilder::new();
+        builder
+            .add_system(Stage::Simulate, "once", log("once"))
+            .run_if(|ran: &Vec<&str>| ran.is_empty());
+        builder.add_system(Stage::Simulate, "always", log("always"));
+
+        let mut schedule = builder.build()?;
+        let mut ran = Vec::new();
+        schedule.run(&mut ran)?;
+        schedule.run(&mut ran)?;
+
+        assert_eq!(vec!["once", "always", "always"], ran);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stage_end_runs_after_each_stage() -> Result<()> {
+        let mut builder = ScheduleBuilder::new();
+        builder.add_system(Stage::Input, "read_keys", log("read_keys"));
+        builder.add_system(Stage::Simulate, "move", log("move"));
+        builder.on_stage_end(|ran: &mut Vec<&str>, stage| {
+            if stage <= Stage::Simulate {
+                ran.push(stage.name());
+            }
+            Ok(())
+        });
+
+        let mut schedule = builder.build()?;
+        let mut ran = Vec::new();
+        schedule.run(&mut ran)?;
+
+        assert_eq!(
+            vec!["pre-input", "read_keys", "input", "move", "simulate"],
+            ran
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_systems_are_batched() -> Result<()> {
+        struct A(u32);
+        struct B(u32);
+        struct Log(Vec<&'static str>);
+
+        fn bump_a(world: &World) -> Result<()> {
+            world.borrow_resource_mut::<A>()?.unwrap().0 += 1;
+            Ok(())
+        }
+        fn bump_b(world: &World) -> Result<()> {
+            world.borrow_resource_mut::<B>()?.unwrap().0 += 1;
+            Ok(())
+        }
+        fn check(world: &World) -> Result<()> {
+            let (a, b) = (
+                world.resource::<A>().unwrap(),
+                world.resource::<B>().unwrap(),
+            );
+            world.borrow_resource_mut::<Log>()?.unwrap().0.push("check");
+            if a.0 != b.0 {
+                bail!("a and b out of step")
+            }
+            Ok(())
+        }
+
+        let mut builder = ScheduleBuilder::<World>::new();
+        let writes_a = SystemAccess::new().write::<A>();
+        let writes_b = SystemAccess::new().write::<B>();
+        builder.add_parallel_system(Stage::Simulate, "a", writes_a.clone(), bump_a);
+        builder.add_parallel_system(Stage::Simulate, "b", writes_b.clone(), bump_b);
+        builder.add_parallel_system(
+            Stage::Simulate,
+            "check",
+            SystemAccess::new().read::<A>().read::<B>().write::<Log>(),
+            check,
+        );
+        builder.add_system(Stage::Simulate, "exclusive", |world: &mut World| {
+            world.resource_mut::<Log>().unwrap().0.push("exclusive");
+            Ok(())
+        });
+        // Not conflicting, but ordered.
+        builder
+            .add_parallel_system(Stage::Simulate, "a_again", writes_a, bump_a)
+            .after("b_again");
+        builder.add_parallel_system(Stage::Simulate, "b_again", writes_b, bump_b);
+
+        let mut schedule = builder.build()?;
+        assert_eq!(
+            vec![
+                vec!["a", "b"],
+                vec!["check"],
+                vec!["exclusive"],
+                vec!["b_again"],
+                vec!["a_again"],
+            ],
+            schedule.batches(Stage::Simulate)
+        );
+
+        for deterministic in [false, true] {
+            let mut world = World::new();
+            world.insert_resource(A(0));
+            world.insert_resource(B(0));
+            world.insert_resource(Log(Vec::new()));
+            schedule.set_deterministic(deterministic);
+            schedule.run(&mut world)?;
+            schedule.run(&mut world)?;
+
+            assert_eq!(4, world.resource::<A>().unwrap().0);
+            assert_eq!(
+                vec!["check", "exclusive", "check", "exclusive"],
+                world.resource::<Log>().unwrap().0
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cycle_is_rejected() {
+        let mut builder = ScheduleBuilder::new();
+        builder
+            .add_system(Stage::Simulate, "a", log("a"))
+            .before("b");
+        builder
+            .add_system(Stage::Simulate, "b", log("b"))
+            .before("c");
+        builder
+            .add_system(Stage::Simulate, "c", log("c"))
+            .before("a");
+        builder
+            .add_system(Stage::Simulate, "d", log("d"))
+            .after("c");
+
+        let err = builder.build().err().unwrap().to_string();
+        assert_eq!("cycle in stage simulate: a -> b -> c -> a", err);
+    }
+
+    #[test]
+    fn test_invalid_constraints_are_rejected() {
+        let mut builder = ScheduleBuilder::new();
+        builder
+            .add_system(Stage::Simulate, "a", log("a"))
+            .after("missing");
+        assert!(builder.build().is_err());
+
+        let mut builder = ScheduleBuilder::new();
+        builder.add_system(Stage::Simulate, "a", log("a"));
+        builder.add_system(Stage::Input, "a", log("a"));
+        assert!(builder.build().is_err());
+
+        let mut builder = ScheduleBuilder::new();
+        builder
+            .add_system(Stage::Simulate, "a", log("a"))
+            .before("b");
+        builder.add_system(Stage::Input, "b", log("b"));
+        assert!(builder.build().is_err());
+    }
+}
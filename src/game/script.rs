@@ -0,0 +1,519 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{CallFnOptions, Dynamic, Engine, FuncArgs, Scope, AST};
+use serde::{Deserialize, Serialize};
+
+use super::components::{Health, Name};
+use super::ecs::{Entity, Transform, World};
+use super::events::ScriptError;
+use super::tiles::{self, TileRegistry};
+use super::turns::{Action, Player, Turns};
+use super::{GameState, TileMap};
+
+const SCRIPT_EXTENSION: &str = "rhai";
+
+/// Operations a single hook may run before it is aborted. Plenty for a behaviour, but an endless
+/// loop fails within a frame instead of hanging the game.
+pub const MAX_OPERATIONS: u64 = 100_000;
+
+/// Gives an entity the behaviour of `scripts/<name>.rhai`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Script {
+    pub name: String,
+    /// Whether `on_spawn` already ran.
+    #[serde(default)]
+    started: bool,
+}
+
+impl Script {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            started: false,
+        }
+    }
+}
+
+/// Functions a script may define. All of them are optional.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hook {
+    /// `on_spawn(world, me)`, on the first tick the entity has its script.
+    Spawn,
+    /// `on_turn(world, me)`, when the actor is ready. Returns `wait()` or `move_by(dx, dy)`, or
+    /// nothing to fall back to the default AI.
+    Turn,
+    /// `on_interact(world, me, other)`, when `other` bumps into the entity.
+    Interact,
+}
+
+impl Hook {
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::Spawn => "on_spawn",
+            Hook::Turn => "on_turn",
+            Hook::Interact => "on_interact",
+        }
+    }
+}
+
+/// The world as scripts see it. It is only shared for the duration of a hook.
+#[derive(Clone)]
+struct ScriptWorld(Rc<RefCell<World>>);
+
+impl ScriptWorld {
+    fn entity(&self, entity: Entity) -> ScriptEntity {
+        ScriptEntity {
+            world: self.0.clone(),
+            entity,
+        }
+    }
+
+    /// Name of the tile at `(x, y)`.
+    fn tile(&self, x: i64, y: i64) -> Option<String> {
+        let world = self.0.borrow();
+        let map = world.resource::<TileMap>()?;
+        let id = map.get(usize::try_from(x).ok()?, usize::try_from(y).ok()?)?;
+        let tiles = world.resource::<TileRegistry>()?;
+        tiles.get(id).map(|def| def.name.clone())
+    }
+
+    fn is_walkable(&self, x: i64, y: i64) -> bool {
+        match (usize::try_from(x), usize::try_from(y)) {
+            (Ok(x), Ok(y)) => tiles::is_walkable(&self.0.borrow(), x, y),
+            _ => false,
+        }
+    }
+
+    fn entity_at(&self, x: i64, y: i64) -> Dynamic {
+        let found = entity_at(&self.0.borrow(), x, y, None);
+        found.map_or(Dynamic::UNIT, |e| Dynamic::from(self.entity(e)))
+    }
+
+    fn player(&self) -> Dynamic {
+        let player = self
+            .0
+            .borrow()
+            .query::<(Entity, &Player)>()
+            .ok()
+            .and_then(|mut query| query.iter().next().map(|(e, _)| e));
+        player.map_or(Dynamic::UNIT, |e| Dynamic::from(self.entity(e)))
+    }
+
+    fn dimensions(&self) -> (i64, i64) {
+        let world = self.0.borrow();
+        let map = world.resource::<TileMap>();
+        map.map_or((0, 0), |map| {
+            let (w, h) = map.dimensions();
+            (w as i64, h as i64)
+        })
+    }
+}
+
+#[derive(Clone)]
+struct ScriptEntity {
+    world: Rc<RefCell<World>>,
+    entity: Entity,
+}
+
+impl ScriptEntity {
+    fn position(&self) -> (i64, i64) {
+        let world = self.world.borrow();
+        world.get::<Transform>(self.entity).map_or((0, 0), |t| {
+            (
+                t.translation.x.round() as i64,
+                t.translation.y.round() as i64,
+            )
+        })
+    }
+
+    fn health(&self) -> Option<Health> {
+        self.world.borrow().get::<Health>(self.entity).map(|h| *h)
+    }
+
+    fn change_health(&mut self, f: impl FnOnce(&mut Health)) {
+        if let Some(health) = self.world.borrow_mut().get_mut::<Health>(self.entity) {
+            f(health);
+        }
+    }
+}
+
+/// Sets up the functions scripts can call. There is no file, module or system access.
+fn build_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(4096)
+        .set_max_array_size(1024)
+        .set_max_map_size(256)
+        .set_module_resolver(DummyModuleResolver::new())
+        .on_print(|text| println!("[script] {}", text))
+        .on_debug(|text, _, pos| println!("[script] {:?} {}", pos, text));
+
+    engine
+        .register_type_with_name::<ScriptWorld>("World")
+        .register_fn("width", |w: &mut ScriptWorld| w.dimensions().0)
+        .register_fn("height", |w: &mut ScriptWorld| w.dimensions().1)
+        .register_fn("tile", |w: &mut ScriptWorld, x: i64, y: i64| {
+            w.tile(x, y).unwrap_or_default()
+        })
+        .register_fn("is_walkable", |w: &mut ScriptWorld, x: i64, y: i64| {
+            w.is_walkable(x, y)
+        })
+        .register_fn("entity_at", ScriptWorld::entity_at)
+        .register_fn("player", |w: &mut ScriptWorld| w.player())
+        .register_fn("turn", |w: &mut ScriptWorld| {
+            let world = w.0.borrow();
+            world.resource::<Turns>().map_or(0, |t| t.turn as i64)
+        });
+
+    engine
+        .register_type_with_name::<ScriptEntity>("Entity")
+        .register_get("x", |e: &mut ScriptEntity| e.position().0)
+        .register_get("y", |e: &mut ScriptEntity| e.position().1)
+        .register_get("name", |e: &mut ScriptEntity| {
+            let world = e.world.borrow();
+            world
+                .get::<Name>(e.entity)
+                .map_or(String::new(), |name| name.0.clone())
+        })
+        .register_get("health", |e: &mut ScriptEntity| {
+            e.health().map_or(0, |h| h.current as i64)
+        })
+        .register_get("max_health", |e: &mut ScriptEntity| {
+            e.health().map_or(0, |h| h.max as i64)
+        })
+        .register_fn("damage", |e: &mut ScriptEntity, amount: i64| {
+            let amount = amount.clamp(0, u32::MAX as i64) as u32;
+            e.change_health(|h| h.current = h.current.saturating_sub(amount));
+        })
+        .register_fn("heal", |e: &mut ScriptEntity, amount: i64| {
+            let amount = amount.clamp(0, u32::MAX as i64) as u32;
+            e.change_health(|h| h.current = h.current.saturating_add(amount).min(h.max));
+        })
+        .register_fn("==", |a: ScriptEntity, b: ScriptEntity| {
+            a.entity == b.entity
+        })
+        .register_fn("!=", |a: ScriptEntity, b: ScriptEntity| {
+            a.entity != b.entity
+        })
+        .register_fn("to_string", |e: &mut ScriptEntity| e.entity.to_string())
+        .register_fn("to_debug", |e: &mut ScriptEntity| e.entity.to_string());
+
+    engine
+        .register_type_with_name::<Action>("Action")
+        .register_fn("wait", || Action::Wait)
+        .register_fn("move_by", |dx: i64, dy: i64| Action::Move {
+            dx: dx.signum() as i32,
+            dy: dy.signum() as i32,
+        });
+
+    engine
+}
+
+/// Compiled behaviour scripts and the sandboxed engine that runs them.
+pub struct Scripts {
+    engine: Engine,
+    asts: HashMap<String, AST>,
+}
+
+impl Default for Scripts {
+    fn default() -> Self {
+        Self {
+            engine: build_engine(),
+            asts: HashMap::new(),
+        }
+    }
+}
+
+impl Scripts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compiles every `.rhai` file in `dir`, named after the file stem. A missing directory just
+    /// means there are no scripts.
+    pub fn load_dir(dir: &Path) -> Result<Self> {
+        let mut scripts = Self::new();
+        if !dir.exists() {
+            log::info!("No scripts at {}", dir.display());
+            return Ok(scripts);
+        }
+
+        let entries =
+            std::fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != SCRIPT_EXTENSION) {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                bail!("script file name {} is not valid utf-8", path.display())
+            };
+            let source = std::fs::read_to_string(&path)
+                .with_context(|| format!("reading {}", path.display()))?;
+            scripts
+                .add_source(name, &source)
+                .with_context(|| format!("loading {}", path.display()))?;
+        }
+
+        log::info!(
+            "Loaded {} scripts from {}",
+            scripts.asts.len(),
+            dir.display()
+        );
+        Ok(scripts)
+    }
+
+    /// Compiles a script. Syntax errors show up here rather than when the script first runs.
+    pub fn add_source(&mut self, name: &str, source: &str) -> Result<()> {
+        let ast = self
+            .engine
+            .compile(source)
+            .map_err(|err| anyhow!("compiling script: {}", err))?;
+        self.asts.insert(name.to_string(), ast);
+        Ok(())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.asts.contains_key(name)
+    }
+
+    fn defines(&self, name: &str, hook: Hook) -> bool {
+        self.asts
+            .get(name)
+            .is_some_and(|ast| ast.iter_functions().any(|f| f.name == hook.name()))
+    }
+}
+
+impl GameState {
+    /// Replaces the known scripts with the ones in the assets directory.
+    pub fn load_scripts(
+        &mut self,
+        assets_path: &crate::graphics::assets::AssetsPath,
+    ) -> Result<()> {
+        self.scripts = Scripts::load_dir(&crate::graphics::assets::scripts_path(assets_path))?;
+        Ok(())
+    }
+
+    pub fn scripts_mut(&mut self) -> &mut Scripts {
+        &mut self.scripts
+    }
+}
+
+/// The scripted entity standing on `(x, y)`, other than `except`.
+fn entity_at(world: &World, x: i64, y: i64, except: Option<Entity>) -> Option<Entity> {
+    let mut query = world.query::<(Entity, &Transform)>().ok()?;
+    query
+        .iter()
+        .filter(|(e, _)| Some(*e) != except)
+        .find(|(_, t)| t.translation.x.round() as i64 == x && t.translation.y.round() as i64 == y)
+        .map(|(e, _)| e)
+}
+
+/// Runs `hook` of the script of `entity`. Failing scripts are reported with a [`ScriptError`]
+/// and treated like they did nothing, so content bugs can't take the game down.
+fn run_hook(
+    game: &mut GameState,
+    entity: Entity,
+    hook: Hook,
+    other: Option<Entity>,
+) -> Option<Action> {
+    let name = game.world.get::<Script>(entity)?.name.clone();
+    if !game.scripts.contains(&name) {
+        report(game, &name, hook, "script not loaded".to_string());
+        return None;
+    }
+    if !game.scripts.defines(&name, hook) {
+        return None;
+    }
+
+    let world = Rc::new(RefCell::new(std::mem::take(&mut game.world)));
+    let api = ScriptWorld(world.clone());
+    let me = api.entity(entity);
+    let result = match other {
+        Some(other) => call(
+            &game.scripts,
+            &name,
+            hook,
+            (api.clone(), me, api.entity(other)),
+        ),
+        None => call(&game.scripts, &name, hook, (api.clone(), me)),
+    };
+    let action = result.and_then(|value| {
+        if value.is_unit() || hook != Hook::Turn {
+            return Ok(None);
+        }
+        let type_name = value.type_name();
+        value
+            .try_cast::<Action>()
+            .map(Some)
+            .ok_or_else(|| format!("expected wait() or move_by(dx, dy), got {}", type_name))
+    });
+
+    // Scripts can't keep handles around, so with `api` gone nothing else refers to the world.
+    drop(api);
+    game.world = Rc::try_unwrap(world)
+        .ok()
+        .expect("script kept the world")
+        .into_inner();
+
+    match action {
+        Ok(action) => action,
+        Err(message) => {
+            report(game, &name, hook, message);
+            None
+        }
+    }
+}
+
+fn call(scripts: &Scripts, name: &str, hook: Hook, args: impl FuncArgs) -> Result<Dynamic, String> {
+    let options = CallFnOptions::new().eval_ast(false);
+    scripts
+        .engine
+        .call_fn_with_options(
+            options,
+            &mut Scope::new(),
+            &scripts.asts[name],
+            hook.name(),
+            args,
+        )
+        .map_err(|err| err.to_string())
+}
+
+fn report(game: &mut GameState, script: &str, hook: Hook, message: String) {
+    log::warn!("Script {} failed in {}: {}", script, hook.name(), message);
+    game.events.send(ScriptError {
+        script: script.to_string(),
+        hook: hook.name(),
+        message,
+    });
+}
+
+/// Runs `on_spawn` for scripted entities that were added since the last tick.
+pub fn start_scripts(game: &mut GameState) -> Result<()> {
+    let new: Vec<Entity> = game
+        .world
+        .query::<(Entity, &Script)>()?
+        .iter()
+        .filter(|(_, script)| !script.started)
+        .map(|(entity, _)| entity)
+        .collect();
+    for entity in new {
+        if let Some(script) = game.world.get_mut::<Script>(entity) {
+            script.started = true;
+        }
+        run_hook(game, entity, Hook::Spawn, None);
+    }
+    Ok(())
+}
+
+/// Asks the script of `actor` what to do, `None` if it has no opinion.
+pub fn on_turn(game: &mut GameState, actor: Entity) -> Option<Action> {
+    run_hook(game, actor, Hook::Turn, None)
+}
+
+/// Lets `actor` bump into whatever scripted entity stands on `(x, y)`. Returns whether there was
+/// one, in which case the actor doesn't move.
+pub fn interact_at(game: &mut GameState, actor: Entity, x: i64, y: i64) -> bool {
+    let target = entity_at(&game.world, x, y, Some(actor));
+    let Some(target) = target.filter(|e| game.world.has::<Script>(*e)) else {
+        return false;
+    };
+    run_hook(game, target, Hook::Interact, Some(actor));
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::ecs::EventReader;
+    use crate::game::turns::Actor;
+
+    fn spawn(game: &mut GameState, script: &str, x: f32) -> Result<Entity> {
+        let e = game.world_mut().spawn();
+        game.world_mut().insert(e, Script::new(script))?;
+        game.world_mut().insert(e, Transform::from_xy(x, 5.0))?;
+        game.world_mut().insert(e, Health::new(10))?;
+        Ok(e)
+    }
+
+    #[test]
+    fn test_hooks_use_the_api() -> Result<()> {
+        let mut game = GameState::new()?;
+        game.scripts_mut().add_source(
+            "chaser",
+            r#"
+                fn on_spawn(world, me) { me.damage(3); }
+                fn on_turn(world, me) {
+                    let player = world.player();
+                    if player == () || !world.is_walkable(me.x - 1, me.y) { return; }
+                    move_by(player.x - me.x, 0)
+                }
+                fn on_interact(world, me, other) { other.heal(100); }
+            "#,
+        )?;
+        let chaser = spawn(&mut game, "chaser", 4.0)?;
+        let player = spawn(&mut game, "chaser", 1.0)?;
+        game.world_mut().remove::<Script>(player);
+        game.world_mut().insert(player, Player)?;
+        game.world_mut()
+            .insert(player, Health { current: 1, max: 5 })?;
+
+        start_scripts(&mut game)?;
+        assert_eq!(7, game.world().get::<Health>(chaser).unwrap().current);
+        assert_eq!(
+            Some(Action::Move { dx: -1, dy: 0 }),
+            on_turn(&mut game, chaser)
+        );
+        assert!(interact_at(&mut game, player, 4, 5));
+        assert_eq!(5, game.world().get::<Health>(player).unwrap().current);
+        assert!(!interact_at(&mut game, chaser, 1, 5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_shipped_bat_bites() -> Result<()> {
+        let assets = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets");
+        let mut game = GameState::new()?;
+        game.load_prefabs(&assets)?;
+        game.load_scripts(&assets)?;
+        let bat = game.spawn_prefab("bat")?;
+        game.world_mut().insert(bat, Transform::from_xy(5.0, 5.0))?;
+        let player = spawn(&mut game, "none", 4.0)?;
+        game.world_mut().remove::<Script>(player);
+        game.world_mut().insert(player, Player)?;
+
+        start_scripts(&mut game)?;
+        assert_eq!(Some(Action::Wait), on_turn(&mut game, bat));
+        assert_eq!(9, game.world().get::<Health>(player).unwrap().current);
+        Ok(())
+    }
+
+    #[test]
+    fn test_runaway_script_is_reported() -> Result<()> {
+        let mut game = GameState::new()?;
+        game.scripts_mut()
+            .add_source("stuck", "fn on_turn(world, me) { loop {} }")?;
+        let e = spawn(&mut game, "stuck", 4.0)?;
+        game.world_mut().insert(e, Actor::new(10))?;
+        let mut reader = EventReader::<ScriptError>::new();
+
+        assert_eq!(None, on_turn(&mut game, e));
+        let errors: Vec<_> = game.events().read(&mut reader).cloned().collect();
+        assert_eq!(1, errors.len());
+        assert_eq!("on_turn", errors[0].hook);
+        assert!(game.world().is_alive(e));
+        assert!(game
+            .scripts_mut()
+            .add_source("broken", "fn on_turn(")
+            .is_err());
+        Ok(())
+    }
+}
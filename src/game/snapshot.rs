@@ -0,0 +1,252 @@
+use anyhow::Result;
+
+use super::camera::CameraRig;
+use super::chunks::ChunkMap;
+use super::clock::Clock;
+use super::components::{Health, Name, Tint};
+use super::ecs::{
+    Children, EventsSnapshot, GlobalTransform, Parent, SnapshotRegistry, Transform, WorldSnapshot,
+};
+use super::events::{
+    ActorActed, EntityDied, LevelEntered, ScriptError, TileChanged, TweenFinished, UserEvent,
+};
+use super::rng::Rng;
+use super::script::Script;
+use super::state::StateSnapshot;
+use super::tiles::TileId;
+use super::timer::{Cooldown, DelayedActions, Timer};
+use super::turns::{Actor, Player, Turns};
+use super::tween::Tweens;
+use super::{GameState, TileMap};
+
+/// Complete simulation state of a [`GameState`] at the end of a tick: entities, components, the
+/// map, the RNG, the clock, pending delayed actions, events still in flight and the state stack.
+///
+/// Input and state hooks are not part of it. Commands are always applied by the
+/// end of a tick, so there are none to capture.
+pub struct GameSnapshot {
+    world: WorldSnapshot,
+    events: EventsSnapshot,
+    states: StateSnapshot,
+}
+
+/// The components and resources every game snapshots. Gameplay code adds its own through
+/// [`GameState::snapshots_mut`].
+pub fn default_registry() -> SnapshotRegistry {
+    let mut registry = SnapshotRegistry::new();
+    registry
+        .register_component::<Transform>()
+        .register_component::<GlobalTransform>()
+        .register_component::<Parent>()
+        .register_component::<Children>()
+        .register_component::<Name>()
+        .register_component::<Health>()
+        .register_component::<TileId>()
+        .register_component::<Actor>()
+        .register_component::<Player>()
+        .register_component::<Timer>()
+        .register_component::<Cooldown>()
+        .register_component::<Tint>()
+        .register_component::<Tweens>()
+        .register_component::<Script>()
+        .register_resource::<TileMap>()
+        .register_resource::<ChunkMap>()
+        .register_resource::<Rng>()
+        .register_resource::<Clock>()
+        .register_resource::<Turns>()
+        .register_resource::<CameraRig>()
+        .register_resource::<DelayedActions>()
+        .register_event::<UserEvent>()
+        .register_event::<EntityDied>()
+        .register_event::<TileChanged>()
+        .register_event::<LevelEntered>()
+        .register_event::<ActorActed>()
+        .register_event::<TweenFinished>()
+        .register_event::<ScriptError>();
+    registry
+}
+
+impl GameState {
+    pub fn snapshots_mut(&mut self) -> &mut SnapshotRegistry {
+        &mut self.snapshots
+    }
+
+    pub fn snapshot(&self) -> Result<GameSnapshot> {
+        Ok(GameSnapshot {
+            world: self.snapshots.snapshot(&self.world)?,
+            events: self.snapshots.snapshot_events(&self.events),
+            states: self.states.snapshot(),
+        })
+    }
+
+    /// Rolls the simulation back to `snapshot`. No state hooks run.
+    pub fn restore(&mut self, snapshot: &GameSnapshot) -> Result<()> {
+        self.snapshots.restore(&mut self.world, &snapshot.world)?;
+        self.snapshots
+            .restore_events(&mut self.events, &snapshot.events);
+        self.states.restore(&snapshot.states);
+        Ok(())
+    }
+
+    /// Whether two snapshots hold exactly the same simulation state.
+    pub fn snapshots_match(&self, a: &GameSnapshot, b: &GameSnapshot) -> bool {
+        a.states == b.states
+            && self.snapshots.matches(&a.world, &b.world)
+            && self.snapshots.events_match(&a.events, &b.events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::ecs::{Entity, EventReader};
+    use crate::game::schedule::{Schedule, ScheduleBuilder, Stage};
+    use crate::game::state::{self, AppState, Transition};
+    use crate::game::timer::DelayedAction;
+
+    /// Stand-in for player input, set before every tick.
+    #[derive(Clone, Copy)]
+    struct Steer(f32, f32);
+
+    /// Sum over the tile changes seen, to notice events read twice or not at all.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Noticed(u32);
+
+    fn wander(state: &mut GameState) -> Result<()> {
+        let Steer(dx, dy) = *state.world().resource::<Steer>().unwrap();
+        let (world, commands) = state.world_and_commands();
+        let mut rng = world.resource::<Rng>().unwrap().clone();
+
+        for (entity, transform, health) in &mut world.query::<(Entity, &Transform, &Health)>()? {
+            if health.is_dead() {
+                commands.despawn(entity);
+                continue;
+            }
+            let step = Transform::from_xy(
+                transform.translation.x + dx + rng.next_f32() - 0.5,
+                transform.translation.y + dy + rng.next_f32() - 0.5,
+            );
+            let hit = Health {
+                current: health.current.saturating_sub(rng.below(3)),
+                ..*health
+            };
+            commands.entity(entity).insert(step).insert(hit);
+        }
+        if rng.chance(0.3) {
+            let x = rng.below(10) as f32;
+            commands
+                .spawn()
+                .insert(Name("Goblin".into()))
+                .insert(Transform::from_xy(x, 0.0))
+                .insert(Health::new(4));
+        }
+        let (x, y) = (rng.range(1..9), rng.range(1..9));
+
+        *state.world_mut().resource_mut::<Rng>().unwrap() = rng;
+        // Always changes the tile, so every tick sends an event.
+        let ty = match state.map().get(x, y) {
+            Some(TileId::WALL) => TileId::FLOOR,
+            _ => TileId::WALL,
+        };
+        state.set_tile(x, y, ty)
+    }
+
+    fn schedule() -> Result<Schedule<GameState>> {
+        let mut builder = ScheduleBuilder::new();
+        builder.on_stage_end(|state: &mut GameState, _| {
+            state.apply_commands();
+            Ok(())
+        });
+        builder.add_system(Stage::PreInput, "advance_clock", |state| {
+            state.world_mut().resource_mut::<Clock>().unwrap().advance();
+            Ok(())
+        });
+        builder
+            .add_system(
+                Stage::PreInput,
+                "delayed_actions",
+                crate::game::timer::run_delayed_actions,
+            )
+            .after("advance_clock");
+        // Reads the changes of the previous tick, as it runs before they are made.
+        let mut reader = EventReader::<TileChanged>::new();
+        builder
+            .add_system(Stage::Simulate, "notice_changes", move |state| {
+                let sum: u32 = state
+                    .events()
+                    .read(&mut reader)
+                    .map(|change| change.position.0 * 10 + change.position.1)
+                    .sum();
+                state.world_mut().resource_mut::<Noticed>().unwrap().0 += sum;
+                Ok(())
+            })
+            .before("wander");
+        builder.add_system(Stage::Simulate, "wander", wander);
+        builder.add_system(Stage::PostSimulate, "apply_transitions", |state| {
+            state::apply_transitions(state);
+            Ok(())
+        });
+        builder.add_system(Stage::RenderExtract, "propagate", |state| {
+            crate::game::ecs::propagate_transforms(state.world_mut());
+            Ok(())
+        });
+        builder.build()
+    }
+
+    fn step(game: &mut GameState, schedule: &mut Schedule<GameState>, input: (f32, f32)) {
+        game.world_mut().insert_resource(Steer(input.0, input.1));
+        schedule.run(game).unwrap();
+        game.finish_tick();
+    }
+
+    #[test]
+    fn test_rollback_and_resimulate_is_identical() -> Result<()> {
+        let mut game = GameState::new()?;
+        game.world_mut().insert_resource(Rng::new(7));
+        game.world_mut().insert_resource(Noticed(0));
+        game.snapshots_mut().register_resource::<Noticed>();
+        let hero = game.world_mut().spawn();
+        game.world_mut()
+            .insert(hero, Transform::from_xy(5.0, 5.0))?;
+        game.world_mut().insert(hero, Health::new(100))?;
+        game.delayed_handlers_mut()
+            .register("heal", |game, entity| {
+                let health = game.world_mut().get_mut::<Health>(entity.unwrap()).unwrap();
+                health.current = health.max;
+                Ok(())
+            });
+
+        let mut schedule = schedule()?;
+        let inputs: Vec<(f32, f32)> = (0..40)
+            .map(|i| ((i % 3) as f32 - 1.0, (i % 5) as f32 * 0.25))
+            .collect();
+
+        for input in &inputs[..10] {
+            step(&mut game, &mut schedule, *input);
+        }
+        game.states_mut()
+            .request(Transition::Push(AppState::Paused));
+        game.after(12, DelayedAction::on("heal", hero))?;
+        // The last tick's change is still waiting for `notice_changes`.
+        assert_eq!(1, game.events().queue::<TileChanged>().unwrap().len());
+        let checkpoint = game.snapshot()?;
+
+        for input in &inputs[10..] {
+            step(&mut game, &mut schedule, *input);
+        }
+        let expected = game.snapshot()?;
+        assert!(!game.snapshots_match(&checkpoint, &expected));
+        assert_eq!(0, game.pending_actions());
+
+        game.restore(&checkpoint)?;
+        assert!(game.snapshots_match(&checkpoint, &game.snapshot()?));
+        assert_eq!(1, game.pending_actions());
+        for input in &inputs[10..] {
+            step(&mut game, &mut schedule, *input);
+        }
+
+        assert!(game.snapshots_match(&expected, &game.snapshot()?));
+        assert_eq!(Some(AppState::Paused), game.app_state());
+        Ok(())
+    }
+}
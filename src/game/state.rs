@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use winit::keyboard::{KeyCode, NamedKey};
+
+use super::GameState;
+
+/// High level states of the game. They form a stack, so menus can be pushed over gameplay and
+/// popped again without losing it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AppState {
+    MainMenu,
+    Playing,
+    Paused,
+    Inventory,
+    GameOver,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Transition {
+    /// Pauses the current state and enters a new one on top of it.
+    Push(AppState),
+    /// Exits the current state and resumes the one below. The root state is never popped.
+    Pop,
+    /// Exits the current state and enters a new one in its place.
+    Switch(AppState),
+    /// Exits every state and enters a new root.
+    Reset(AppState),
+    /// Exits every state and stops the game.
+    Quit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Hook {
+    Enter,
+    Exit,
+    /// Another state was pushed on top.
+    Pause,
+    /// The state on top was popped.
+    Resume,
+}
+
+pub type HookFn = Box<dyn FnMut(&mut GameState)>;
+
+/// Stack of [`AppState`]s. Transitions are only requested during a tick and applied together by
+/// [`apply_transitions`], so a key press can't trigger the handlers of two states in one tick.
+pub struct StateMachine {
+    stack: Vec<AppState>,
+    pending: Vec<Transition>,
+    hooks: HashMap<(AppState, Hook), Vec<HookFn>>,
+    quit: bool,
+}
+
+impl StateMachine {
+    /// Starts empty, with `initial` entered on the first [`apply_transitions`] so its enter hooks
+    /// run as well.
+    pub fn new(initial: AppState) -> Self {
+        Self {
+            stack: Vec::new(),
+            pending: vec![Transition::Reset(initial)],
+            hooks: HashMap::new(),
+            quit: false,
+        }
+    }
+
+    pub fn current(&self) -> Option<AppState> {
+        self.stack.last().copied()
+    }
+
+    pub fn stack(&self) -> &[AppState] {
+        &self.stack
+    }
+
+    pub fn request(&mut self, transition: Transition) {
+        self.pending.push(transition);
+    }
+
+    pub fn should_quit(&self) -> bool {
+        self.quit
+    }
+
+    pub fn add_hook<F>(&mut self, state: AppState, hook: Hook, f: F)
+    where
+        F: FnMut(&mut GameState) + 'static,
+    {
+        self.hooks
+            .entry((state, hook))
+            .or_default()
+            .push(Box::new(f));
+    }
+
+    pub fn on_enter<F: FnMut(&mut GameState) + 'static>(&mut self, state: AppState, f: F) {
+        self.add_hook(state, Hook::Enter, f)
+    }
+
+    pub fn on_exit<F: FnMut(&mut GameState) + 'static>(&mut self, state: AppState, f: F) {
+        self.add_hook(state, Hook::Exit, f)
+    }
+
+    pub fn on_pause<F: FnMut(&mut GameState) + 'static>(&mut self, state: AppState, f: F) {
+        self.add_hook(state, Hook::Pause, f)
+    }
+
+    pub fn on_resume<F: FnMut(&mut GameState) + 'static>(&mut self, state: AppState, f: F) {
+        self.add_hook(state, Hook::Resume, f)
+    }
+}
+
+/// The stack and pending transitions of a [`StateMachine`], without its hooks.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    stack: Vec<AppState>,
+    pending: Vec<Transition>,
+    quit: bool,
+}
+
+impl StateMachine {
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            stack: self.stack.clone(),
+            pending: self.pending.clone(),
+            quit: self.quit,
+        }
+    }
+
+    /// Restores the stack without running any hooks.
+    pub fn restore(&mut self, snapshot: &StateSnapshot) {
+        self.stack = snapshot.stack.clone();
+        self.pending = snapshot.pending.clone();
+        self.quit = snapshot.quit;
+    }
+}
+
+type Hooks = HashMap<(AppState, Hook), Vec<HookFn>>;
+
+/// Applies the requested transitions in order and runs their hooks. Transitions requested by the
+/// hooks themselves are applied on the next call.
+pub fn apply_transitions(game: &mut GameState) {
+    let pending = std::mem::take(&mut game.states.pending);
+    if pending.is_empty() {
+        return;
+    }
+
+    let mut hooks = std::mem::take(&mut game.states.hooks);
+    for transition in pending {
+        apply(game, &mut hooks, transition);
+    }
+
+    // Keep hooks that were registered while running hooks.
+    for (key, mut added) in std::mem::replace(&mut game.states.hooks, hooks) {
+        game.states.hooks.entry(key).or_default().append(&mut added);
+    }
+
+    log::debug!("State stack: {:?}", game.states.stack);
+}
+
+fn apply(game: &mut GameState, hooks: &mut Hooks, transition: Transition) {
+    match transition {
+        Transition::Push(state) => {
+            if let Some(top) = game.states.current() {
+                run_hooks(game, hooks, top, Hook::Pause);
+            }
+            enter(game, hooks, state);
+        }
+        Transition::Pop => {
+            if game.states.stack.len() <= 1 {
+                return;
+            }
+            exit_top(game, hooks);
+            if let Some(top) = game.states.current() {
+                run_hooks(game, hooks, top, Hook::Resume);
+            }
+        }
+        Transition::Switch(state) => {
+            exit_top(game, hooks);
+            enter(game, hooks, state);
+        }
+        Transition::Reset(state) => {
+            while exit_top(game, hooks) {}
+            enter(game, hooks, state);
+        }
+        Transition::Quit => {
+            while exit_top(game, hooks) {}
+            game.states.quit = true;
+        }
+    }
+}
+
+fn enter(game: &mut GameState, hooks: &mut Hooks, state: AppState) {
+    game.states.stack.push(state);
+    run_hooks(game, hooks, state, Hook::Enter);
+}
+
+fn exit_top(game: &mut GameState, hooks: &mut Hooks) -> bool {
+    match game.states.stack.pop() {
+        Some(top) => {
+            run_hooks(game, hooks, top, Hook::Exit);
+            true
+        }
+        None => false,
+    }
+}
+
+fn run_hooks(game: &mut GameState, hooks: &mut Hooks, state: AppState, hook: Hook) {
+    for f in hooks.get_mut(&(state, hook)).into_iter().flatten() {
+        f(game);
+    }
+}
+
+/// Maps key presses to transitions, depending on the current state.
+pub fn handle_input(game: &mut GameState) {
+    let Some(current) = game.states.current() else {
+        return;
+    };
+
+    let input = &game.input;
+    let escape = input.is_logical_key_pressed(NamedKey::Escape);
+    let enter = input.is_logical_key_pressed(NamedKey::Enter);
+    let inventory = input.is_physical_key_pressed(KeyCode::KeyI);
+    let quit = input.is_physical_key_pressed(KeyCode::KeyQ);
+
+    let transition = match current {
+        AppState::MainMenu if enter => Transition::Switch(AppState::Playing),
+        AppState::MainMenu if escape => Transition::Quit,
+        AppState::Playing if escape => Transition::Push(AppState::Paused),
+        AppState::Playing if inventory => Transition::Push(AppState::Inventory),
+        AppState::Paused if escape => Transition::Pop,
+        AppState::Paused if quit => Transition::Reset(AppState::MainMenu),
+        AppState::Inventory if escape || inventory => Transition::Pop,
+        AppState::GameOver if enter => Transition::Reset(AppState::MainMenu),
+        AppState::GameOver if escape => Transition::Quit,
+        _ => return,
+    };
+
+    game.states.request(transition);
+}
+
+/// Run condition for systems that only belong to one state.
+pub fn in_state(state: AppState) -> impl Fn(&GameState) -> bool + 'static {
+    move |game| game.states.current() == Some(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    fn record(game: &mut GameState, entry: &str) {
+        game.world_mut()
+            .resource_mut::<Vec<String>>()
+            .unwrap()
+            .push(entry.to_string());
+    }
+
+    fn recording_game() -> Result<GameState> {
+        let mut game = GameState::new()?;
+        game.world_mut().insert_resource(Vec::<String>::new());
+
+        for state in [AppState::MainMenu, AppState::Playing, AppState::Paused] {
+            let states = game.states_mut();
+            states.on_enter(state, move |g| record(g, &format!("enter {:?}", state)));
+            states.on_exit(state, move |g| record(g, &format!("exit {:?}", state)));
+            states.on_pause(state, move |g| record(g, &format!("pause {:?}", state)));
+            states.on_resume(state, move |g| record(g, &format!("resume {:?}", state)));
+        }
+
+        Ok(game)
+    }
+
+    fn take_log(game: &mut GameState) -> Vec<String> {
+        std::mem::take(game.world_mut().resource_mut::<Vec<String>>().unwrap())
+    }
+
+    #[test]
+    fn test_transitions_run_hooks_in_order() -> Result<()> {
+        let mut game = recording_game()?;
+        apply_transitions(&mut game);
+        assert_eq!(vec!["enter MainMenu"], take_log(&mut game));
+
+        game.states_mut()
+            .request(Transition::Switch(AppState::Playing));
+        game.states_mut()
+            .request(Transition::Push(AppState::Paused));
+        apply_transitions(&mut game);
+        assert_eq!(
+            vec![
+                "exit MainMenu",
+                "enter Playing",
+                "pause Playing",
+                "enter Paused"
+            ],
+            take_log(&mut game)
+        );
+        assert_eq!(
+            &[AppState::Playing, AppState::Paused],
+            game.states().stack()
+        );
+        assert!(!game.exit());
+
+        game.states_mut().request(Transition::Pop);
+        apply_transitions(&mut game);
+        assert_eq!(vec!["exit Paused", "resume Playing"], take_log(&mut game));
+
+        // The root state stays.
+        game.states_mut().request(Transition::Pop);
+        apply_transitions(&mut game);
+        assert_eq!(Some(AppState::Playing), game.states().current());
+
+        game.states_mut().request(Transition::Quit);
+        apply_transitions(&mut game);
+        assert_eq!(vec!["exit Playing"], take_log(&mut game));
+        assert!(game.exit());
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_state() -> Result<()> {
+        let mut game = GameState::new()?;
+        let playing = in_state(AppState::Playing);
+        apply_transitions(&mut game);
+        assert!(!playing(&game));
+
+        game.states_mut()
+            .request(Transition::Switch(AppState::Playing));
+        apply_transitions(&mut game);
+        assert!(playing(&game));
+
+        game.states_mut()
+            .request(Transition::Push(AppState::Inventory));
+        apply_transitions(&mut game);
+        assert!(!playing(&game));
+        Ok(())
+    }
+}
@@ -0,0 +1,1026 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use base64::Engine as _;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::tiles::{TileId, TileRegistry};
+use super::{TileMap, GROUND_LAYER};
+
+/// The top bits of a gid say how the tile is flipped. The renderer can't flip, so they are
+/// dropped.
+const FLIP_FLAGS: u32 = 0xf000_0000;
+
+/// Tileset tiles name the registry tile they stand for with this property, or with their class.
+const TILE_PROPERTY: &str = "tile";
+
+/// A custom property set in Tiled. Colors and files are kept as strings, object references as
+/// the object id.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Property {
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+pub type Properties = BTreeMap<String, Property>;
+
+/// An object of an object layer, e.g. a spawn point or a trigger. Positions and sizes are in
+/// tiles with y pointing up like in [`TileMap`], `(x, y)` being the bottom left corner.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpawnDesc {
+    pub id: u32,
+    pub name: String,
+    /// The class (type in older Tiled versions) the designer gave the object.
+    pub class: String,
+    /// Name of the object layer.
+    pub layer: String,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub properties: Properties,
+}
+
+impl SpawnDesc {
+    /// The tile under the centre of the object.
+    pub fn tile(&self) -> (i64, i64) {
+        (
+            (self.x + self.width / 2.0).floor() as i64,
+            (self.y + self.height / 2.0).floor() as i64,
+        )
+    }
+}
+
+/// A layer as it was in Tiled.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerDesc {
+    pub name: String,
+    /// The [`TileMap`] layer it became, `None` for object layers. The first tile layer becomes
+    /// the [`GROUND_LAYER`] whatever it was called.
+    pub map_layer: Option<String>,
+    pub properties: Properties,
+}
+
+/// A map made in the Tiled editor. The first tile layer becomes the ground, the others are
+/// added on top of it in order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TiledMap {
+    pub map: TileMap,
+    /// Tile and object layers in order.
+    pub layers: Vec<LayerDesc>,
+    pub spawns: Vec<SpawnDesc>,
+    pub properties: Properties,
+}
+
+impl TiledMap {
+    /// Loads a `.tmx` or `.tmj` file. External tilesets are looked up next to it.
+    pub fn load(path: &Path, tiles: &TileRegistry) -> Result<Self> {
+        let source =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let result = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("tmx") => Self::from_tmx(&source, dir, tiles),
+            Some("tmj" | "json") => Self::from_tmj(&source, dir, tiles),
+            _ => bail!("{} is not a .tmx or .tmj map", path.display()),
+        };
+        result.with_context(|| format!("loading {}", path.display()))
+    }
+
+    pub fn from_tmx(source: &str, dir: &Path, tiles: &TileRegistry) -> Result<Self> {
+        tmx::parse_map(source, dir)?.build(tiles)
+    }
+
+    pub fn from_tmj(source: &str, dir: &Path, tiles: &TileRegistry) -> Result<Self> {
+        tmj::parse_map(source, dir)?.build(tiles)
+    }
+}
+
+/// What both formats are read into before tiles are matched up with the registry.
+struct RawMap {
+    width: usize,
+    height: usize,
+    tile_width: f32,
+    tile_height: f32,
+    tilesets: Vec<RawTileset>,
+    layers: Vec<RawLayer>,
+    properties: Properties,
+}
+
+struct RawTileset {
+    name: String,
+    first_gid: u32,
+    /// Registry tile names by local tile id.
+    tiles: HashMap<u32, String>,
+}
+
+struct RawLayer {
+    name: String,
+    properties: Properties,
+    kind: RawLayerKind,
+}
+
+enum RawLayerKind {
+    Tiles { visible: bool, gids: Vec<u32> },
+    Objects { objects: Vec<RawObject> },
+}
+
+/// In pixels, y pointing down.
+struct RawObject {
+    id: u32,
+    name: String,
+    class: String,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    is_tile: bool,
+    properties: Properties,
+}
+
+fn check_supported(orientation: &str, infinite: bool) -> Result<()> {
+    if orientation != "orthogonal" {
+        bail!(
+            "{} maps are not supported, only orthogonal ones",
+            orientation
+        )
+    }
+    if infinite {
+        bail!("infinite maps are not supported, turn off `Infinite` in the map properties")
+    }
+    Ok(())
+}
+
+fn tileset_tile_name(class: &str, properties: &Properties) -> Option<String> {
+    match properties.get(TILE_PROPERTY) {
+        Some(Property::String(name)) => Some(name.clone()),
+        _ if !class.is_empty() => Some(class.to_string()),
+        _ => None,
+    }
+}
+
+impl RawMap {
+    fn build(self, tiles: &TileRegistry) -> Result<TiledMap> {
+        let (width, height) = (self.width, self.height);
+        let Some(len) = width.checked_mul(height) else {
+            bail!("a {}x{} map is too large", width, height)
+        };
+        let mut map: Option<TileMap> = None;
+        let mut layers = Vec::new();
+        let mut spawns = Vec::new();
+        let mut resolved = HashMap::new();
+
+        for layer in &self.layers {
+            let name = &layer.name;
+            let mut map_layer = None;
+            match &layer.kind {
+                RawLayerKind::Tiles { visible, gids } => {
+                    if gids.len() != len {
+                        bail!(
+                            "layer `{}` has {} tiles instead of {}",
+                            name,
+                            gids.len(),
+                            len
+                        )
+                    }
+                    let layer_name = match &mut map {
+                        None => {
+                            map = Some(TileMap::filled(width, height, TileId::FLOOR)?);
+                            GROUND_LAYER
+                        }
+                        Some(_) if name == GROUND_LAYER => bail!(
+                            "layer `{}` is not the first tile layer, but the first one always \
+                             becomes the ground, rename it",
+                            name
+                        ),
+                        Some(map) if map.layer_index(name).is_some() => {
+                            bail!("there are two tile layers named `{}`", name)
+                        }
+                        Some(map) => {
+                            map.add_layer(name)?;
+                            name.as_str()
+                        }
+                    };
+                    map_layer = Some(layer_name.to_string());
+                    let map = map.as_mut().unwrap();
+                    for (index, gid) in gids.iter().enumerate() {
+                        let gid = gid & !FLIP_FLAGS;
+                        let ty = match resolved.get(&gid) {
+                            Some(ty) => *ty,
+                            None => {
+                                let ty = self.resolve(gid, tiles)?;
+                                resolved.insert(gid, ty);
+                                ty
+                            }
+                        };
+                        // Tiled counts rows from the top.
+                        let (x, y) = (index % width, height - 1 - index / width);
+                        map.set_in(layer_name, x, y, ty)?;
+                    }
+                    map.set_visible(layer_name, *visible)?;
+                }
+                RawLayerKind::Objects { objects } => {
+                    spawns.extend(objects.iter().map(|object| self.spawn(object, name)));
+                }
+            }
+            layers.push(LayerDesc {
+                name: name.clone(),
+                map_layer,
+                properties: layer.properties.clone(),
+            });
+        }
+
+        Ok(TiledMap {
+            map: map.context("the map has no tile layers")?,
+            layers,
+            spawns,
+            properties: self.properties,
+        })
+    }
+
+    fn resolve(&self, gid: u32, tiles: &TileRegistry) -> Result<Option<TileId>> {
+        if gid == 0 {
+            return Ok(None);
+        }
+
+        let tileset = self
+            .tilesets
+            .iter()
+            .filter(|tileset| tileset.first_gid <= gid)
+            .max_by_key(|tileset| tileset.first_gid)
+            .with_context(|| format!("tile {} is not in any tileset", gid))?;
+        let local = gid - tileset.first_gid;
+        let name = tileset.tiles.get(&local).with_context(|| {
+            format!(
+                "tile {} of tileset `{}` has neither a `{}` property nor a class",
+                local, tileset.name, TILE_PROPERTY
+            )
+        })?;
+        let id = tiles.id(name).with_context(|| {
+            format!(
+                "tile {} of tileset `{}` is `{}`, which is not in the tile registry",
+                local, tileset.name, name
+            )
+        })?;
+        Ok(Some(id))
+    }
+
+    fn spawn(&self, object: &RawObject, layer: &str) -> SpawnDesc {
+        // Tile objects hang from their bottom left corner, everything else from the top left.
+        let bottom = match object.is_tile {
+            true => object.y,
+            false => object.y + object.height,
+        };
+        SpawnDesc {
+            id: object.id,
+            name: object.name.clone(),
+            class: object.class.clone(),
+            layer: layer.to_string(),
+            x: object.x / self.tile_width,
+            y: self.height as f32 - bottom / self.tile_height,
+            width: object.width / self.tile_width,
+            height: object.height / self.tile_height,
+            properties: object.properties.clone(),
+        }
+    }
+}
+
+fn decode_csv(text: &str) -> Result<Vec<u32>> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|gid| !gid.is_empty())
+        .map(|gid| {
+            gid.parse()
+                .with_context(|| format!("invalid tile `{}`", gid))
+        })
+        .collect()
+}
+
+fn decode_base64(text: &str, compression: Option<&str>) -> Result<Vec<u32>> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(text.trim())
+        .context("invalid base64 tile data")?;
+    let bytes = match compression {
+        None | Some("") => bytes,
+        Some("zlib") => {
+            let mut out = Vec::new();
+            flate2::read::ZlibDecoder::new(&bytes[..]).read_to_end(&mut out)?;
+            out
+        }
+        Some("gzip") => {
+            let mut out = Vec::new();
+            flate2::read::GzDecoder::new(&bytes[..]).read_to_end(&mut out)?;
+            out
+        }
+        Some(other) => bail!(
+            "{} compressed layers are not supported, use zlib, gzip or none",
+            other
+        ),
+    };
+    if bytes.len() % 4 != 0 {
+        bail!("tile data is cut off")
+    }
+
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|gid| u32::from_le_bytes([gid[0], gid[1], gid[2], gid[3]]))
+        .collect())
+}
+
+fn decode_data(text: &str, encoding: Option<&str>, compression: Option<&str>) -> Result<Vec<u32>> {
+    match encoding {
+        Some("csv") => decode_csv(text),
+        Some("base64") => decode_base64(text, compression),
+        Some(other) => bail!("{} encoded layers are not supported", other),
+        None => bail!("layer data without encoding"),
+    }
+}
+
+/// The XML format.
+mod tmx {
+    use roxmltree::{Document, Node};
+
+    use super::*;
+
+    pub(super) fn parse_map(source: &str, dir: &Path) -> Result<RawMap> {
+        let doc = Document::parse(source).context("invalid XML")?;
+        let root = doc.root_element();
+        if root.tag_name().name() != "map" {
+            bail!("expected <map>, found <{}>", root.tag_name().name())
+        }
+        check_supported(
+            root.attribute("orientation").unwrap_or("orthogonal"),
+            root.attribute("infinite") == Some("1"),
+        )?;
+
+        let mut map = RawMap {
+            width: parse_attr(root, "width")?,
+            height: parse_attr(root, "height")?,
+            tile_width: parse_attr(root, "tilewidth")?,
+            tile_height: parse_attr(root, "tileheight")?,
+            tilesets: Vec::new(),
+            layers: Vec::new(),
+            properties: Properties::new(),
+        };
+        for node in root.children().filter(Node::is_element) {
+            let name = node.attribute("name").unwrap_or_default();
+            match node.tag_name().name() {
+                "properties" => map.properties = properties(node)?,
+                "tileset" => map.tilesets.push(tileset(node, dir)?),
+                "layer" => map
+                    .layers
+                    .push(tile_layer(node).with_context(|| format!("in layer `{}`", name))?),
+                "objectgroup" => map
+                    .layers
+                    .push(object_layer(node).with_context(|| format!("in layer `{}`", name))?),
+                "imagelayer" => bail!("image layers like `{}` are not supported", name),
+                "group" => bail!("group layers like `{}` are not supported", name),
+                _ => {}
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// Reads an external `.tsx` tileset.
+    pub(super) fn parse_tileset(source: &str, first_gid: u32) -> Result<RawTileset> {
+        let doc = Document::parse(source).context("invalid XML")?;
+        let mut tileset = tileset_tiles(doc.root_element())?;
+        tileset.first_gid = first_gid;
+        Ok(tileset)
+    }
+
+    fn parse_attr<T>(node: Node, name: &str) -> Result<T>
+    where
+        T: std::str::FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        let value = node
+            .attribute(name)
+            .with_context(|| format!("<{}> is missing `{}`", node.tag_name().name(), name))?;
+        value
+            .parse()
+            .with_context(|| format!("invalid `{}` of <{}>", name, node.tag_name().name()))
+    }
+
+    fn parse_attr_or<T>(node: Node, name: &str, default: T) -> Result<T>
+    where
+        T: std::str::FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        match node.attribute(name) {
+            Some(_) => parse_attr(node, name),
+            None => Ok(default),
+        }
+    }
+
+    fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+        node.children().find(|child| child.has_tag_name(name))
+    }
+
+    fn properties(node: Node) -> Result<Properties> {
+        let mut properties = Properties::new();
+        for property in node.children().filter(|n| n.has_tag_name("property")) {
+            let name: String = parse_attr(property, "name")?;
+            // Multi-line strings are stored as text instead of an attribute.
+            let value = property
+                .attribute("value")
+                .or(property.text())
+                .unwrap_or_default();
+            let value = match property.attribute("type").unwrap_or("string") {
+                "string" | "color" | "file" => Property::String(value.to_string()),
+                "int" | "object" => Property::Int(parse_attr(property, "value")?),
+                "float" => Property::Float(parse_attr(property, "value")?),
+                "bool" => Property::Bool(value == "true"),
+                other => bail!("property `{}` has unsupported type `{}`", name, other),
+            };
+            properties.insert(name, value);
+        }
+        Ok(properties)
+    }
+
+    fn tileset(node: Node, dir: &Path) -> Result<RawTileset> {
+        let first_gid = parse_attr(node, "firstgid")?;
+        let Some(source) = node.attribute("source") else {
+            let mut tileset = tileset_tiles(node)?;
+            tileset.first_gid = first_gid;
+            return Ok(tileset);
+        };
+
+        load_tileset(&dir.join(source), first_gid)
+    }
+
+    fn tileset_tiles(node: Node) -> Result<RawTileset> {
+        let name = node.attribute("name").unwrap_or_default().to_string();
+        let mut tiles = HashMap::new();
+        for tile in node.children().filter(|n| n.has_tag_name("tile")) {
+            let id = parse_attr(tile, "id")?;
+            let class = tile
+                .attribute("class")
+                .or(tile.attribute("type"))
+                .unwrap_or_default();
+            let properties = match child(tile, "properties") {
+                Some(node) => properties(node)?,
+                None => Properties::new(),
+            };
+            if let Some(tile_name) = tileset_tile_name(class, &properties) {
+                tiles.insert(id, tile_name);
+            }
+        }
+
+        Ok(RawTileset {
+            name,
+            first_gid: 0,
+            tiles,
+        })
+    }
+
+    fn tile_layer(node: Node) -> Result<RawLayer> {
+        let data = child(node, "data").context("layer has no data")?;
+        let gids = match data.attribute("encoding") {
+            // The oldest format, a <tile> element per tile.
+            None => data
+                .children()
+                .filter(|n| n.has_tag_name("tile"))
+                .map(|tile| parse_attr_or(tile, "gid", 0))
+                .collect::<Result<_>>()?,
+            encoding => decode_data(
+                data.text().unwrap_or_default(),
+                encoding,
+                data.attribute("compression"),
+            )?,
+        };
+
+        Ok(RawLayer {
+            name: node.attribute("name").unwrap_or_default().to_string(),
+            properties: layer_properties(node)?,
+            kind: RawLayerKind::Tiles {
+                visible: node.attribute("visible") != Some("0"),
+                gids,
+            },
+        })
+    }
+
+    fn object_layer(node: Node) -> Result<RawLayer> {
+        let mut objects = Vec::new();
+        for object in node.children().filter(|n| n.has_tag_name("object")) {
+            let id = parse_attr(object, "id")?;
+            for shape in ["polygon", "polyline", "text"] {
+                if child(object, shape).is_some() {
+                    bail!("object {} is a {}, which is not supported", id, shape)
+                }
+            }
+            let properties = match child(object, "properties") {
+                Some(node) => properties(node)?,
+                None => Properties::new(),
+            };
+            objects.push(RawObject {
+                id,
+                name: object.attribute("name").unwrap_or_default().to_string(),
+                class: object
+                    .attribute("class")
+                    .or(object.attribute("type"))
+                    .unwrap_or_default()
+                    .to_string(),
+                x: parse_attr_or(object, "x", 0.0)?,
+                y: parse_attr_or(object, "y", 0.0)?,
+                width: parse_attr_or(object, "width", 0.0)?,
+                height: parse_attr_or(object, "height", 0.0)?,
+                is_tile: object.attribute("gid").is_some(),
+                properties,
+            });
+        }
+
+        Ok(RawLayer {
+            name: node.attribute("name").unwrap_or_default().to_string(),
+            properties: layer_properties(node)?,
+            kind: RawLayerKind::Objects { objects },
+        })
+    }
+
+    fn layer_properties(node: Node) -> Result<Properties> {
+        match child(node, "properties") {
+            Some(node) => properties(node),
+            None => Ok(Properties::new()),
+        }
+    }
+}
+
+/// The JSON format.
+mod tmj {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct JsonMap {
+        #[serde(default = "orthogonal")]
+        orientation: String,
+        #[serde(default)]
+        infinite: bool,
+    }
+
+    fn orthogonal() -> String {
+        "orthogonal".to_string()
+    }
+
+    #[derive(Deserialize)]
+    struct JsonContent {
+        width: usize,
+        height: usize,
+        tilewidth: f32,
+        tileheight: f32,
+        #[serde(default)]
+        tilesets: Vec<JsonTilesetRef>,
+        #[serde(default)]
+        layers: Vec<JsonLayer>,
+        #[serde(default)]
+        properties: Vec<JsonProperty>,
+    }
+
+    #[derive(Deserialize)]
+    struct JsonTilesetRef {
+        firstgid: u32,
+        source: Option<String>,
+        #[serde(flatten)]
+        tileset: JsonTileset,
+    }
+
+    #[derive(Deserialize)]
+    struct JsonTileset {
+        #[serde(default)]
+        name: String,
+        #[serde(default)]
+        tiles: Vec<JsonTile>,
+    }
+
+    #[derive(Deserialize)]
+    struct JsonTile {
+        id: u32,
+        #[serde(default, alias = "class")]
+        r#type: String,
+        #[serde(default)]
+        properties: Vec<JsonProperty>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(tag = "type")]
+    enum JsonLayer {
+        #[serde(rename = "tilelayer")]
+        Tiles {
+            name: String,
+            #[serde(default = "visible")]
+            visible: bool,
+            data: Value,
+            encoding: Option<String>,
+            compression: Option<String>,
+            #[serde(default)]
+            properties: Vec<JsonProperty>,
+        },
+        #[serde(rename = "objectgroup")]
+        Objects {
+            name: String,
+            objects: Vec<JsonObject>,
+            #[serde(default)]
+            properties: Vec<JsonProperty>,
+        },
+        #[serde(rename = "imagelayer")]
+        Image { name: String },
+        #[serde(rename = "group")]
+        Group { name: String },
+    }
+
+    fn visible() -> bool {
+        true
+    }
+
+    #[derive(Deserialize)]
+    struct JsonObject {
+        id: u32,
+        #[serde(default)]
+        name: String,
+        #[serde(default, alias = "class")]
+        r#type: String,
+        x: f32,
+        y: f32,
+        #[serde(default)]
+        width: f32,
+        #[serde(default)]
+        height: f32,
+        gid: Option<u32>,
+        polygon: Option<Value>,
+        polyline: Option<Value>,
+        text: Option<Value>,
+        #[serde(default)]
+        properties: Vec<JsonProperty>,
+    }
+
+    #[derive(Deserialize)]
+    struct JsonProperty {
+        name: String,
+        #[serde(default = "string")]
+        r#type: String,
+        value: Value,
+    }
+
+    fn string() -> String {
+        "string".to_string()
+    }
+
+    pub(super) fn parse_map(source: &str, dir: &Path) -> Result<RawMap> {
+        let value: Value = serde_json::from_str(source).context("invalid JSON")?;
+        // Infinite maps store their tiles differently, so check before reading them.
+        let header = JsonMap::deserialize(&value)?;
+        check_supported(&header.orientation, header.infinite)?;
+        let content = JsonContent::deserialize(value)?;
+
+        let mut tilesets = Vec::new();
+        for tileset in content.tilesets {
+            tilesets.push(match tileset.source {
+                Some(source) => load_tileset(&dir.join(source), tileset.firstgid)?,
+                None => raw_tileset(tileset.tileset, tileset.firstgid)?,
+            });
+        }
+
+        let mut layers = Vec::new();
+        for layer in content.layers {
+            layers.push(match layer {
+                JsonLayer::Tiles {
+                    name,
+                    visible,
+                    data,
+                    encoding,
+                    compression,
+                    properties: layer_properties,
+                } => {
+                    let gids = match &data {
+                        Value::String(text) => {
+                            decode_data(text, encoding.as_deref(), compression.as_deref())
+                        }
+                        data => Vec::<u32>::deserialize(data).map_err(Into::into),
+                    };
+                    RawLayer {
+                        properties: properties(layer_properties)
+                            .with_context(|| format!("in layer `{}`", name))?,
+                        kind: RawLayerKind::Tiles {
+                            gids: gids.with_context(|| format!("in layer `{}`", name))?,
+                            visible,
+                        },
+                        name,
+                    }
+                }
+                JsonLayer::Objects {
+                    name,
+                    objects,
+                    properties: layer_properties,
+                } => RawLayer {
+                    properties: properties(layer_properties)
+                        .with_context(|| format!("in layer `{}`", name))?,
+                    kind: RawLayerKind::Objects {
+                        objects: objects
+                            .into_iter()
+                            .map(raw_object)
+                            .collect::<Result<_>>()
+                            .with_context(|| format!("in layer `{}`", name))?,
+                    },
+                    name,
+                },
+                JsonLayer::Image { name } => {
+                    bail!("image layers like `{}` are not supported", name)
+                }
+                JsonLayer::Group { name } => {
+                    bail!("group layers like `{}` are not supported", name)
+                }
+            });
+        }
+
+        Ok(RawMap {
+            width: content.width,
+            height: content.height,
+            tile_width: content.tilewidth,
+            tile_height: content.tileheight,
+            tilesets,
+            layers,
+            properties: properties(content.properties)?,
+        })
+    }
+
+    /// Reads an external `.tsj` tileset.
+    pub(super) fn parse_tileset(source: &str, first_gid: u32) -> Result<RawTileset> {
+        let tileset: JsonTileset = serde_json::from_str(source).context("invalid JSON")?;
+        raw_tileset(tileset, first_gid)
+    }
+
+    fn raw_tileset(tileset: JsonTileset, first_gid: u32) -> Result<RawTileset> {
+        let mut tiles = HashMap::new();
+        for tile in tileset.tiles {
+            let properties = properties(tile.properties)?;
+            if let Some(name) = tileset_tile_name(&tile.r#type, &properties) {
+                tiles.insert(tile.id, name);
+            }
+        }
+
+        Ok(RawTileset {
+            name: tileset.name,
+            first_gid,
+            tiles,
+        })
+    }
+
+    fn raw_object(object: JsonObject) -> Result<RawObject> {
+        for (shape, value) in [
+            ("polygon", &object.polygon),
+            ("polyline", &object.polyline),
+            ("text", &object.text),
+        ] {
+            if value.is_some() {
+                bail!(
+                    "object {} is a {}, which is not supported",
+                    object.id,
+                    shape
+                )
+            }
+        }
+
+        Ok(RawObject {
+            id: object.id,
+            name: object.name,
+            class: object.r#type,
+            x: object.x,
+            y: object.y,
+            width: object.width,
+            height: object.height,
+            is_tile: object.gid.is_some(),
+            properties: properties(object.properties)?,
+        })
+    }
+
+    fn properties(list: Vec<JsonProperty>) -> Result<Properties> {
+        let mut properties = Properties::new();
+        for property in list {
+            let value = &property.value;
+            let value = match property.r#type.as_str() {
+                "string" | "color" | "file" => value.as_str().map(|s| Property::String(s.into())),
+                "int" | "object" => value.as_i64().map(Property::Int),
+                "float" => value.as_f64().map(Property::Float),
+                "bool" => value.as_bool().map(Property::Bool),
+                other => bail!(
+                    "property `{}` has unsupported type `{}`",
+                    property.name,
+                    other
+                ),
+            };
+            let value = value.with_context(|| {
+                format!(
+                    "property `{}` is not a valid {}",
+                    property.name, property.r#type
+                )
+            })?;
+            properties.insert(property.name, value);
+        }
+        Ok(properties)
+    }
+}
+
+fn load_tileset(path: &Path, first_gid: u32) -> Result<RawTileset> {
+    let source =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let tileset = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("tsx") => tmx::parse_tileset(&source, first_gid),
+        Some("tsj" | "json") => tmj::parse_tileset(&source, first_gid),
+        _ => bail!("{} is not a .tsx or .tsj tileset", path.display()),
+    };
+    tileset.with_context(|| format!("loading {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn registry() -> TileRegistry {
+        TileRegistry::from_ron(
+            r#"[
+                (name: "floor", sprite: "floor.png", walkable: true),
+                (name: "wall", sprite: "wall.png"),
+                (name: "rug", sprite: "rug.png", walkable: true),
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    /// 3x2 tiles of 16 pixels. Ground is walls on the top row and floor below, with a rug on
+    /// the bottom left in a hidden second layer.
+    fn tmx(extra: &str, decoration: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <map version="1.10" orientation="orthogonal" {extra} width="3" height="2"
+                 tilewidth="16" tileheight="16">
+              <properties>
+                <property name="music" value="cave.ogg"/>
+              </properties>
+              <tileset firstgid="1" name="dungeon" tilewidth="16" tileheight="16">
+                <tile id="0" type="floor"/>
+                <tile id="1">
+                  <properties><property name="tile" value="wall"/></properties>
+                </tile>
+                <tile id="2" class="rug"/>
+              </tileset>
+              <layer id="1" name="Ground" width="3" height="2">
+                <properties><property name="footsteps" value="stone"/></properties>
+                <data encoding="csv">2,2,2,1,1,1</data>
+              </layer>
+              <layer id="2" name="decoration" width="3" height="2" visible="0">
+                <data encoding="base64" compression="zlib">{decoration}</data>
+              </layer>
+              <objectgroup id="3" name="spawns">
+                <properties><property name="wave" type="int" value="2"/></properties>
+                <object id="1" name="boss" type="goblin" x="24" y="24">
+                  <properties>
+                    <property name="health" type="int" value="12"/>
+                    <property name="angry" type="bool" value="true"/>
+                  </properties>
+                  <point/>
+                </object>
+                <object id="2" class="trigger" x="0" y="0" width="48" height="16"/>
+              </objectgroup>
+            </map>"#
+        )
+    }
+
+    fn tmj(decoration: &str) -> String {
+        format!(
+            r#"{{
+              "orientation": "orthogonal", "infinite": false,
+              "width": 3, "height": 2, "tilewidth": 16, "tileheight": 16,
+              "properties": [{{ "name": "music", "type": "string", "value": "cave.ogg" }}],
+              "tilesets": [{{
+                "firstgid": 1, "name": "dungeon",
+                "tiles": [
+                  {{ "id": 0, "type": "floor" }},
+                  {{ "id": 1, "properties": [{{ "name": "tile", "type": "string", "value": "wall" }}] }},
+                  {{ "id": 2, "class": "rug" }}
+                ]
+              }}],
+              "layers": [
+                {{ "type": "tilelayer", "name": "Ground", "data": [2, 2, 2, 1, 1, 1],
+                   "properties": [{{ "name": "footsteps", "type": "string", "value": "stone" }}] }},
+                {{ "type": "tilelayer", "name": "decoration", "visible": false,
+                   "encoding": "base64", "compression": "zlib", "data": "{decoration}" }},
+                {{ "type": "objectgroup", "name": "spawns",
+                   "properties": [{{ "name": "wave", "type": "int", "value": 2 }}], "objects": [
+                  {{ "id": 1, "name": "boss", "type": "goblin", "x": 24, "y": 24, "point": true,
+                     "properties": [
+                       {{ "name": "health", "type": "int", "value": 12 }},
+                       {{ "name": "angry", "type": "bool", "value": true }}
+                     ] }},
+                  {{ "id": 2, "class": "trigger", "x": 0, "y": 0, "width": 48, "height": 16 }}
+                ] }}
+              ]
+            }}"#
+        )
+    }
+
+    fn encode(gids: &[u32]) -> Result<String> {
+        let bytes: Vec<u8> = gids.iter().flat_map(|gid| gid.to_le_bytes()).collect();
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(&bytes)?;
+        Ok(base64::engine::general_purpose::STANDARD.encode(encoder.finish()?))
+    }
+
+    #[test]
+    fn test_tmx_and_tmj_import_the_same_map() -> Result<()> {
+        let tiles = registry();
+        let decoration = encode(&[0, 0, 0, 3, 0, 0])?;
+        let from_tmx = TiledMap::from_tmx(&tmx("", &decoration), Path::new(""), &tiles)?;
+        let from_tmj = TiledMap::from_tmj(&tmj(&decoration), Path::new(""), &tiles)?;
+        assert_eq!(from_tmx, from_tmj);
+
+        let map = &from_tmx.map;
+        assert_eq!((3, 2), map.dimensions());
+        assert_eq!(Some(TileId::WALL), map.get(0, 1));
+        assert_eq!(Some(TileId::FLOOR), map.get(0, 0));
+        assert_eq!(Some(TileId(2)), map.get_in("decoration", 0, 0));
+        assert_eq!(None, map.get_in("decoration", 1, 0));
+        assert!(!map.layers()[1].visible);
+
+        let names: Vec<(&str, Option<&str>)> = from_tmx
+            .layers
+            .iter()
+            .map(|layer| (layer.name.as_str(), layer.map_layer.as_deref()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("Ground", Some(GROUND_LAYER)),
+                ("decoration", Some("decoration")),
+                ("spawns", None)
+            ],
+            names
+        );
+        assert_eq!(
+            Some(&Property::String("stone".into())),
+            from_tmx.layers[0].properties.get("footsteps")
+        );
+        assert_eq!(
+            Some(&Property::Int(2)),
+            from_tmx.layers[2].properties.get("wave")
+        );
+
+        let boss = &from_tmx.spawns[0];
+        assert_eq!(
+            ("boss", "goblin", "spawns"),
+            (&*boss.name, &*boss.class, &*boss.layer)
+        );
+        assert_eq!((1, 0), boss.tile());
+        assert_eq!(Some(&Property::Int(12)), boss.properties.get("health"));
+        assert_eq!(Some(&Property::Bool(true)), boss.properties.get("angry"));
+        let trigger = &from_tmx.spawns[1];
+        assert_eq!(
+            (0.0, 1.0, 3.0, 1.0),
+            (trigger.x, trigger.y, trigger.width, trigger.height)
+        );
+        assert_eq!(
+            Some(&Property::String("cave.ogg".into())),
+            from_tmx.properties.get("music")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported_maps_are_rejected() -> Result<()> {
+        let tiles = registry();
+        let decoration = encode(&[0; 6])?;
+        let error = |source: String| {
+            let err = TiledMap::from_tmx(&source, Path::new(""), &tiles).unwrap_err();
+            format!("{:#}", err)
+        };
+
+        let infinite = tmx(r#"infinite="1""#, &decoration);
+        assert!(error(infinite).contains("infinite maps are not supported"));
+        let isometric = tmx("", &decoration).replace("orthogonal", "isometric");
+        assert!(error(isometric).contains("isometric maps are not supported"));
+        let zstd = tmx("", &decoration).replace("zlib", "zstd");
+        assert!(error(zstd).contains("zstd compressed layers are not supported"));
+        let unknown = tmx("", &decoration).replace("\"rug\"", "\"lava\"");
+        let unknown = unknown.replace(&decoration, &encode(&[3, 0, 0, 0, 0, 0])?);
+        assert!(error(unknown).contains("`lava`, which is not in the tile registry"));
+        let ground = tmx("", &decoration).replace("\"decoration\"", "\"ground\"");
+        assert!(error(ground).contains("layer `ground` is not the first tile layer"));
+        let twice = tmx("", &decoration).replace(
+            "</map>",
+            r#"<layer id="4" name="decoration" width="3" height="2">
+                 <data encoding="csv">0,0,0,0,0,0</data>
+               </layer>
+            </map>"#,
+        );
+        assert!(error(twice).contains("two tile layers named `decoration`"));
+        let huge =
+            tmj(&decoration).replace(r#""width": 3"#, &format!(r#""width": {}"#, usize::MAX));
+        let err = TiledMap::from_tmj(&huge, Path::new(""), &tiles).unwrap_err();
+        assert!(format!("{:#}", err).contains("is too large"));
+        Ok(())
+    }
+}
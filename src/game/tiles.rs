@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::ecs::{Ref, World};
+use super::{GameState, TileMap};
+
+/// Index of a tile type in the [`TileRegistry`]. Maps and saves store these, so only ever append
+/// to `tiles.ron`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TileId(pub u16);
+
+impl TileId {
+    /// Map generation needs something to walk on and something to fill the rest with, so every
+    /// registry starts with these two.
+    pub const FLOOR: TileId = TileId(0);
+    pub const WALL: TileId = TileId(1);
+
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// One entry of `tiles.ron`:
+///
+/// ```ron
+/// (
+///     name: "door",
+///     sprite: "sprites/door.png",
+///     walkable: true,
+///     opaque: true,
+///     flags: ["door"],
+/// )
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TileDef {
+    pub name: String,
+    /// Image path relative to the assets directory.
+    pub sprite: String,
+    #[serde(default)]
+    pub walkable: bool,
+    /// Blocks line of sight.
+    #[serde(default)]
+    pub opaque: bool,
+    /// How many steps entering the tile is worth, for pathfinding.
+    #[serde(default = "default_movement_cost")]
+    pub movement_cost: u32,
+    /// Anything gameplay code wants to check for that doesn't warrant its own field.
+    #[serde(default)]
+    pub flags: Vec<String>,
+}
+
+fn default_movement_cost() -> u32 {
+    1
+}
+
+impl TileDef {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f == flag)
+    }
+}
+
+/// Every tile type the game knows about. Kept as a world resource.
+#[derive(Clone, Debug)]
+pub struct TileRegistry {
+    defs: Vec<TileDef>,
+    ids: HashMap<String, TileId>,
+    /// Unique per registry, so the renderer notices when the resource was replaced.
+    version: u64,
+}
+
+impl Default for TileRegistry {
+    /// Just floor and wall, enough to play without a `tiles.ron`.
+    fn default() -> Self {
+        let tile = |name: &str, sprite: &str, walkable| TileDef {
+            name: name.to_string(),
+            sprite: sprite.to_string(),
+            walkable,
+            opaque: !walkable,
+            movement_cost: 1,
+            flags: Vec::new(),
+        };
+        Self::new(vec![
+            tile("floor", "sprites/test5.png", true),
+            tile("wall", "sprites/test4.png", false),
+        ])
+        .unwrap()
+    }
+}
+
+impl TileRegistry {
+    pub fn new(defs: Vec<TileDef>) -> Result<Self> {
+        if defs.len() > u16::MAX as usize {
+            bail!("too many tile types ({})", defs.len())
+        }
+
+        let mut ids = HashMap::new();
+        for (index, def) in defs.iter().enumerate() {
+            if ids.insert(def.name.clone(), TileId(index as u16)).is_some() {
+                bail!("tile `{}` is defined twice", def.name)
+            }
+        }
+        for (id, name) in [(TileId::FLOOR, "floor"), (TileId::WALL, "wall")] {
+            if ids.get(name) != Some(&id) {
+                bail!("`{}` has to be tile number {}", name, id.index())
+            }
+        }
+
+        Ok(Self {
+            defs,
+            ids,
+            version: super::next_version(),
+        })
+    }
+
+    /// Parses a `tiles.ron`, a list of [`TileDef`]s.
+    pub fn from_ron(source: &str) -> Result<Self> {
+        let defs: Vec<TileDef> = ron::from_str(source)?;
+        Self::new(defs)
+    }
+
+    /// Loads `path`. A missing file means the built-in floor and wall.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            log::warn!(
+                "No tiles at {}, only floor and wall are known",
+                path.display()
+            );
+            return Ok(Self::default());
+        }
+
+        let source =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let registry =
+            Self::from_ron(&source).with_context(|| format!("loading {}", path.display()))?;
+        log::info!(
+            "Loaded {} tiles from {}",
+            registry.defs.len(),
+            path.display()
+        );
+        Ok(registry)
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn get(&self, id: TileId) -> Option<&TileDef> {
+        self.defs.get(id.index())
+    }
+
+    pub fn id(&self, name: &str) -> Option<TileId> {
+        self.ids.get(name).copied()
+    }
+
+    /// Definitions in id order.
+    pub fn defs(&self) -> &[TileDef] {
+        &self.defs
+    }
+
+    pub fn len(&self) -> usize {
+        self.defs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.defs.is_empty()
+    }
+
+    /// Unknown tiles are not walkable.
+    pub fn is_walkable(&self, id: TileId) -> bool {
+        self.get(id).is_some_and(|def| def.walkable)
+    }
+}
+
+/// Whether every tile at `(x, y)` can be walked on, so furniture on an upper layer blocks the
+/// floor below. Outside of the map nothing is.
+pub fn is_walkable(world: &World, x: usize, y: usize) -> bool {
+    let (Some(map), Some(tiles)) = (
+        world.resource::<TileMap>(),
+        world.resource::<TileRegistry>(),
+    ) else {
+        return false;
+    };
+    map.is_walkable(&tiles, x, y)
+}
+
+impl TileMap {
+    /// Like [`is_walkable`], for callers that already hold the map.
+    pub fn is_walkable(&self, tiles: &TileRegistry, x: usize, y: usize) -> bool {
+        let mut stack = self.stack(x, y).peekable();
+        stack.peek().is_some() && stack.all(|id| tiles.is_walkable(id))
+    }
+}
+
+impl GameState {
+    pub fn tiles(&self) -> Ref<'_, TileRegistry> {
+        self.world
+            .resource::<TileRegistry>()
+            .expect("tile registry missing")
+    }
+
+    /// Replaces the tile registry with the one in the assets directory.
+    pub fn load_tiles(&mut self, assets_path: &crate::graphics::assets::AssetsPath) -> Result<()> {
+        let tiles = TileRegistry::load(&crate::graphics::assets::tiles_path(assets_path))?;
+        self.world.insert_resource(tiles);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TILES: &str = r#"[
+        (name: "floor", sprite: "floor.png", walkable: true),
+        (name: "wall", sprite: "wall.png", opaque: true),
+        (name: "water", sprite: "water.png", walkable: true, movement_cost: 3, flags: ["wet"]),
+    ]"#;
+
+    #[test]
+    fn test_registry_from_ron() -> Result<()> {
+        let tiles = TileRegistry::from_ron(TILES)?;
+        let water = tiles.id("water").unwrap();
+        assert_eq!(TileId(2), water);
+        let def = tiles.get(water).unwrap();
+        assert_eq!(3, def.movement_cost);
+        assert!(def.has_flag("wet"));
+        assert!(!def.opaque);
+        assert!(tiles.is_walkable(TileId::FLOOR));
+        assert!(!tiles.is_walkable(TileId::WALL));
+        assert!(!tiles.is_walkable(TileId(7)));
+
+        assert!(TileRegistry::from_ron(r#"[(name: "wall", sprite: "wall.png")]"#).is_err());
+        let twice = TILES.replace("water", "wall");
+        assert!(TileRegistry::from_ron(&twice).is_err());
+
+        // Every registry is a new version, even with the same tiles.
+        assert_eq!(tiles.version(), tiles.clone().version());
+        assert_ne!(tiles.version(), TileRegistry::from_ron(TILES)?.version());
+        Ok(())
+    }
+
+    #[test]
+    fn test_walkability_comes_from_the_registry() -> Result<()> {
+        let mut game = GameState::new()?;
+        game.world_mut()
+            .insert_resource(TileRegistry::from_ron(TILES)?);
+        game.set_tile(2, 2, TileId(2))?;
+
+        assert!(is_walkable(game.world(), 1, 1));
+        assert!(is_walkable(game.world(), 2, 2));
+        assert!(!is_walkable(game.world(), 0, 0));
+        assert!(!is_walkable(game.world(), 100, 0));
+
+        let map = game.world_mut().resource_mut::<TileMap>().unwrap();
+        map.add_layer("furniture")?;
+        map.set_in("furniture", 1, 1, Some(TileId::WALL))?;
+        map.set_visible("furniture", false)?;
+        assert!(!is_walkable(game.world(), 1, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_shipped_tiles_load() -> Result<()> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/tiles.ron");
+        let tiles = TileRegistry::load(&path)?;
+        assert!(tiles
+            .get(tiles.id("door").unwrap())
+            .unwrap()
+            .has_flag("door"));
+        Ok(())
+    }
+}
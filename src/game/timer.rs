@@ -0,0 +1,433 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::clock::Clock;
+use super::ecs::Entity;
+use super::GameState;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimerMode {
+    Once,
+    Repeating,
+}
+
+/// Counts simulation ticks, not wall time, so it runs the same at any frame rate and stands still
+/// while the game is paused. Attach it to an entity and [`tick_timers`] advances it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timer {
+    duration: u64,
+    elapsed: u64,
+    mode: TimerMode,
+    paused: bool,
+    just_finished: bool,
+}
+
+impl Timer {
+    /// A timer always takes at least one tick.
+    pub fn new(duration: u64, mode: TimerMode) -> Self {
+        Self {
+            duration: duration.max(1),
+            elapsed: 0,
+            mode,
+            paused: false,
+            just_finished: false,
+        }
+    }
+
+    pub fn once(duration: u64) -> Self {
+        Self::new(duration, TimerMode::Once)
+    }
+
+    pub fn repeating(duration: u64) -> Self {
+        Self::new(duration, TimerMode::Repeating)
+    }
+
+    pub fn tick(&mut self) {
+        self.just_finished = false;
+        if self.paused || self.is_finished() {
+            return;
+        }
+
+        self.elapsed += 1;
+        if self.elapsed >= self.duration {
+            self.just_finished = true;
+            if self.mode == TimerMode::Repeating {
+                self.elapsed = 0;
+            }
+        }
+    }
+
+    pub fn duration(&self) -> u64 {
+        self.duration
+    }
+
+    pub fn elapsed(&self) -> u64 {
+        self.elapsed
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.duration - self.elapsed
+    }
+
+    /// Progress from 0 to 1, handy for animations.
+    pub fn fraction(&self) -> f32 {
+        self.elapsed as f32 / self.duration as f32
+    }
+
+    /// Whether a one-shot timer ran out. Repeating timers never finish.
+    pub fn is_finished(&self) -> bool {
+        self.mode == TimerMode::Once && self.elapsed >= self.duration
+    }
+
+    /// Whether the timer ran out during the last tick.
+    pub fn just_finished(&self) -> bool {
+        self.just_finished
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn reset(&mut self) {
+        self.elapsed = 0;
+        self.just_finished = false;
+    }
+}
+
+/// Ability cooldown. It starts out ready, using it blocks it for `duration` ticks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cooldown {
+    duration: u64,
+    remaining: u64,
+}
+
+impl Cooldown {
+    pub fn new(duration: u64) -> Self {
+        Self {
+            duration,
+            remaining: 0,
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.remaining == 0
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Starts the cooldown if it is ready. Returns whether the ability may be used.
+    pub fn trigger(&mut self) -> bool {
+        if !self.is_ready() {
+            return false;
+        }
+        self.remaining = self.duration;
+        true
+    }
+
+    pub fn tick(&mut self) {
+        self.remaining = self.remaining.saturating_sub(1);
+    }
+
+    pub fn reset(&mut self) {
+        self.remaining = 0;
+    }
+}
+
+/// A registered action to run later, see [`GameState::after`]. Plain data, so pending actions
+/// are snapshotted and saved like everything else in the world.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DelayedAction {
+    /// Name the handler was registered under with [`DelayedHandlers::register`].
+    pub name: String,
+    /// Entity the action is about, if any. It may be gone by the time the action runs.
+    pub entity: Option<Entity>,
+}
+
+impl DelayedAction {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            entity: None,
+        }
+    }
+
+    pub fn on(name: &str, entity: Entity) -> Self {
+        Self {
+            name: name.to_string(),
+            entity: Some(entity),
+        }
+    }
+}
+
+/// Actions waiting for a [`Clock`] tick. Lives in the world as a resource.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DelayedActions {
+    /// Sorted by due tick, actions due on the same tick keep the order they were added in.
+    queue: Vec<(u64, DelayedAction)>,
+}
+
+impl DelayedActions {
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+
+    fn push(&mut self, due: u64, action: DelayedAction) {
+        let idx = self.queue.partition_point(|(d, _)| *d <= due);
+        self.queue.insert(idx, (due, action));
+    }
+
+    /// Removes the actions due by `now` that `runnable` accepts, the others stay queued.
+    fn take_due<F>(&mut self, now: u64, mut runnable: F) -> Vec<DelayedAction>
+    where
+        F: FnMut(&DelayedAction) -> bool,
+    {
+        let due = self.queue.partition_point(|(d, _)| *d <= now);
+        let (taken, kept) = self
+            .queue
+            .drain(..due)
+            .partition::<Vec<_>, _>(|(_, action)| runnable(action));
+        self.queue.splice(..0, kept);
+        taken.into_iter().map(|(_, action)| action).collect()
+    }
+}
+
+pub type DelayedHandler = Rc<dyn Fn(&mut GameState, Option<Entity>) -> Result<()>>;
+
+/// What the delayed actions do, by name. Handlers are code rather than world data, so register
+/// them again after loading a save in a new game.
+#[derive(Default)]
+pub struct DelayedHandlers {
+    handlers: HashMap<String, DelayedHandler>,
+    /// Names of due actions without a handler that were already reported.
+    reported: HashSet<String>,
+}
+
+impl DelayedHandlers {
+    pub fn register<F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        F: Fn(&mut GameState, Option<Entity>) -> Result<()> + 'static,
+    {
+        self.handlers.insert(name.to_string(), Rc::new(f));
+        self
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.handlers.contains_key(name)
+    }
+}
+
+impl GameState {
+    pub fn delayed_handlers_mut(&mut self) -> &mut DelayedHandlers {
+        &mut self.delayed
+    }
+
+    /// Runs `action` once `ticks` more simulation ticks passed, at the earliest on the next tick.
+    pub fn after(&mut self, ticks: u64, action: DelayedAction) -> Result<()> {
+        if !self.delayed.contains(&action.name) {
+            bail!("no handler for delayed action `{}`", action.name)
+        }
+
+        let now = self
+            .world
+            .resource::<Clock>()
+            .map_or(0, |clock| clock.tick());
+        if !self.world.contains_resource::<DelayedActions>() {
+            self.world.insert_resource(DelayedActions::default());
+        }
+        self.world
+            .resource_mut::<DelayedActions>()
+            .unwrap()
+            .push(now + ticks, action);
+        Ok(())
+    }
+
+    /// Number of actions waiting to run.
+    pub fn pending_actions(&self) -> usize {
+        self.world
+            .resource::<DelayedActions>()
+            .map_or(0, |delayed| delayed.len())
+    }
+}
+
+/// Advances every [`Timer`] and [`Cooldown`] by one tick.
+pub fn tick_timers(game: &mut GameState) -> Result<()> {
+    for mut timer in &mut game.world.query::<&mut Timer>()? {
+        timer.tick();
+    }
+    for mut cooldown in &mut game.world.query::<&mut Cooldown>()? {
+        cooldown.tick();
+    }
+    Ok(())
+}
+
+/// Runs the delayed actions that are due on the current [`Clock`] tick. Actions added while
+/// running wait for a later tick.
+///
+/// Actions without a handler, e.g. from a save of a game that registered more, stay queued until
+/// one is registered. A failing handler is logged and doesn't keep the other actions from
+/// running.
+pub fn run_delayed_actions(game: &mut GameState) -> Result<()> {
+    let now = game
+        .world
+        .resource::<Clock>()
+        .map_or(0, |clock| clock.tick());
+    let handlers = &mut game.delayed;
+    let due = match game.world.resource_mut::<DelayedActions>() {
+        Some(delayed) => delayed.take_due(now, |action| {
+            if handlers.contains(&action.name) {
+                return true;
+            }
+            if handlers.reported.insert(action.name.clone()) {
+                log::warn!("no handler for delayed action `{}`", action.name);
+            }
+            false
+        }),
+        None => return Ok(()),
+    };
+    for action in due {
+        let handler = game.delayed.handlers[&action.name].clone();
+        if let Err(err) = handler(game, action.entity) {
+            log::error!("delayed action `{}` failed: {:#}", action.name, err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::state::{self, AppState, Transition};
+
+    #[test]
+    fn test_timer_modes() {
+        let mut once = Timer::once(3);
+        let mut repeating = Timer::repeating(2);
+        let mut finished = (Vec::new(), Vec::new());
+        for tick in 1..=6 {
+            once.tick();
+            repeating.tick();
+            if once.just_finished() {
+                finished.0.push(tick);
+            }
+            if repeating.just_finished() {
+                finished.1.push(tick);
+            }
+            if tick == 4 {
+                repeating.pause();
+            }
+        }
+
+        assert_eq!((vec![3], vec![2, 4]), finished);
+        assert!(once.is_finished());
+        assert_eq!(0, once.remaining());
+        assert_eq!(2, repeating.remaining());
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let mut cooldown = Cooldown::new(2);
+        assert!(cooldown.trigger());
+        assert!(!cooldown.trigger());
+        cooldown.tick();
+        assert!(!cooldown.is_ready());
+        cooldown.tick();
+        assert!(cooldown.trigger());
+    }
+
+    #[test]
+    fn test_timers_freeze_while_paused() -> Result<()> {
+        let mut game = GameState::new()?;
+        let mut schedule = crate::game::build_schedule()?;
+        let mut tick = |game: &mut GameState| {
+            schedule.run(game).unwrap();
+            game.finish_tick();
+        };
+        let transition = |game: &mut GameState, transition| {
+            game.states_mut().request(transition);
+            state::apply_transitions(game);
+        };
+        transition(&mut game, Transition::Reset(AppState::Playing));
+
+        let e = game.world_mut().spawn();
+        game.world_mut().insert(e, Timer::once(2))?;
+        game.world_mut().insert(e, Cooldown::new(3))?;
+        game.world_mut().get_mut::<Cooldown>(e).unwrap().trigger();
+        game.delayed_handlers_mut()
+            .register("despawn", |game, entity| {
+                game.world_mut().despawn(entity.unwrap())
+            });
+        let doomed = game.world_mut().spawn();
+        game.after(2, DelayedAction::on("despawn", doomed))?;
+        assert!(game.after(1, DelayedAction::new("explode")).is_err());
+
+        tick(&mut game);
+        transition(&mut game, Transition::Push(AppState::Paused));
+        for _ in 0..10 {
+            tick(&mut game);
+        }
+        assert_eq!(1, game.world().get::<Timer>(e).unwrap().elapsed());
+        assert_eq!(2, game.world().get::<Cooldown>(e).unwrap().remaining());
+        assert!(game.world().is_alive(doomed));
+
+        transition(&mut game, Transition::Pop);
+        tick(&mut game);
+        assert!(game.world().get::<Timer>(e).unwrap().just_finished());
+        assert_eq!(1, game.world().get::<Cooldown>(e).unwrap().remaining());
+        assert!(!game.world().is_alive(doomed));
+        assert_eq!(0, game.pending_actions());
+        Ok(())
+    }
+
+    #[test]
+    fn test_failing_and_unknown_actions_keep_the_rest() -> Result<()> {
+        let mut game = GameState::new()?;
+        game.delayed_handlers_mut()
+            .register("fail", |_, _| bail!("broken"))
+            .register("despawn", |game, entity| {
+                game.world_mut().despawn(entity.unwrap())
+            });
+        let doomed = game.world_mut().spawn();
+        game.after(0, DelayedAction::new("fail"))?;
+        game.after(0, DelayedAction::on("despawn", doomed))?;
+        // As if loaded from a save, `after` only takes known actions.
+        game.world_mut()
+            .resource_mut::<DelayedActions>()
+            .unwrap()
+            .push(0, DelayedAction::on("despawn_later", doomed));
+
+        run_delayed_actions(&mut game)?;
+        assert!(!game.world().is_alive(doomed));
+        assert_eq!(1, game.pending_actions());
+
+        let revived = game.world_mut().spawn();
+        game.delayed_handlers_mut()
+            .register("despawn_later", move |game, _| {
+                game.world_mut().despawn(revived)
+            });
+        run_delayed_actions(&mut game)?;
+        assert!(!game.world().is_alive(revived));
+        assert_eq!(0, game.pending_actions());
+        Ok(())
+    }
+}
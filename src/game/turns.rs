@@ -0,0 +1,512 @@
+use std::collections::VecDeque;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use winit::keyboard::{KeyCode, NamedKey};
+
+use super::ecs::{Entity, Transform, World};
+use super::events::ActorActed;
+use super::rng::Rng;
+use super::script;
+use super::tiles::{self, TileRegistry};
+use super::{GameState, TileMap};
+
+/// Monsters further than this many steps away from the player don't notice it.
+pub const CHASE_RANGE: u32 = 8;
+
+/// Energy an actor spends per action. Actors gain their speed in energy per time step, so an
+/// actor with twice the speed acts twice as often.
+pub const ACTION_COST: u32 = 100;
+
+/// Anything that takes turns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Actor {
+    pub speed: u32,
+    pub energy: u32,
+}
+
+impl Actor {
+    /// New actors can act right away.
+    pub fn new(speed: u32) -> Self {
+        Self {
+            speed,
+            energy: ACTION_COST,
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.energy >= ACTION_COST
+    }
+
+    /// Time steps until the actor can act, `None` if it never will.
+    fn steps_until_ready(&self) -> Option<u32> {
+        if self.is_ready() {
+            return Some(0);
+        }
+        if self.speed == 0 {
+            return None;
+        }
+
+        Some((ACTION_COST - self.energy).div_ceil(self.speed))
+    }
+}
+
+/// Marks the actor the world waits for. Everyone else is controlled by [`ai_action`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Player;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Action {
+    Wait,
+    Move { dx: i32, dy: i32 },
+}
+
+/// Progress of the turn based mode. The world only moves once the player submitted an action.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Turns {
+    /// Time steps passed, i.e. how often every actor gained energy.
+    pub turn: u64,
+    submitted: Option<Action>,
+}
+
+impl Turns {
+    pub fn submit(&mut self, action: Action) {
+        self.submitted = Some(action);
+    }
+
+    pub fn has_submitted(&self) -> bool {
+        self.submitted.is_some()
+    }
+}
+
+/// Run condition for [`take_turns`].
+pub fn action_submitted(game: &GameState) -> bool {
+    game.world
+        .resource::<Turns>()
+        .is_some_and(|turns| turns.has_submitted())
+}
+
+/// Maps arrow keys to moves and space to waiting.
+pub fn read_player_input(game: &mut GameState) {
+    let input = &game.input;
+    let action = if input.is_logical_key_pressed(NamedKey::ArrowUp) {
+        Action::Move { dx: 0, dy: 1 }
+    } else if input.is_logical_key_pressed(NamedKey::ArrowDown) {
+        Action::Move { dx: 0, dy: -1 }
+    } else if input.is_logical_key_pressed(NamedKey::ArrowLeft) {
+        Action::Move { dx: -1, dy: 0 }
+    } else if input.is_logical_key_pressed(NamedKey::ArrowRight) {
+        Action::Move { dx: 1, dy: 0 }
+    } else if input.is_physical_key_pressed(KeyCode::Space) {
+        Action::Wait
+    } else {
+        return;
+    };
+
+    if let Some(turns) = game.world.resource_mut::<Turns>() {
+        turns.submit(action);
+    }
+}
+
+/// Lets the player perform the submitted action, then runs time forward until the player is
+/// ready again. Actors that are ready at the same time act in order of their energy, ties broken
+/// by entity index, so runs are reproducible.
+pub fn take_turns(game: &mut GameState) -> Result<()> {
+    let Some(action) = game
+        .world
+        .resource_mut::<Turns>()
+        .and_then(|turns| turns.submitted.take())
+    else {
+        return Ok(());
+    };
+
+    let player = game
+        .world
+        .query::<(Entity, &Player)>()?
+        .into_iter()
+        .next()
+        .map(|(entity, _)| entity);
+    let Some(player) = player else {
+        return Ok(());
+    };
+    let unready = |game: &GameState| {
+        game.world
+            .get::<Actor>(player)
+            .is_some_and(|a| !a.is_ready())
+    };
+    if unready(game) {
+        // The player had no energy to act yet, e.g. right after spawning.
+        advance_until_ready(game, player)?;
+        if unready(game) {
+            // And never will.
+            return Ok(());
+        }
+    }
+    act(game, player, action)?;
+    advance_until_ready(game, player)
+}
+
+fn advance_until_ready(game: &mut GameState, player: Entity) -> Result<()> {
+    loop {
+        let steps = game
+            .world
+            .get::<Actor>(player)
+            .and_then(|a| a.steps_until_ready());
+        if steps.is_none() {
+            // The player lost its actor or has no speed, so waiting for it would never end.
+            return Ok(());
+        }
+        let ready = ready_actors(&game.world)?;
+        if ready.is_empty() {
+            if !advance_time(&mut game.world)? {
+                // Nobody will ever act again.
+                return Ok(());
+            }
+            continue;
+        }
+        for actor in ready {
+            if actor == player {
+                return Ok(());
+            }
+            let action = match script::on_turn(game, actor) {
+                Some(action) => action,
+                None => ai_action(&mut game.world, actor)?,
+            };
+            act(game, actor, action)?;
+        }
+    }
+}
+
+fn ready_actors(world: &World) -> Result<Vec<Entity>> {
+    let mut ready: Vec<(Entity, u32)> = world
+        .query::<(Entity, &Actor)>()?
+        .iter()
+        .filter(|(_, actor)| actor.is_ready())
+        .map(|(entity, actor)| (entity, actor.energy))
+        .collect();
+    ready.sort_by_key(|(entity, energy)| (std::cmp::Reverse(*energy), entity.index()));
+    Ok(ready.into_iter().map(|(entity, _)| entity).collect())
+}
+
+/// Skips ahead to the next time step where somebody can act. Returns false if nobody ever will.
+fn advance_time(world: &mut World) -> Result<bool> {
+    let mut query = world.query::<&mut Actor>()?;
+    let steps = query.iter().filter_map(|a| a.steps_until_ready()).min();
+    let Some(steps) = steps else {
+        return Ok(false);
+    };
+
+    for mut actor in &mut query {
+        actor.energy += actor.speed * steps;
+    }
+    drop(query);
+
+    world
+        .resource_mut::<Turns>()
+        .context("turns resource missing")?
+        .turn += steps as u64;
+    Ok(true)
+}
+
+/// Performs `action` for `actor` and pays its energy.
+fn act(game: &mut GameState, actor: Entity, action: Action) -> Result<()> {
+    if let Action::Move { dx, dy } = action {
+        let target = game.world.get::<Transform>(actor).map(|t| {
+            let x = t.translation.x.round() as i64 + dx as i64;
+            let y = t.translation.y.round() as i64 + dy as i64;
+            (x, y)
+        });
+        // Bumping into a scripted entity interacts with it instead of moving.
+        let target = target
+            .filter(|(x, y)| !script::interact_at(game, actor, *x, *y))
+            .and_then(|(x, y)| Some((usize::try_from(x).ok()?, usize::try_from(y).ok()?)));
+        if let Some(target) = target.filter(|(x, y)| tiles::is_walkable(&game.world, *x, *y)) {
+            let transform = game.world.get_mut::<Transform>(actor).unwrap();
+            *transform = Transform::from_xy(target.0 as f32, target.1 as f32);
+        }
+    }
+
+    if let Some(actor) = game.world.get_mut::<Actor>(actor) {
+        actor.energy -= ACTION_COST;
+    }
+    game.events.send(ActorActed {
+        entity: actor,
+        action,
+    });
+    Ok(())
+}
+
+/// Steps from every tile to the player over walkable tiles, `None` where the player can't be
+/// reached. Built by [`plan_paths`] before the turn, so it doesn't include the player's move.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PathMap {
+    width: usize,
+    height: usize,
+    steps: Vec<Option<u32>>,
+}
+
+const NEIGHBOURS: [(i32, i32); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+
+impl PathMap {
+    pub fn steps(&self, x: i64, y: i64) -> Option<u32> {
+        let x = usize::try_from(x).ok().filter(|x| *x < self.width)?;
+        let y = usize::try_from(y).ok().filter(|y| *y < self.height)?;
+        self.steps[y * self.width + x]
+    }
+
+    /// The move that brings `(x, y)` one step closer to the player.
+    pub fn towards_player(&self, x: i64, y: i64) -> Option<Action> {
+        let here = self.steps(x, y)?;
+        NEIGHBOURS
+            .into_iter()
+            .filter_map(|(dx, dy)| Some((dx, dy, self.steps(x + dx as i64, y + dy as i64)?)))
+            .filter(|(_, _, steps)| *steps < here)
+            .min_by_key(|(_, _, steps)| *steps)
+            .map(|(dx, dy, _)| Action::Move { dx, dy })
+    }
+}
+
+/// Floods the map from the player's tile into the [`PathMap`]. Only reads the map and positions,
+/// so it runs on the thread pool.
+pub fn plan_paths(world: &World) -> Result<()> {
+    let map = world.resource::<TileMap>().context("no map loaded")?;
+    let tiles = world
+        .resource::<TileRegistry>()
+        .context("tile registry missing")?;
+    let (width, height) = map.dimensions();
+    let player = world
+        .query::<(&Transform, &Player)>()?
+        .iter()
+        .next()
+        .and_then(|(transform, _)| {
+            let x = usize::try_from(transform.translation.x.round() as i64).ok()?;
+            let y = usize::try_from(transform.translation.y.round() as i64).ok()?;
+            Some((x, y)).filter(|(x, y)| *x < width && *y < height)
+        });
+
+    let mut steps = vec![None; width * height];
+    let mut queue = VecDeque::new();
+    if let Some((x, y)) = player {
+        steps[y * width + x] = Some(0);
+        queue.push_back((x, y));
+    }
+    while let Some((x, y)) = queue.pop_front() {
+        let next = steps[y * width + x].map(|s| s + 1);
+        for (dx, dy) in NEIGHBOURS {
+            let (Some(nx), Some(ny)) = (
+                x.checked_add_signed(dx as isize),
+                y.checked_add_signed(dy as isize),
+            ) else {
+                continue;
+            };
+            if nx >= width || ny >= height || steps[ny * width + nx].is_some() {
+                continue;
+            }
+            if map.is_walkable(&tiles, nx, ny) {
+                steps[ny * width + nx] = next;
+                queue.push_back((nx, ny));
+            }
+        }
+    }
+
+    *world
+        .borrow_resource_mut::<PathMap>()?
+        .context("path map missing")? = PathMap {
+        width,
+        height,
+        steps,
+    };
+    Ok(())
+}
+
+/// Monsters without an `on_turn` script close in on the player once it is within
+/// [`CHASE_RANGE`] steps, and wander around randomly otherwise.
+pub fn ai_action(world: &mut World, actor: Entity) -> Result<Action> {
+    let chase = world.get::<Transform>(actor).and_then(|t| {
+        let (x, y) = (
+            t.translation.x.round() as i64,
+            t.translation.y.round() as i64,
+        );
+        let paths = world.resource::<PathMap>()?;
+        match paths.steps(x, y)? {
+            // Next to the player already.
+            0 | 1 => Some(Action::Wait),
+            steps if steps <= CHASE_RANGE => paths.towards_player(x, y),
+            _ => None,
+        }
+    });
+    if let Some(action) = chase {
+        return Ok(action);
+    }
+
+    let rng = world
+        .resource_mut::<Rng>()
+        .context("rng resource missing")?;
+    let action = match rng.below(5) {
+        0 => Action::Wait,
+        1 => Action::Move { dx: 1, dy: 0 },
+        2 => Action::Move { dx: -1, dy: 0 },
+        3 => Action::Move { dx: 0, dy: 1 },
+        _ => Action::Move { dx: 0, dy: -1 },
+    };
+    Ok(action)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::game::ecs::EventReader;
+
+    fn spawn_actor(game: &mut GameState, speed: u32, x: f32) -> Result<Entity> {
+        let e = game.world_mut().spawn();
+        game.world_mut().insert(e, Actor::new(speed))?;
+        game.world_mut().insert(e, Transform::from_xy(x, 5.0))?;
+        Ok(e)
+    }
+
+    #[test]
+    fn test_speed_two_acts_twice_per_speed_one() -> Result<()> {
+        let mut game = GameState::new()?;
+        let player = spawn_actor(&mut game, 10, 1.0)?;
+        game.world_mut().insert(player, Player)?;
+        let slow = spawn_actor(&mut game, 10, 4.0)?;
+        let fast = spawn_actor(&mut game, 20, 6.0)?;
+
+        let mut reader = EventReader::<ActorActed>::new();
+        let mut counts: HashMap<Entity, u32> = HashMap::new();
+        let mut order = Vec::new();
+        for _ in 0..10 {
+            game.world_mut()
+                .resource_mut::<Turns>()
+                .unwrap()
+                .submit(Action::Wait);
+            take_turns(&mut game)?;
+            for acted in game.events().read(&mut reader) {
+                *counts.entry(acted.entity).or_default() += 1;
+                order.push(acted.entity);
+            }
+        }
+
+        assert_eq!(10, counts[&player]);
+        assert_eq!(2 * counts[&slow], counts[&fast]);
+        // Same energy, so the lower index goes first.
+        assert_eq!(vec![player, slow, fast, fast], order[..4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_world_waits_for_the_player() -> Result<()> {
+        let mut game = GameState::new()?;
+        let player = spawn_actor(&mut game, 10, 1.0)?;
+        game.world_mut().insert(player, Player)?;
+        spawn_actor(&mut game, 30, 4.0)?;
+
+        take_turns(&mut game)?;
+        assert_eq!(0, game.world().resource::<Turns>().unwrap().turn);
+        assert!(!action_submitted(&game));
+
+        game.world_mut()
+            .resource_mut::<Turns>()
+            .unwrap()
+            .submit(Action::Move { dx: 1, dy: 0 });
+        assert!(action_submitted(&game));
+        take_turns(&mut game)?;
+        let turn = game.world().resource::<Turns>().unwrap().turn;
+        assert_eq!(10, turn);
+        assert_eq!(
+            Transform::from_xy(2.0, 5.0),
+            *game.world().get::<Transform>(player).unwrap()
+        );
+
+        // Walls block, but the turn is still spent.
+        let mut walled = game.world().get::<Transform>(player).unwrap().translation;
+        walled.x = 1.0;
+        game.world_mut().insert(
+            player,
+            Transform {
+                translation: walled,
+            },
+        )?;
+        game.world_mut()
+            .resource_mut::<Turns>()
+            .unwrap()
+            .submit(Action::Move { dx: -1, dy: 0 });
+        take_turns(&mut game)?;
+        assert_eq!(
+            Transform::from_xy(1.0, 5.0),
+            *game.world().get::<Transform>(player).unwrap()
+        );
+        assert_eq!(turn + 10, game.world().resource::<Turns>().unwrap().turn);
+        Ok(())
+    }
+
+    #[test]
+    fn test_monsters_close_in_on_the_player() -> Result<()> {
+        let mut game = GameState::new()?;
+        let player = spawn_actor(&mut game, 10, 1.0)?;
+        game.world_mut().insert(player, Player)?;
+        let monster = spawn_actor(&mut game, 10, 6.0)?;
+
+        let mut schedule = crate::game::build_schedule()?;
+        // Planning runs on the thread pool, not as part of the turn.
+        assert!(schedule
+            .batches(crate::game::schedule::Stage::Simulate)
+            .contains(&vec!["plan_paths"]));
+        for x in [5.0, 4.0, 3.0, 2.0, 2.0] {
+            game.world_mut()
+                .resource_mut::<Turns>()
+                .unwrap()
+                .submit(Action::Wait);
+            schedule.run(&mut game)?;
+            assert_eq!(
+                Transform::from_xy(x, 5.0),
+                *game.world().get::<Transform>(monster).unwrap()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_player_without_actor_stops_the_world() -> Result<()> {
+        let mut game = GameState::new()?;
+        let player = game.world_mut().spawn();
+        game.world_mut().insert(player, Player)?;
+        game.world_mut()
+            .insert(player, Transform::from_xy(1.0, 5.0))?;
+        let monster = spawn_actor(&mut game, 10, 4.0)?;
+
+        game.world_mut()
+            .resource_mut::<Turns>()
+            .unwrap()
+            .submit(Action::Wait);
+        take_turns(&mut game)?;
+        assert_eq!(0, game.world().resource::<Turns>().unwrap().turn);
+        assert_eq!(Actor::new(10), *game.world().get::<Actor>(monster).unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn test_player_without_speed_stops_the_world() -> Result<()> {
+        let mut game = GameState::new()?;
+        let player = spawn_actor(&mut game, 0, 1.0)?;
+        game.world_mut().insert(player, Player)?;
+        spawn_actor(&mut game, 10, 4.0)?;
+
+        let mut reader = EventReader::<ActorActed>::new();
+        for _ in 0..2 {
+            game.world_mut()
+                .resource_mut::<Turns>()
+                .unwrap()
+                .submit(Action::Wait);
+            take_turns(&mut game)?;
+        }
+        // The player used up its starting energy and can't act again, so nobody else does.
+        let acted: Vec<_> = game.events().read(&mut reader).map(|a| a.entity).collect();
+        assert_eq!(vec![player], acted);
+        assert_eq!(0, game.world().resource::<Turns>().unwrap().turn);
+        assert_eq!(0, game.world().get::<Actor>(player).unwrap().energy);
+        Ok(())
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::{HashMap, VecDeque};
+use std::f32::consts::PI;
+
+use anyhow::Result;
+use cgmath::{Vector2, VectorSpace};
+use serde::{Deserialize, Serialize};
+
+use super::camera::CameraRig;
+use super::components::Tint;
+use super::ecs::{Entity, Transform, World};
+use super::events::TweenFinished;
+use super::GameState;
+
+/// Easing curves, mapping progress from 0 to 1 onto the share of the way travelled. See
+/// <https://easings.net> for what they look like.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Ease {
+    #[default]
+    Linear,
+    QuadIn,
+    QuadOut,
+    QuadInOut,
+    CubicIn,
+    CubicOut,
+    CubicInOut,
+    ElasticIn,
+    ElasticOut,
+    ElasticInOut,
+    BackIn,
+    BackOut,
+    BackInOut,
+}
+
+impl Ease {
+    /// Elastic and back curves overshoot, so the result can leave 0..=1 in between.
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        // Overshoot of the back curves.
+        const C1: f32 = 1.70158;
+        const C2: f32 = C1 * 1.525;
+        const C3: f32 = C1 + 1.0;
+
+        match self {
+            Ease::Linear => t,
+            Ease::QuadIn => t * t,
+            Ease::QuadOut => 1.0 - (1.0 - t).powi(2),
+            Ease::QuadInOut if t < 0.5 => 2.0 * t * t,
+            Ease::QuadInOut => 1.0 - (-2.0 * t + 2.0).powi(2) / 2.0,
+            Ease::CubicIn => t.powi(3),
+            Ease::CubicOut => 1.0 - (1.0 - t).powi(3),
+            Ease::CubicInOut if t < 0.5 => 4.0 * t.powi(3),
+            Ease::CubicInOut => 1.0 - (-2.0 * t + 2.0).powi(3) / 2.0,
+            Ease::ElasticIn | Ease::ElasticOut | Ease::ElasticInOut if t == 0.0 || t == 1.0 => t,
+            Ease::ElasticIn => {
+                -(2f32.powf(10.0 * t - 10.0)) * ((t * 10.0 - 10.75) * (2.0 * PI / 3.0)).sin()
+            }
+            Ease::ElasticOut => {
+                2f32.powf(-10.0 * t) * ((t * 10.0 - 0.75) * (2.0 * PI / 3.0)).sin() + 1.0
+            }
+            Ease::ElasticInOut => {
+                let wave = ((20.0 * t - 11.125) * (2.0 * PI / 4.5)).sin();
+                if t < 0.5 {
+                    -(2f32.powf(20.0 * t - 10.0) * wave) / 2.0
+                } else {
+                    2f32.powf(-20.0 * t + 10.0) * wave / 2.0 + 1.0
+                }
+            }
+            Ease::BackIn => C3 * t.powi(3) - C1 * t * t,
+            Ease::BackOut => 1.0 + C3 * (t - 1.0).powi(3) + C1 * (t - 1.0).powi(2),
+            Ease::BackInOut if t < 0.5 => (2.0 * t).powi(2) * ((C2 + 1.0) * 2.0 * t - C2) / 2.0,
+            Ease::BackInOut => {
+                ((2.0 * t - 2.0).powi(2) * ((C2 + 1.0) * (t * 2.0 - 2.0) + C2) + 2.0) / 2.0
+            }
+        }
+    }
+}
+
+/// A property a tween animates, holding the value to animate to or from.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TweenValue {
+    /// The entity's [`Transform`].
+    Translation(Vector2<f32>),
+    /// The entity's [`Tint`].
+    Tint(Tint),
+    /// [`CameraRig::position`], whatever entity the tween runs on.
+    CameraPosition(Vector2<f32>),
+    /// [`CameraRig::zoom`].
+    CameraZoom(f32),
+}
+
+impl TweenValue {
+    fn lerp(self, to: TweenValue, t: f32) -> TweenValue {
+        match (self, to) {
+            (TweenValue::Translation(a), TweenValue::Translation(b)) => {
+                TweenValue::Translation(a.lerp(b, t))
+            }
+            (TweenValue::Tint(Tint(a)), TweenValue::Tint(Tint(b))) => {
+                TweenValue::Tint(Tint(std::array::from_fn(|i| a[i] + (b[i] - a[i]) * t)))
+            }
+            (TweenValue::CameraPosition(a), TweenValue::CameraPosition(b)) => {
+                TweenValue::CameraPosition(a.lerp(b, t))
+            }
+            (TweenValue::CameraZoom(a), TweenValue::CameraZoom(b)) => {
+                TweenValue::CameraZoom(a + (b - a) * t)
+            }
+            _ => unreachable!("tween from {:?} to {:?}", self, to),
+        }
+    }
+
+    /// The current value of the same property.
+    fn read(self, world: &World, entity: Option<Entity>) -> TweenValue {
+        let camera = || world.resource::<CameraRig>();
+        match self {
+            TweenValue::Translation(to) => TweenValue::Translation(
+                entity
+                    .and_then(|e| world.get::<Transform>(e))
+                    .map_or(to, |t| t.translation),
+            ),
+            TweenValue::Tint(_) => TweenValue::Tint(
+                entity
+                    .and_then(|e| world.get::<Tint>(e).map(|t| *t))
+                    .unwrap_or_default(),
+            ),
+            TweenValue::CameraPosition(to) => {
+                TweenValue::CameraPosition(camera().map_or(to, |c| c.position))
+            }
+            TweenValue::CameraZoom(to) => TweenValue::CameraZoom(camera().map_or(to, |c| c.zoom)),
+        }
+    }
+
+    fn write(self, world: &mut World, entity: Option<Entity>) -> Result<()> {
+        match (self, entity) {
+            (TweenValue::Translation(translation), Some(e)) => {
+                world.insert(e, Transform { translation })?;
+            }
+            (TweenValue::Tint(tint), Some(e)) => {
+                world.insert(e, tint)?;
+            }
+            (TweenValue::CameraPosition(position), _) => {
+                if let Some(camera) = world.resource_mut::<CameraRig>() {
+                    camera.position = position;
+                }
+            }
+            (TweenValue::CameraZoom(zoom), _) => {
+                if let Some(camera) = world.resource_mut::<CameraRig>() {
+                    camera.zoom = zoom;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Animates a property to `to` over `duration` simulation ticks. It starts from whatever the
+/// value is when the tween starts, so tweens can be queued without knowing where the previous one
+/// ends.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Tween {
+    to: TweenValue,
+    from: Option<TweenValue>,
+    ease: Ease,
+    duration: u64,
+    elapsed: u64,
+    label: Option<String>,
+}
+
+impl Tween {
+    /// A tween always takes at least one tick.
+    pub fn new(to: TweenValue, duration: u64, ease: Ease) -> Self {
+        Self {
+            to,
+            from: None,
+            ease,
+            duration: duration.max(1),
+            elapsed: 0,
+            label: None,
+        }
+    }
+
+    pub fn translation(to: Vector2<f32>, duration: u64, ease: Ease) -> Self {
+        Self::new(TweenValue::Translation(to), duration, ease)
+    }
+
+    pub fn tint(to: Tint, duration: u64, ease: Ease) -> Self {
+        Self::new(TweenValue::Tint(to), duration, ease)
+    }
+
+    pub fn camera_position(to: Vector2<f32>, duration: u64, ease: Ease) -> Self {
+        Self::new(TweenValue::CameraPosition(to), duration, ease)
+    }
+
+    pub fn camera_zoom(to: f32, duration: u64, ease: Ease) -> Self {
+        Self::new(TweenValue::CameraZoom(to), duration, ease)
+    }
+
+    /// Names the tween, so a [`TweenFinished`] is sent and the hooks registered for the label with
+    /// [`TweenHooks::on_finished`] run once it completes.
+    pub fn labeled(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Progress from 0 to 1, before easing.
+    pub fn progress(&self) -> f32 {
+        self.elapsed as f32 / self.duration as f32
+    }
+
+    /// Advances by one tick. Returns the new value to write and whether the tween is done.
+    fn step(&mut self, world: &World, entity: Option<Entity>) -> (TweenValue, bool) {
+        let from = *self.from.get_or_insert_with(|| self.to.read(world, entity));
+        self.elapsed += 1;
+        let value = from.lerp(self.to, self.ease.apply(self.progress()));
+        (value, self.elapsed >= self.duration)
+    }
+}
+
+/// Tweens played one after another.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Tweens {
+    queue: VecDeque<Tween>,
+}
+
+impl Tweens {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues `tween` after the ones already in the sequence.
+    pub fn then(mut self, tween: Tween) -> Self {
+        self.push(tween);
+        self
+    }
+
+    pub fn push(&mut self, tween: Tween) {
+        self.queue.push_back(tween);
+    }
+
+    pub fn current(&self) -> Option<&Tween> {
+        self.queue.front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+
+    /// Steps the current tween. Returns the value to write, and its label if it just finished.
+    fn step(&mut self, world: &World, entity: Option<Entity>) -> Option<TweenStep> {
+        let tween = self.queue.front_mut()?;
+        let (value, done) = tween.step(world, entity);
+        let finished = if done {
+            self.queue.pop_front().map(|tween| tween.label)
+        } else {
+            None
+        };
+        Some(TweenStep { value, finished })
+    }
+}
+
+struct TweenStep {
+    value: TweenValue,
+    /// Set once the tween finished, to its label if it has one.
+    finished: Option<Option<String>>,
+}
+
+pub type TweenHook = Box<dyn FnMut(&mut GameState, Option<Entity>)>;
+
+/// Callbacks for labelled tweens. They get the entity the tween ran on, `None` for tweens of the
+/// [`CameraRig`].
+#[derive(Default)]
+pub struct TweenHooks {
+    hooks: HashMap<String, Vec<TweenHook>>,
+}
+
+impl TweenHooks {
+    pub fn on_finished<F>(&mut self, label: &str, f: F)
+    where
+        F: FnMut(&mut GameState, Option<Entity>) + 'static,
+    {
+        self.hooks
+            .entry(label.to_string())
+            .or_default()
+            .push(Box::new(f));
+    }
+}
+
+impl GameState {
+    pub fn tween_hooks_mut(&mut self) -> &mut TweenHooks {
+        &mut self.tween_hooks
+    }
+}
+
+/// Advances the [`Tweens`] of every entity and of the [`CameraRig`] by one tick, then reports the
+/// labelled tweens that finished.
+pub fn update_tweens(game: &mut GameState) -> Result<()> {
+    let mut finished = Vec::new();
+
+    // The tweens write to other components of their entity, so the new values are written once
+    // the query is done.
+    let mut writes = Vec::new();
+    let mut done = Vec::new();
+    for (entity, mut tweens) in &mut game.world.query::<(Entity, &mut Tweens)>()? {
+        if let Some(step) = tweens.step(&game.world, Some(entity)) {
+            writes.push((entity, step.value));
+            if let Some(label) = step.finished {
+                finished.push((Some(entity), label));
+            }
+        }
+        if tweens.is_empty() {
+            done.push(entity);
+        }
+    }
+    for (entity, value) in writes {
+        value.write(&mut game.world, Some(entity))?;
+    }
+    for entity in done {
+        game.world.remove::<Tweens>(entity);
+    }
+
+    if let Some(camera) = game.world.resource_mut::<CameraRig>() {
+        let mut tweens = std::mem::take(&mut camera.tweens);
+        let step = tweens.step(&game.world, None);
+        game.world.resource_mut::<CameraRig>().unwrap().tweens = tweens;
+        if let Some(step) = step {
+            step.value.write(&mut game.world, None)?;
+            if let Some(label) = step.finished {
+                finished.push((None, label));
+            }
+        }
+    }
+
+    let mut hooks = std::mem::take(&mut game.tween_hooks.hooks);
+    for (entity, label) in finished {
+        let Some(label) = label else {
+            continue;
+        };
+        for f in hooks.get_mut(&label).into_iter().flatten() {
+            f(game, entity);
+        }
+        game.events.send(TweenFinished { entity, label });
+    }
+    // Keep hooks that were registered while running hooks.
+    for (label, mut added) in std::mem::replace(&mut game.tween_hooks.hooks, hooks) {
+        game.tween_hooks
+            .hooks
+            .entry(label)
+            .or_default()
+            .append(&mut added);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::game::ecs::EventReader;
+
+    #[test]
+    fn test_easing_curves_start_and_end_in_place() {
+        let curves = [
+            Ease::Linear,
+            Ease::QuadIn,
+            Ease::QuadOut,
+            Ease::QuadInOut,
+            Ease::CubicIn,
+            Ease::CubicOut,
+            Ease::CubicInOut,
+            Ease::ElasticIn,
+            Ease::ElasticOut,
+            Ease::ElasticInOut,
+            Ease::BackIn,
+            Ease::BackOut,
+            Ease::BackInOut,
+        ];
+        for ease in curves {
+            assert!(ease.apply(0.0).abs() < 1e-5, "{:?}", ease);
+            assert!((ease.apply(1.0) - 1.0).abs() < 1e-5, "{:?}", ease);
+        }
+        assert_eq!(0.25, Ease::QuadIn.apply(0.5));
+        assert!(Ease::BackIn.apply(0.2) < 0.0);
+        assert!(Ease::ElasticOut.apply(0.2) > 1.0);
+    }
+
+    #[test]
+    fn test_sequence_runs_in_order_and_reports_completion() -> Result<()> {
+        let mut game = GameState::new()?;
+        let e = game.world_mut().spawn();
+        game.world_mut().insert(e, Transform::from_xy(0.0, 0.0))?;
+        let tweens = Tweens::new()
+            .then(Tween::translation(Vector2::new(4.0, 0.0), 4, Ease::Linear))
+            .then(Tween::tint(Tint([1.0, 0.0, 0.0, 1.0]), 2, Ease::QuadOut).labeled("flash"))
+            .then(Tween::camera_zoom(2.0, 1, Ease::Linear));
+        game.world_mut().insert(e, tweens)?;
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let hook_seen = seen.clone();
+        game.tween_hooks_mut()
+            .on_finished("flash", move |_, entity| {
+                hook_seen.borrow_mut().push(entity)
+            });
+        let mut reader = EventReader::<TweenFinished>::new();
+
+        update_tweens(&mut game)?;
+        assert_eq!(
+            Transform::from_xy(1.0, 0.0),
+            *game.world().get::<Transform>(e).unwrap()
+        );
+        for _ in 0..5 {
+            update_tweens(&mut game)?;
+        }
+        assert_eq!(
+            Transform::from_xy(4.0, 0.0),
+            *game.world().get::<Transform>(e).unwrap()
+        );
+        assert_eq!(
+            Tint([1.0, 0.0, 0.0, 1.0]),
+            *game.world().get::<Tint>(e).unwrap()
+        );
+        assert_eq!(vec![Some(e)], *seen.borrow());
+        let events: Vec<_> = game.events().read(&mut reader).cloned().collect();
+        assert_eq!(
+            vec![TweenFinished {
+                entity: Some(e),
+                label: "flash".into()
+            }],
+            events
+        );
+        assert_eq!(1.0, game.world().resource::<CameraRig>().unwrap().zoom);
+
+        update_tweens(&mut game)?;
+        assert_eq!(2.0, game.world().resource::<CameraRig>().unwrap().zoom);
+        assert!(!game.world().has::<Tweens>(e));
+        Ok(())
+    }
+}
@@ -1,4 +1,3 @@
-use std::path::PathBuf;
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
@@ -7,14 +6,28 @@ use wgpu::{Adapter, Device, PresentMode, Queue, Surface, SurfaceCapabilities};
 use winit::dpi::{self, PhysicalSize};
 use winit::window::Window;
 
+use crate::game::camera::CameraRig;
+use crate::game::chunks::ChunkMap;
+use crate::game::ecs::World;
+use crate::game::state::AppState;
+use crate::game::tiles::TileRegistry;
 use crate::game::{GameState, TileMap};
+use crate::window::Config;
 
-use self::sprites::Sprite;
+use self::sprites::SpriteArray;
 
 pub mod assets;
 mod mesh_builder;
+pub mod preview;
 mod sprites;
 
+const PLAYING_CLEAR_COLOR: wgpu::Color = wgpu::Color {
+    r: 1.0,
+    g: 0.2,
+    b: 1.3,
+    a: 1.0,
+};
+
 pub struct State {
     surface: Surface<'static>,
     device: Device,
@@ -25,19 +38,31 @@ pub struct State {
     clear_color: wgpu::Color,
 
     render_pipeline: wgpu::RenderPipeline,
-    triangle_mesh: mesh_builder::TriangleMesh,
     quad_mesh: mesh_builder::QuadMesh,
-    render_quad: bool,
-    floor_tile: Sprite,
-    wall_tile: Sprite,
+    tile_sprites: SpriteArray,
+    /// [`TileRegistry::version`] the sprites were loaded for.
+    tiles_version: u64,
+    map_mesh: mesh_builder::MapMesh,
+    /// Entities move all the time, so their instances are rebuilt every frame.
     instances: Vec<mesh_builder::TileInstance>,
     camera_buffer: mesh_builder::CameraBuffer,
     camera: mesh_builder::Camera,
     grid_uniform_buffer: mesh_builder::GridUniformBuffer,
+    /// Chunks are placed by tile position, not around the centre of the map.
+    chunk_grid: wgpu::BindGroup,
+    chunk_meshes: mesh_builder::ChunkMeshes,
 }
 
 impl State {
-    pub fn new(window: Window, assets_path: PathBuf) -> Self {
+    /// Creates the renderer for the map, tile registry and config resources in `world`.
+    pub fn new(window: Window, world: &World) -> Self {
+        let config = world.resource::<Config>().expect("config resource missing");
+        let assets_path = config.assets_path();
+        let tile_map = world.resource::<TileMap>().expect("map resource missing");
+        let tiles = world
+            .resource::<TileRegistry>()
+            .expect("tile registry missing");
+
         let window_arc = Arc::new(window);
         let size = window_arc.inner_size();
         let instance = Self::create_gpu_instance();
@@ -52,20 +77,11 @@ impl State {
 
         let (device, queue) = Self::create_device(&adapter);
         let surface_caps = surface.get_capabilities(&adapter);
-        let config = Self::create_surface_config(size, surface_caps);
-        surface.configure(&device, &config);
+        let surface_config = Self::create_surface_config(size, surface_caps);
+        surface.configure(&device, &surface_config);
         let shader = device.create_shader_module(wgpu::include_wgsl!("shader.wgsl"));
 
-        let loaded_floor_tile =
-            assets::LoadedImage::from_path(&assets_path, "sprites/test5.png").unwrap();
-        let floor_tile = sprites::Sprite::new(&device, &queue, loaded_floor_tile);
-
-        let loaded_wall_tile =
-            assets::LoadedImage::from_path(&assets_path, "sprites/test4.png").unwrap();
-        let wall_tile = sprites::Sprite::new(&device, &queue, loaded_wall_tile);
-
-        // This is temporary.
-        let tile_map = TileMap::new(20, 20).unwrap();
+        let tile_sprites = Self::load_tile_sprites(&device, &queue, assets_path, &tiles).unwrap();
 
         let camera = mesh_builder::Camera::new(size.width as f32, size.height as f32, 25.0);
         let camera_buffer = mesh_builder::CameraBuffer::new(&camera, &device);
@@ -76,8 +92,7 @@ impl State {
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
                 bind_group_layouts: &[
-                    &floor_tile.bind_group_layout,
-                    &wall_tile.bind_group_layout,
+                    &tile_sprites.bind_group_layout,
                     &camera_buffer.bind_group_layout,
                     &grid_uniform_buffer.bind_group_layout,
                 ],
@@ -100,8 +115,9 @@ impl State {
                 module: &shader,
                 entry_point: Some("fs_main"),
                 targets: &[Some(wgpu::ColorTargetState {
-                    format: config.format,
-                    blend: Some(wgpu::BlendState::REPLACE),
+                    format: surface_config.format,
+                    // Upper map layers are mostly transparent.
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
                     write_mask: wgpu::ColorWrites::ALL,
                 })],
                 compilation_options: Default::default(),
@@ -124,36 +140,54 @@ impl State {
             cache: None,
         });
 
-        let triangle_mesh = mesh_builder::TriangleMesh::new(&device);
-        let instances = mesh_builder::TileInstance::from_tile_map(&tile_map);
+        let mut map_mesh = mesh_builder::MapMesh::default();
+        map_mesh.update(&device, &tile_map);
+        let instances = mesh_builder::TileInstance::from_world(world);
         let quad_mesh = mesh_builder::QuadMesh::new(&device, &instances);
+        let chunk_grid = grid_uniform_buffer.bind_group_for(&device, (0, 0));
+        let mut chunk_meshes = mesh_builder::ChunkMeshes::default();
+        chunk_meshes.update(&device, world.resource::<ChunkMap>().as_deref());
 
         Self {
             surface,
             device,
             queue,
-            config,
+            config: surface_config,
             size,
             window: window_arc,
-            clear_color: wgpu::Color {
-                r: 1.0,
-                g: 0.2,
-                b: 1.3,
-                a: 1.0,
-            },
+            clear_color: PLAYING_CLEAR_COLOR,
             render_pipeline,
-            triangle_mesh,
             quad_mesh,
-            render_quad: false,
-            floor_tile,
-            wall_tile,
+            tile_sprites,
+            tiles_version: tiles.version(),
+            map_mesh,
             instances,
             camera_buffer,
             camera,
             grid_uniform_buffer,
+            chunk_grid,
+            chunk_meshes,
         }
     }
 
+    /// One layer per tile type, so a tile id is also its texture index.
+    fn load_tile_sprites(
+        device: &Device,
+        queue: &Queue,
+        assets_path: &assets::AssetsPath,
+        tiles: &TileRegistry,
+    ) -> Result<SpriteArray> {
+        let tile_images = tiles
+            .defs()
+            .iter()
+            .map(|def| {
+                assets::LoadedImage::from_path(assets_path, &def.sprite)
+                    .with_context(|| format!("loading sprite of tile `{}`", def.name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        SpriteArray::new(device, queue, "tiles", &tile_images)
+    }
+
     fn create_surface_config(
         size: PhysicalSize<u32>,
         capabilities: SurfaceCapabilities,
@@ -196,7 +230,7 @@ impl State {
         instance
             .request_adapter(&wgpu::RequestAdapterOptions {
                 power_preference: wgpu::PowerPreference::HighPerformance,
-                compatible_surface: Some(&surface),
+                compatible_surface: Some(surface),
                 force_fallback_adapter: false,
             })
             .block_on()
@@ -211,20 +245,59 @@ impl State {
     }
 
     pub fn resize(&mut self, new_size: dpi::PhysicalSize<u32>) {
+        if new_size.width == 0 || new_size.height == 0 {
+            return;
+        }
+
+        self.size = new_size;
+        self.config.width = new_size.width;
+        self.config.height = new_size.height;
+        self.surface.configure(&self.device, &self.config);
+
         self.camera
             .update_aspect_ratio(new_size.width, new_size.height);
-        self.camera_buffer = mesh_builder::CameraBuffer::new(&self.camera, &self.device);
+        self.camera_buffer.write(&self.camera, &self.queue);
     }
 
     pub fn update(&mut self, s: &GameState) -> Result<()> {
-        let needs_inversion = (s.inverted() && !self.triangle_mesh.is_inverted())
-            || (!s.inverted() && self.triangle_mesh.is_inverted());
-
-        if needs_inversion {
-            self.triangle_mesh.invert(&self.device)
+        // No UI yet, so the background is the only hint of which state we are in.
+        self.clear_color = match s.app_state() {
+            Some(AppState::Playing) | None => PLAYING_CLEAR_COLOR,
+            Some(AppState::Paused | AppState::Inventory) => wgpu::Color {
+                r: 0.3,
+                g: 0.3,
+                b: 0.3,
+                a: 1.0,
+            },
+            Some(AppState::MainMenu | AppState::GameOver) => wgpu::Color::BLACK,
         };
 
-        self.render_quad = s.render_quad();
+        if let Some(rig) = s.world().resource::<CameraRig>() {
+            self.camera.set_view(rig.position, rig.zoom);
+            self.camera_buffer.write(&self.camera, &self.queue);
+        }
+
+        // Loading a save or dump can bring other tile types along.
+        let tiles = s.tiles();
+        if tiles.version() != self.tiles_version {
+            let config = s
+                .world()
+                .resource::<Config>()
+                .context("config resource missing")?;
+            self.tile_sprites =
+                Self::load_tile_sprites(&self.device, &self.queue, config.assets_path(), &tiles)?;
+            self.tiles_version = tiles.version();
+        }
+
+        self.chunk_meshes
+            .update(&self.device, s.world().resource::<ChunkMap>().as_deref());
+        let map = s.map();
+        self.grid_uniform_buffer
+            .set_dimensions(&self.device, map.dimensions());
+        self.map_mesh.update(&self.device, &map);
+        self.instances = mesh_builder::TileInstance::from_world(s.world());
+        self.quad_mesh
+            .update_instances(&self.device, &self.queue, &self.instances);
 
         Ok(())
     }
@@ -258,22 +331,33 @@ impl State {
             });
 
             render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.set_bind_group(0, &self.floor_tile.bind_group, &[]);
-            render_pass.set_bind_group(1, &self.wall_tile.bind_group, &[]);
-            render_pass.set_bind_group(2, &self.camera_buffer.bind_group, &[]);
-            render_pass.set_bind_group(3, &self.grid_uniform_buffer.bind_group, &[]);
+            render_pass.set_bind_group(0, &self.tile_sprites.bind_group, &[]);
+            render_pass.set_bind_group(1, &self.camera_buffer.bind_group, &[]);
 
             render_pass.set_vertex_buffer(0, self.quad_mesh.buf.slice(..));
-            render_pass.set_vertex_buffer(1, self.quad_mesh.instance_buf.slice(..));
             render_pass.set_index_buffer(self.quad_mesh.index.slice(..), wgpu::IndexFormat::Uint32);
-            render_pass.draw_indexed(
-                0..mesh_builder::QUAD_INDEX.len() as u32,
-                0,
-                0..self.instances.len() as u32,
-            );
+            let quad = 0..mesh_builder::QUAD_INDEX.len() as u32;
+
+            // Chunks first, the map and entities are drawn on top.
+            render_pass.set_bind_group(2, &self.chunk_grid, &[]);
+            for (buffer, len) in self.chunk_meshes.buffers() {
+                render_pass.set_vertex_buffer(1, buffer.slice(..));
+                render_pass.draw_indexed(quad.clone(), 0, 0..len);
+            }
+
+            render_pass.set_bind_group(2, &self.grid_uniform_buffer.bind_group, &[]);
+            for (buffer, len) in self.map_mesh.buffers() {
+                render_pass.set_vertex_buffer(1, buffer.slice(..));
+                render_pass.draw_indexed(quad.clone(), 0, 0..len);
+            }
+            if !self.instances.is_empty() {
+                render_pass.set_vertex_buffer(1, self.quad_mesh.instance_buf.slice(..));
+                render_pass.draw_indexed(quad, 0, 0..self.instances.len() as u32);
+            }
         }
 
         self.queue.submit(std::iter::once(encoder.finish()));
+        self.window.pre_present_notify();
         output.present();
 
         Ok(())
@@ -4,6 +4,9 @@ use std::ffi::OsString;
 use std::path::PathBuf;
 
 const ASSETS_DIR: &str = "assets";
+const PREFABS_DIR: &str = "prefabs";
+const SCRIPTS_DIR: &str = "scripts";
+const TILES_FILE: &str = "tiles.ron";
 
 pub type AssetsPath = PathBuf;
 
@@ -40,3 +43,15 @@ pub fn make_assets_path() -> Result<AssetsPath> {
     let current_dir = std::env::current_dir().context("getting current directory")?;
     Ok(current_dir.join(ASSETS_DIR))
 }
+
+pub fn prefabs_path(assets_path: &AssetsPath) -> PathBuf {
+    assets_path.join(PREFABS_DIR)
+}
+
+pub fn scripts_path(assets_path: &AssetsPath) -> PathBuf {
+    assets_path.join(SCRIPTS_DIR)
+}
+
+pub fn tiles_path(assets_path: &AssetsPath) -> PathBuf {
+    assets_path.join(TILES_FILE)
+}
@@ -1,24 +1,13 @@
-use std::mem;
+use std::collections::HashMap;
 
-use cgmath::SquareMatrix;
+use cgmath::{SquareMatrix, Vector2};
 use wgpu::util::DeviceExt;
 
-use crate::game::{self, TileMap};
-
-type Polygon = [Vertex; 3];
-
-const NUM_INSTANCES_PER_ROW: u32 = 10;
-const INSTANCE_DISPLACEMENT: cgmath::Vector2<f32> = cgmath::Vector2::new(
-    (NUM_INSTANCES_PER_ROW as f32 - 1.0) * 0.5,
-    (NUM_INSTANCES_PER_ROW as f32 - 1.0) * 0.5,
-);
-
-#[rustfmt::skip]
-const TRIANGLE: Polygon = [
-        Vertex{ position: [-0.75, -0.75], color: [1.0, 0.0, 0.0 ], tex_coord: [0.0, 1.0]},
-        Vertex{ position: [0.75, -0.75],  color: [0.0, 1.0, 0.0 ], tex_coord: [0.0, 1.0] },
-        Vertex{ position: [0.0, 0.75],    color: [0.0, 0.0, 1.0 ], tex_coord: [0.0, 1.0] },
-    ];
+use crate::game::chunks::{ChunkCoord, ChunkMap};
+use crate::game::components::Tint;
+use crate::game::ecs::{GlobalTransform, World};
+use crate::game::tiles::TileId;
+use crate::game::TileMap;
 
 #[rustfmt::skip]
 const QUAD: [Vertex; 4] = [
@@ -42,15 +31,48 @@ pub const QUAD_INDEX: [u32; 6] = [0, 1, 2, 3, 2, 0];
 pub struct TileInstance {
     position: [f32; 2],
     texture_index: u32,
+    tint: [f32; 4],
 }
 
 impl TileInstance {
-    pub fn from_tile_map(tile_map: &TileMap) -> Vec<TileInstance> {
-        tile_map
+    /// Visible layers from the bottom up. Instances are drawn in order, so upper layers end up
+    /// on top.
+    fn from_tile_map_at(tile_map: &TileMap, origin: (i64, i64)) -> Vec<TileInstance> {
+        (0..tile_map.layers().len())
+            .filter(|index| tile_map.layers()[*index].visible)
+            .flat_map(|index| Self::from_layer_at(tile_map, index, origin))
+            .collect()
+    }
+
+    /// The tiles of the layer at `index`, hidden or not.
+    fn from_layer_at(
+        tile_map: &TileMap,
+        index: usize,
+        origin: (i64, i64),
+    ) -> impl Iterator<Item = TileInstance> + '_ {
+        tile_map.iter_layer(index).map(move |tile| TileInstance {
+            position: [
+                (origin.0 + tile.position.0 as i64) as f32,
+                (origin.1 + tile.position.1 as i64) as f32,
+            ],
+            texture_index: tile.ty.0 as u32,
+            tint: Tint::WHITE.0,
+        })
+    }
+
+    /// Entities with a position are drawn on top of the map. Until there are proper sprites
+    /// they use the texture of their [`TileId`].
+    pub fn from_world(world: &World) -> Vec<TileInstance> {
+        let Ok(mut query) = world.query::<(&GlobalTransform, &TileId, Option<&Tint>)>() else {
+            return Vec::new();
+        };
+
+        query
             .iter()
-            .map(|tile| TileInstance {
-                position: [tile.position.0 as f32, tile.position.1 as f32],
-                texture_index: tile.ty as u32,
+            .map(|(global, ty, tint)| TileInstance {
+                position: global.translation().into(),
+                texture_index: ty.0 as u32,
+                tint: tint.copied().unwrap_or_default().0,
             })
             .collect()
     }
@@ -70,26 +92,122 @@ impl TileInstance {
                     offset: std::mem::size_of::<[f32; 2]>() as wgpu::BufferAddress,
                     format: wgpu::VertexFormat::Uint32,
                 },
+                wgpu::VertexAttribute {
+                    shader_location: 5,
+                    offset: std::mem::size_of::<[u32; 3]>() as wgpu::BufferAddress,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
             ],
         }
     }
 }
 
+/// Instance buffers of the map's visible layers, one per layer. A layer's buffer is only rebuilt
+/// when one of its tiles changed, it was shown again or the map was replaced.
+#[derive(Default)]
+pub struct MapMesh {
+    /// Visible layers from the bottom up.
+    layers: Vec<LayerMesh>,
+}
+
+struct LayerMesh {
+    version: u64,
+    /// `None` for layers without tiles.
+    buffer: Option<wgpu::Buffer>,
+    len: u32,
+}
+
+impl MapMesh {
+    pub fn update(&mut self, device: &wgpu::Device, tile_map: &TileMap) {
+        // Versions are unique, so moved and renamed layers keep their buffers.
+        let mut built: HashMap<u64, LayerMesh> = self
+            .layers
+            .drain(..)
+            .map(|mesh| (mesh.version, mesh))
+            .collect();
+
+        for (index, layer) in tile_map.layers().iter().enumerate() {
+            if !layer.visible {
+                continue;
+            }
+            let mesh = built.remove(&layer.version()).unwrap_or_else(|| {
+                let instances: Vec<TileInstance> =
+                    TileInstance::from_layer_at(tile_map, index, (0, 0)).collect();
+                LayerMesh {
+                    version: layer.version(),
+                    buffer: (!instances.is_empty())
+                        .then(|| make_instance_buffer(device, &instances)),
+                    len: instances.len() as u32,
+                }
+            });
+            self.layers.push(mesh);
+        }
+    }
+
+    /// Instance buffers and instance counts from the bottom layer up, skipping empty layers.
+    pub fn buffers(&self) -> impl Iterator<Item = (&wgpu::Buffer, u32)> {
+        self.layers
+            .iter()
+            .filter_map(|mesh| Some((mesh.buffer.as_ref()?, mesh.len)))
+    }
+}
+
+/// Instance buffers of the loaded chunks. A chunk's buffer is only rebuilt when the chunk was
+/// loaded again or changed.
+#[derive(Default)]
+pub struct ChunkMeshes {
+    meshes: HashMap<ChunkCoord, ChunkMesh>,
+}
+
+struct ChunkMesh {
+    version: u64,
+    /// `None` for chunks without visible tiles.
+    buffer: Option<wgpu::Buffer>,
+    len: u32,
+}
+
+impl ChunkMeshes {
+    pub fn update(&mut self, device: &wgpu::Device, chunks: Option<&ChunkMap>) {
+        let Some(chunks) = chunks else {
+            self.meshes.clear();
+            return;
+        };
+        self.meshes
+            .retain(|coord, mesh| chunks.version(*coord) == Some(mesh.version));
+
+        for (coord, chunk) in chunks.chunks() {
+            if self.meshes.contains_key(&coord) {
+                continue;
+            }
+            let instances = TileInstance::from_tile_map_at(chunk, coord.origin());
+            let buffer = (!instances.is_empty()).then(|| make_instance_buffer(device, &instances));
+            self.meshes.insert(
+                coord,
+                ChunkMesh {
+                    version: chunks.version(coord).unwrap(),
+                    buffer,
+                    len: instances.len() as u32,
+                },
+            );
+        }
+    }
+
+    /// Instance buffers and instance counts of the chunks with something to draw.
+    pub fn buffers(&self) -> impl Iterator<Item = (&wgpu::Buffer, u32)> {
+        self.meshes
+            .values()
+            .filter_map(|mesh| Some((mesh.buffer.as_ref()?, mesh.len)))
+    }
+}
+
 pub struct GridUniformBuffer {
     pub bind_group: wgpu::BindGroup,
     pub bind_group_layout: wgpu::BindGroupLayout,
+    dims: (usize, usize),
 }
 
 impl GridUniformBuffer {
     pub fn from(tile_map: &TileMap, device: &wgpu::Device) -> Self {
-        let dims = tile_map.dimensions();
-        let uniform: [f32; 2] = [dims.0 as f32, dims.1 as f32];
-        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Camera Buffer"),
-            contents: bytemuck::cast_slice(&uniform),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
-
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[wgpu::BindGroupLayoutEntry {
                 binding: 0,
@@ -103,21 +221,52 @@ impl GridUniformBuffer {
             }],
             label: Some("grid_bind_group_layout"),
         });
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &bind_group_layout,
+        let dims = tile_map.dimensions();
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, dims);
+
+        Self {
+            bind_group,
+            bind_group_layout,
+            dims,
+        }
+    }
+
+    /// Rebuilds the bind group if the map's dimensions changed, e.g. after entering a level.
+    pub fn set_dimensions(&mut self, device: &wgpu::Device, dims: (usize, usize)) {
+        if dims != self.dims {
+            self.bind_group = Self::create_bind_group(device, &self.bind_group_layout, dims);
+            self.dims = dims;
+        }
+    }
+
+    /// A grid of the same layout with different dimensions. `(0, 0)` draws tiles at their own
+    /// positions instead of around the centre of the map.
+    pub fn bind_group_for(&self, device: &wgpu::Device, dims: (usize, usize)) -> wgpu::BindGroup {
+        Self::create_bind_group(device, &self.bind_group_layout, dims)
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        dims: (usize, usize),
+    ) -> wgpu::BindGroup {
+        let uniform: [f32; 2] = [dims.0 as f32, dims.1 as f32];
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Grid Buffer"),
+            contents: bytemuck::cast_slice(&uniform),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
             entries: &[wgpu::BindGroupEntry {
                 binding: 0,
                 resource: buffer.as_entire_binding(),
             }],
             label: Some("grid_bind_group"),
-        });
-
-        Self { bind_group, bind_group_layout }
+        })
     }
 }
 
-
-
 pub struct Camera {
     view: cgmath::Matrix4<f32>,
     orthographic: cgmath::Matrix4<f32>,
@@ -139,6 +288,12 @@ impl Camera {
         self.orthographic = Camera::create_ortho(width as f32, height as f32, self.world_width);
     }
 
+    /// Looks at `position`, relative to the centre of the map, magnified by `zoom`.
+    pub fn set_view(&mut self, position: Vector2<f32>, zoom: f32) {
+        self.view = cgmath::Matrix4::from_scale(zoom)
+            * cgmath::Matrix4::from_translation(-position.extend(0.0));
+    }
+
     fn create_ortho(width: f32, height: f32, world_width: f32) -> cgmath::Matrix4<f32> {
         let aspect = width / height;
         let world_height = world_width / aspect;
@@ -159,6 +314,7 @@ impl Camera {
 }
 
 pub struct CameraBuffer {
+    buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
     pub bind_group_layout: wgpu::BindGroupLayout,
 }
@@ -196,10 +352,16 @@ impl CameraBuffer {
         });
 
         Self {
+            buffer,
             bind_group,
             bind_group_layout,
         }
     }
+
+    pub fn write(&self, camera: &Camera, queue: &wgpu::Queue) {
+        let uniform: [[f32; 4]; 4] = camera.build_view_projection_matrix().into();
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&uniform));
+    }
 }
 
 #[repr(C)]
@@ -224,100 +386,59 @@ impl Vertex {
 }
 
 pub struct QuadMesh {
-    mesh: [Vertex; 4],
     pub buf: wgpu::Buffer,
     pub index: wgpu::Buffer,
     pub instance_buf: wgpu::Buffer,
 }
 
 impl QuadMesh {
-    pub fn new(device: &wgpu::Device, instances: &Vec<TileInstance>) -> Self {
+    pub fn new(device: &wgpu::Device, instances: &[TileInstance]) -> Self {
         let mesh = QUAD;
         let (buf, index) = make_quad_buffers(device, &mesh);
         let instance_buf = make_instance_buffer(device, instances);
 
         Self {
-            mesh,
             buf,
             index,
             instance_buf,
         }
     }
-}
-
-pub struct TriangleMesh {
-    mesh: Polygon,
-    buf: wgpu::Buffer,
-    is_inverted: bool,
-}
-
-impl TriangleMesh {
-    pub fn new(device: &wgpu::Device) -> Self {
-        let mesh = TRIANGLE;
-        let buf = make_triangle_buffer(device, &mesh);
 
-        Self {
-            mesh,
-            buf,
-            is_inverted: false,
+    /// Uploads new instance data, growing the buffer if needed.
+    pub fn update_instances(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        instances: &[TileInstance],
+    ) {
+        let size = std::mem::size_of_val(instances) as wgpu::BufferAddress;
+        if size > self.instance_buf.size() {
+            self.instance_buf = make_instance_buffer(device, instances);
+        } else {
+            queue.write_buffer(&self.instance_buf, 0, bytemuck::cast_slice(instances));
         }
     }
-
-    pub fn is_inverted(&self) -> bool {
-        self.is_inverted
-    }
-
-    pub fn invert(&mut self, device: &wgpu::Device) {
-        self.mesh = invert_triangle(self.mesh);
-        self.buf = make_triangle_buffer(device, &self.mesh);
-        self.is_inverted = !self.is_inverted
-    }
-
-    pub fn slice(&self) -> wgpu::BufferSlice {
-        self.buf.slice(..)
-    }
 }
 
 fn make_quad_buffers(device: &wgpu::Device, mesh: &[Vertex; 4]) -> (wgpu::Buffer, wgpu::Buffer) {
     let buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
         label: Some("quad buffer"),
-        contents: &bytemuck::cast_slice(mesh),
+        contents: bytemuck::cast_slice(mesh),
         usage: wgpu::BufferUsages::VERTEX,
     });
     let index = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
         label: Some("index buffer"),
-        contents: &bytemuck::cast_slice(&QUAD_INDEX),
+        contents: bytemuck::cast_slice(&QUAD_INDEX),
         usage: wgpu::BufferUsages::INDEX,
     });
 
     (buf, index)
 }
 
-fn make_instance_buffer(device: &wgpu::Device, instances: &Vec<TileInstance>) -> wgpu::Buffer {
+fn make_instance_buffer(device: &wgpu::Device, instances: &[TileInstance]) -> wgpu::Buffer {
     device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
         label: Some("instance buffer"),
-        contents: bytemuck::cast_slice(&instances),
+        contents: bytemuck::cast_slice(instances),
         usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
     })
 }
-
-fn make_triangle_buffer(device: &wgpu::Device, mesh: &Polygon) -> wgpu::Buffer {
-    let buf_disc = wgpu::util::BufferInitDescriptor {
-        label: Some("triangle buffer"),
-        contents: &bytemuck::cast_slice(mesh),
-        usage: wgpu::BufferUsages::VERTEX,
-    };
-
-    device.create_buffer_init(&buf_disc)
-}
-
-fn invert_triangle(mut mesh: Polygon) -> Polygon {
-    for vertex in mesh.iter_mut() {
-        // Assuming position is [x, y, z]
-        vertex.position[1] = -vertex.position[1]; // Flip Y coordinate
-    }
-
-    mesh.swap(0, 2);
-
-    mesh
-}
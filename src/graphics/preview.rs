@@ -0,0 +1,170 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+
+use crate::game::tiles::TileRegistry;
+use crate::game::TileMap;
+
+use super::assets::{AssetsPath, LoadedImage};
+
+/// Draws maps into an image without a GPU, for looking at generated maps in CI artifacts and
+/// pull requests.
+pub struct MapPreview {
+    /// Indexed by tile id, all `tile_size` squared.
+    sprites: Vec<RgbaImage>,
+    tile_size: u32,
+}
+
+impl MapPreview {
+    /// Sprite `i` is drawn for tile id `i`. Sprites are scaled to `tile_size`, or to the size of
+    /// the first one like on the GPU.
+    pub fn new(images: &[LoadedImage], tile_size: Option<u32>) -> Result<Self> {
+        let Some(first) = images.first() else {
+            bail!("no tile sprites")
+        };
+        let tile_size = tile_size.unwrap_or(first.width.max(first.height));
+        if tile_size == 0 {
+            bail!("tiles need to be at least one pixel")
+        }
+
+        let sprites = images
+            .iter()
+            .map(|image| {
+                if (image.width, image.height) == (tile_size, tile_size) {
+                    image.data.clone()
+                } else {
+                    imageops::resize(&image.data, tile_size, tile_size, FilterType::Nearest)
+                }
+            })
+            .collect();
+        Ok(Self { sprites, tile_size })
+    }
+
+    /// Loads the sprite of every tile in `tiles`.
+    pub fn load(
+        assets_path: &AssetsPath,
+        tiles: &TileRegistry,
+        tile_size: Option<u32>,
+    ) -> Result<Self> {
+        let images = tiles
+            .defs()
+            .iter()
+            .map(|def| {
+                LoadedImage::from_path(assets_path, &def.sprite)
+                    .with_context(|| format!("loading sprite of tile `{}`", def.name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(&images, tile_size)
+    }
+
+    pub fn tile_size(&self) -> u32 {
+        self.tile_size
+    }
+
+    /// Visible layers drawn bottom to top with the top row of the map at the top of the image.
+    /// Cells without tiles stay transparent.
+    pub fn render(&self, map: &TileMap) -> Result<RgbaImage> {
+        let (width, height) = map.dimensions();
+        let pixels = |tiles: usize| {
+            u32::try_from(tiles)
+                .ok()
+                .and_then(|tiles| tiles.checked_mul(self.tile_size))
+        };
+        let (Some(image_width), Some(image_height)) = (pixels(width), pixels(height)) else {
+            bail!(
+                "a {}x{} map with {} pixel tiles is too large for an image",
+                width,
+                height,
+                self.tile_size
+            )
+        };
+        let mut image = RgbaImage::new(image_width, image_height);
+
+        for (index, layer) in map.layers().iter().enumerate() {
+            if !layer.visible {
+                continue;
+            }
+            for tile in map.iter_layer(index) {
+                let Some(sprite) = self.sprites.get(tile.ty.index()) else {
+                    bail!(
+                        "no sprite for tile {} in layer `{}`",
+                        tile.ty.0,
+                        layer.name()
+                    )
+                };
+                let (x, y) = tile.position;
+                let top = image_height - (y + 1) * self.tile_size;
+                imageops::overlay(&mut image, sprite, (x * self.tile_size) as i64, top as i64);
+            }
+        }
+
+        Ok(image)
+    }
+
+    pub fn save(&self, map: &TileMap, path: &Path) -> Result<()> {
+        self.render(map)?
+            .save(path)
+            .with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::tiles::TileId;
+    use image::Rgba;
+
+    fn solid(name: &str, size: u32, color: [u8; 4]) -> LoadedImage {
+        LoadedImage {
+            data: RgbaImage::from_pixel(size, size, Rgba(color)),
+            height: size,
+            width: size,
+            file_name: name.to_string(),
+            file_type: "png".into(),
+        }
+    }
+
+    #[test]
+    fn test_render_map_preview() -> Result<()> {
+        let floor = [0, 255, 0, 255];
+        let wall = [0, 0, 255, 255];
+        let glass = [255, 0, 0, 128];
+        let preview = MapPreview::new(
+            &[
+                solid("floor.png", 4, floor),
+                solid("wall.png", 8, wall),
+                solid("glass.png", 4, glass),
+            ],
+            None,
+        )?;
+        assert_eq!(4, preview.tile_size());
+
+        let mut map = TileMap::from_ascii("#.\n. ", &TileRegistry::default())?;
+        map.add_layer("overlay")?;
+        map.set_in("overlay", 1, 1, Some(TileId(2)))?;
+        let image = preview.render(&map)?;
+
+        assert_eq!((8, 8), image.dimensions());
+        assert_eq!(Rgba(wall), *image.get_pixel(0, 0));
+        assert_eq!(Rgba(floor), *image.get_pixel(0, 7));
+        assert_eq!(Rgba([0, 0, 0, 0]), *image.get_pixel(7, 7));
+        // Glass over floor.
+        let mixed = image.get_pixel(7, 0);
+        assert!(mixed[0] > 100 && mixed[1] > 100 && mixed[2] == 0);
+
+        map.set_visible("overlay", false)?;
+        assert_eq!(Rgba(floor), *preview.render(&map)?.get_pixel(7, 0));
+        map.set(0, 0, TileId(9))?;
+        assert!(preview.render(&map).is_err());
+
+        let huge = MapPreview {
+            sprites: Vec::new(),
+            tile_size: 1 << 20,
+        };
+        let error = huge.render(&TileMap::new(5000, 1)?).unwrap_err();
+        assert!(error.to_string().contains("too large"));
+        Ok(())
+    }
+}
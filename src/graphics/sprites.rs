@@ -1,21 +1,35 @@
+use anyhow::{bail, Result};
+use image::imageops::{self, FilterType};
+
 use super::assets::LoadedImage;
 
-pub struct Sprite {
+/// Images stacked into the layers of one texture, so the shader can pick one per instance.
+pub struct SpriteArray {
     // Whether this should be on the Sprite or not, I can decide later.
     pub bind_group: wgpu::BindGroup,
     pub bind_group_layout: wgpu::BindGroupLayout,
 }
 
-impl Sprite {
-    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, image: LoadedImage) -> Self {
+impl SpriteArray {
+    /// Layer `i` holds `images[i]`. Images are scaled to the size of the first one.
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        label: &str,
+        images: &[LoadedImage],
+    ) -> Result<Self> {
+        let Some(first) = images.first() else {
+            bail!("sprite array `{}` has no images", label)
+        };
+        let (width, height) = (first.width, first.height);
         let texture_size = wgpu::Extent3d {
-            width: image.width,
-            height: image.height,
-            depth_or_array_layers: 1,
+            width,
+            height,
+            depth_or_array_layers: images.len() as u32,
         };
 
         let texture_discriptor = wgpu::TextureDescriptor {
-            label: Some(&image.file_name),
+            label: Some(label),
             size: texture_size,
             mip_level_count: 1,
             sample_count: 1,
@@ -27,27 +41,45 @@ impl Sprite {
 
         let texture = device.create_texture(&texture_discriptor);
 
-        let image_copy_texture = wgpu::ImageCopyTexture {
-            texture: &texture,
-            mip_level: 0,
-            origin: wgpu::Origin3d::ZERO,
-            aspect: wgpu::TextureAspect::All,
-        };
-        let image_data_layout = wgpu::ImageDataLayout {
-            offset: 0,
-            bytes_per_row: Some(4 * image.width),
-            rows_per_image: Some(image.height),
-        };
-        queue.write_texture(
-            image_copy_texture,
-            &image.data,
-            image_data_layout,
-            texture_size,
-        );
+        for (layer, image) in images.iter().enumerate() {
+            let image_copy_texture = wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: 0,
+                    y: 0,
+                    z: layer as u32,
+                },
+                aspect: wgpu::TextureAspect::All,
+            };
+            let image_data_layout = wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * width),
+                rows_per_image: Some(height),
+            };
+            let layer_size = wgpu::Extent3d {
+                depth_or_array_layers: 1,
+                ..texture_size
+            };
+            if (image.width, image.height) == (width, height) {
+                queue.write_texture(
+                    image_copy_texture,
+                    &image.data,
+                    image_data_layout,
+                    layer_size,
+                );
+            } else {
+                let scaled = imageops::resize(&image.data, width, height, FilterType::Nearest);
+                queue.write_texture(image_copy_texture, &scaled, image_data_layout, layer_size);
+            }
+        }
 
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
         let sample_descriptor = wgpu::SamplerDescriptor {
-            label: Some(&image.file_name),
+            label: Some(label),
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
             address_mode_w: wgpu::AddressMode::ClampToEdge,
@@ -59,14 +91,14 @@ impl Sprite {
         let sampler = device.create_sampler(&sample_descriptor);
 
         let bind_group_layout_descriptor = wgpu::BindGroupLayoutDescriptor {
-            label: Some(&image.file_name),
+            label: Some(label),
             entries: &[
                 wgpu::BindGroupLayoutEntry {
                     binding: 0,
                     visibility: wgpu::ShaderStages::FRAGMENT,
                     ty: wgpu::BindingType::Texture {
                         sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        view_dimension: wgpu::TextureViewDimension::D2,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
                         multisampled: false,
                     },
                     count: None,
@@ -82,7 +114,7 @@ impl Sprite {
         let bind_group_layout = device.create_bind_group_layout(&bind_group_layout_descriptor);
 
         let bind_group_descriptor = wgpu::BindGroupDescriptor {
-            label: Some(&image.file_name),
+            label: Some(label),
             layout: &bind_group_layout,
             entries: &[
                 wgpu::BindGroupEntry {
@@ -97,9 +129,9 @@ impl Sprite {
         };
         let bind_group = device.create_bind_group(&bind_group_descriptor);
 
-        Self {
+        Ok(Self {
             bind_group,
             bind_group_layout,
-        }
+        })
     }
 }
@@ -3,18 +3,19 @@ use std::time::Duration;
 use anyhow::Result;
 use winit::event_loop::{ControlFlow, EventLoop};
 
+use self::game::events::UserEvent;
 use self::window::{Config, StateApplication};
 
-mod game;
-mod graphics;
+pub mod game;
+pub mod graphics;
 mod window;
 
 pub async fn run() -> Result<()> {
     let assets_path = graphics::assets::make_assets_path()?;
     let config = Config::new(60, Duration::from_millis(17), assets_path);
 
-    let mut app = StateApplication::new(config);
-    let event_loop = EventLoop::new()?;
+    let event_loop = EventLoop::<UserEvent>::with_user_event().build()?;
+    let mut app = StateApplication::new(config, event_loop.create_proxy());
     event_loop.set_control_flow(ControlFlow::Poll);
     event_loop.run_app(&mut app).unwrap();
 
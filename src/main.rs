@@ -2,6 +2,8 @@ use anyhow::Result;
 use game::run;
 
 fn main() -> Result<()> {
+    // `RUST_LOG=debug` shows more, e.g. the state stack.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     pollster::block_on(run())?;
     Ok(())
 }
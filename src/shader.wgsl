@@ -1,6 +1,7 @@
 struct InstanceInput {
     @location(3) instance_position: vec2<f32>,
     @location(4) texture_index: u32,
+    @location(5) tint: vec4<f32>,
 }
 
 struct Vertex {
@@ -13,6 +14,7 @@ struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) texCoord: vec2<f32>,
     @location(1) texture_index: u32,
+    @location(2) tint: vec4<f32>,
 };
 
 struct CameraUniform {
@@ -23,21 +25,20 @@ struct GridUniform {
     @location(0) dimensions: vec2<f32>
 }
 
-@group(0) @binding(0) var floor_texture: texture_2d<f32>;
-@group(0) @binding(1) var floor_texture_sampler: sampler;
+// One layer per tile type, indexed by tile id.
+@group(0) @binding(0) var tile_textures: texture_2d_array<f32>;
+@group(0) @binding(1) var tile_sampler: sampler;
 
-@group(1) @binding(0) var wall_texture: texture_2d<f32>;
-@group(1) @binding(1) var wall_texture_sampler: sampler;
+@group(1) @binding(0) var<uniform> camera: CameraUniform;
 
-@group(2) @binding(0) var<uniform> camera: CameraUniform;
-
-@group(3) @binding(0) var<uniform> grid: GridUniform;
+@group(2) @binding(0) var<uniform> grid: GridUniform;
 
 @vertex
 fn vs_main(vertex: Vertex, instance: InstanceInput) -> VertexOutput {
     var out: VertexOutput;
     out.texCoord = vertex.texCoord;
     out.texture_index = instance.texture_index;
+    out.tint = instance.tint;
 
     let grid_center = grid.dimensions / 2.0;
     let centered_instance_pos = instance.instance_position - grid_center;
@@ -57,10 +58,6 @@ fn vs_main(vertex: Vertex, instance: InstanceInput) -> VertexOutput {
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    if (in.texture_index == 0u) {
-        return textureSample(floor_texture, floor_texture_sampler, in.texCoord);
-    }
-
-    return textureSample(wall_texture, wall_texture_sampler, in.texCoord);
+    return textureSample(tile_textures, tile_sampler, in.texCoord, in.texture_index) * in.tint;
 }
 
@@ -1,15 +1,18 @@
 use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::Context;
 use winit::application::ApplicationHandler;
 use winit::event::WindowEvent;
-use winit::event_loop::ActiveEventLoop;
+use winit::event_loop::{ActiveEventLoop, EventLoopProxy};
 use winit::window::{Window, WindowId};
 
-use crate::game::{self, ECS};
+use crate::game::events::UserEvent;
+use crate::game::rng::Rng;
+use crate::game::{self, Game};
 use crate::graphics::State;
 
+#[derive(Clone)]
 pub struct Config {
     max_frame_time: Duration,
     target_frame_time: Duration,
@@ -24,40 +27,68 @@ impl Config {
             assets_path,
         }
     }
+
+    pub fn assets_path(&self) -> &PathBuf {
+        &self.assets_path
+    }
 }
 
 pub struct StateApplication {
-    state: Option<game::ECS>,
+    state: Option<Game>,
     accumulated_time: Duration,
     instant: Instant,
     config: Config,
+    proxy: EventLoopProxy<UserEvent>,
 }
 
 impl StateApplication {
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Config, proxy: EventLoopProxy<UserEvent>) -> Self {
         Self {
             state: None,
             accumulated_time: Duration::ZERO,
             instant: Instant::now(),
             config,
+            proxy,
         }
     }
 }
 
-impl ApplicationHandler for StateApplication {
+impl ApplicationHandler<UserEvent> for StateApplication {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         let window = {
             event_loop
                 .create_window(Window::default_attributes().with_title("Hello!"))
                 .unwrap()
         };
-        let renderer = State::new(window, self.config.assets_path.clone());
 
         match &mut self.state {
             Some(state) => {
+                let renderer = State::new(window, state.game_state.world());
                 state.update_renderer(renderer);
             }
-            None => self.state = Some(ECS::new(game::GameState::new().unwrap(), renderer)),
+            None => {
+                let mut game_state = game::GameState::new().unwrap();
+                game_state.set_event_proxy(self.proxy.clone());
+                game_state.world_mut().insert_resource(self.config.clone());
+                game_state
+                    .world_mut()
+                    .insert_resource(Rng::new(seed_from_time()));
+                game_state
+                    .load_tiles(self.config.assets_path())
+                    .context("when loading tiles")
+                    .unwrap();
+                game_state
+                    .load_prefabs(self.config.assets_path())
+                    .context("when loading prefabs")
+                    .unwrap();
+                game_state
+                    .load_scripts(self.config.assets_path())
+                    .context("when loading scripts")
+                    .unwrap();
+
+                let renderer = State::new(window, game_state.world());
+                self.state = Some(Game::new(game_state, renderer).unwrap())
+            }
         }
     }
 
@@ -95,6 +126,13 @@ impl ApplicationHandler for StateApplication {
         }
     }
 
+    fn user_event(&mut self, _event_loop: &ActiveEventLoop, event: UserEvent) {
+        // Events sent before the first `resumed` have no game to go to and are dropped.
+        if let Some(state) = &mut self.state {
+            state.game_state.handle(event);
+        }
+    }
+
     fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
         let state = match &mut self.state {
             Some(state) => state,
@@ -110,21 +148,14 @@ impl ApplicationHandler for StateApplication {
 
         self.accumulated_time += elapsed;
 
-        let mut keys_updated = false;
-
         while self.accumulated_time > self.config.target_frame_time {
-            state.game_state.update();
+            state.tick().context("when ticking").unwrap();
 
             if state.game_state.exit() {
                 event_loop.exit();
                 return;
             }
 
-            if !keys_updated {
-                state.game_state.update_keys();
-                keys_updated = true;
-            }
-
             self.accumulated_time = self
                 .accumulated_time
                 .saturating_sub(self.config.target_frame_time);
@@ -136,3 +167,9 @@ impl ApplicationHandler for StateApplication {
         }
     }
 }
+
+fn seed_from_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}